@@ -1,7 +1,6 @@
-use crate::state::compute_key_hash;
 use crate::transaction::{Transaction, SignedTransaction, generate_random_transaction, generate_random_signed_transaction};
 use serde::{Serialize, Deserialize};
-use crate::crypto::hash::{self, H256, Hashable,generate_random_hash};
+use crate::crypto::hash::{self, H160, H256, Hashable,generate_random_hash};
 use rand::Rng;
 use crate::crypto::merkle::MerkleTree;
 use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
@@ -30,6 +29,7 @@ pub struct Header {
     pub vrf_hash: Vec<u8>,
     pub vrf_pub_key: Vec<u8>,
     pub rand: u128,     // randomness for PoS leader election. TODO: update rand every epoch 
+    pub coinbase: H160,  // account credited with the block reward
 }
 
 #[derive(Serialize, Deserialize, Debug,Hash, Eq, PartialEq,Clone)]
@@ -64,7 +64,7 @@ impl Block {
         let txns = self.content.data.clone();
         info!("***** Print txns in block {:?} *****", self.hash());
         for txn in txns {
-            let sender = compute_key_hash(txn.sign.pubk);
+            let sender = txn.transaction.sender;
             let recv = txn.transaction.recv;
             info!("{:?} sends {:?} value {:?}", sender, recv, txn.transaction.value);
         }
@@ -98,6 +98,7 @@ pub fn generate_pow_block(data: &Vec<SignedTransaction>, transaction_ref: &Vec<H
         vrf_hash: vrf_hash.to_vec(),
         vrf_pub_key: vrf_pub_key.to_vec(),
         rand: rand,
+        coinbase: Default::default(),
     };
     Block {
         header,
@@ -129,6 +130,7 @@ pub fn generate_genesis_block() -> Block {
         vrf_hash: Default::default(),
         vrf_pub_key: Default::default(),
         rand: Default::default(),
+        coinbase: Default::default(),
     };
     Block {
         header,
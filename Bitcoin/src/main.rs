@@ -23,6 +23,7 @@ use crossbeam::channel;
 use log::{error, info};
 use api::Server as ApiServer;
 use network::{server, worker, spv_worker,selfish_worker};
+use network::message::{Version, Services, PROTOCOL, PROTOCOL_VERSION};
 use std::net;
 use std::process;
 use std::thread;
@@ -45,6 +46,9 @@ fn main() {
      (@arg spv_client: --spv [BOOL] default_value("false") "Whether spv client or full node") // false for full node, true for spv client
      //(@arg fly_client: --fly [BOOL] default_value("false") "Whether fly client or full node") // false for full node, true for fly client
      (@arg selfish_node: --selfish [BOOL] default_value("false") "Whether selfish or honest node") // false for honest node, true for selfish node
+     (@arg coinbase: --coinbase [ADDR] "Sets the hex account credited with the rewards of mined blocks, the first ICO account if not given")
+     (@arg block_reward: --("block-reward") [INT] default_value("50") "Sets the reward of a block")
+     (@arg halving_interval: --("halving-interval") [INT] default_value("0") "Sets the number of blocks after which the block reward halves, 0 never halves")
     )
     .get_matches();
 
@@ -108,10 +112,6 @@ fn main() {
     // let (selfish_context_update_send, selfish_context_update_recv) = channel::unbounded();
 
 
-    // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
-
     // start the worker
     let p2p_workers = matches
         .value_of("p2p_workers")
@@ -130,6 +130,22 @@ fn main() {
     let mut mempool = Vec::new();
     let mut all_txns = HashMap::new();
     let mut state = state::State::new();
+    state.rewards.reward = matches
+        .value_of("block_reward")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block reward: {}", e);
+            process::exit(1);
+        });
+    state.rewards.halving_interval = matches
+        .value_of("halving_interval")
+        .unwrap()
+        .parse::<u128>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing halving interval: {}", e);
+            process::exit(1);
+        });
     let blockchain = Arc::new(std::sync::Mutex::new(blockchain));
     let buffer = Arc::new(std::sync::Mutex::new(buffer));
     let all_blocks = Arc::new(std::sync::Mutex::new(all_blocks));
@@ -137,6 +153,18 @@ fn main() {
     let mempool = Arc::new(std::sync::Mutex::new(mempool));
     let all_txns = Arc::new(std::sync::Mutex::new(all_txns));
 
+    // start the p2p server
+    let version = Version {
+        protocol: PROTOCOL.to_string(),
+        version: PROTOCOL_VERSION,
+        genesis_hash: blockchain.lock().unwrap().tip(),
+        best_height: 0,
+        services: if spv_client { Services::SPV } else { Services::Full },
+        listen_port: p2p_addr.port(),
+    };
+    let (server_ctx, server) = server::new(p2p_addr, msg_tx, version, &blockchain).unwrap();
+    server_ctx.start().unwrap();
+
     // ico 
     let ico_account_number = 900;
     let keypairs = state::create_ico_keys(ico_account_number);
@@ -145,6 +173,14 @@ fn main() {
     //let genesis_block = block::generate_genesis_block();
     let genesis_block_hash = blockchain.lock().unwrap().tip();
     state.ico(genesis_block_hash, &accounts, amount);
+    let coinbase: H160 = match matches.value_of("coinbase") {
+        Some(coinbase) => parse_h160(coinbase).unwrap_or_else(|e| {
+            error!("Error parsing coinbase: {}", e);
+            process::exit(1);
+        }),
+        None => accounts[0],
+    };
+    info!("Coinbase of mined blocks: {:?}", coinbase);
     info!("***** State After ICO *****");
     state.print_last_block_state(&genesis_block_hash);
     info!("***************************");
@@ -240,6 +276,7 @@ fn main() {
         &state,
         &all_blocks,
         selfish_node,
+        coinbase,
     );
     miner_ctx.start();
    
@@ -295,3 +332,14 @@ fn main() {
 
     
 }
+
+/// Parse a 20 byte hex account
+fn parse_h160(input: &str) -> Result<H160, String> {
+    let bytes = hex::decode(input).map_err(|e| format!("invalid hex {}: {}", input, e))?;
+    if bytes.len() != 20 {
+        return Err(format!("{} is not 20 bytes long", input));
+    }
+    let mut raw = [0u8; 20];
+    raw.copy_from_slice(&bytes);
+    Ok(raw.into())
+}
@@ -51,6 +51,7 @@ pub struct Context {
     state: Arc<Mutex<State>>,
     all_blocks: Arc<Mutex<HashMap<H256,Block>>>,
     selfish_miner: bool,
+    coinbase: H160,
 }
 
 #[derive(Clone)]
@@ -68,6 +69,7 @@ pub fn new(
     state: &Arc<Mutex<State>>,
     all_blocks: &Arc<Mutex<HashMap<H256,Block>>>,
     selfish_miner: bool,
+    coinbase: H160,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
 
@@ -82,6 +84,7 @@ pub fn new(
         state: Arc::clone(state),
         all_blocks: Arc::clone(all_blocks),
         selfish_miner: selfish_miner,
+        coinbase,
     };
 
     let handle = Handle {
@@ -214,6 +217,7 @@ impl Context {
 
             if enough_txn {
                 let mut blk = generate_pow_block(&data, &transaction_ref, &parent, rng.gen(), &difficulty, ts, &vrf_proof, &vrf_hash, &vrf_public_key, rand, self.selfish_miner);
+                blk.header.coinbase = self.coinbase;
                 loop {
                     // info!("Start mining!");
                     handle_context_update!(blk);
@@ -239,10 +243,13 @@ impl Context {
                                 last_longest_chain.remove(0);
                                 longest_chain.remove(0);
                             }
-                            // let mut blocks = Vec::new();
+                            let mut blocks = Vec::new();
                             // update the state
-                        
-                            // self.state.lock().unwrap().update_blocks(&blocks);
+                            for blk_hash in longest_chain.iter() {
+                                let block = self.blockchain.lock().unwrap().find_one_block(blk_hash).unwrap();
+                                blocks.push(block);
+                            }
+                            self.state.lock().unwrap().update_blocks(&blocks);
                             
 
                             // add txns back to the mempool
@@ -6,8 +6,51 @@ use tari_mmr::{MerkleMountainRange, MerkleProof, Hash};
 use sha2::{Digest, Sha256};
 use crate::blockchain::{FlyClientProposal,FlyClientProof,FlyClientQuery};
 
+/// Protocol name sent in the handshake, nodes of other protocols are dropped
+pub const PROTOCOL: &str = "bitcoin";
+/// Bumped on every incompatible change of the messages
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Services {
+    Full,
+    SPV,
+    Fly,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub protocol: String,
+    pub version: u32,
+    pub genesis_hash: H256,
+    pub best_height: u128,
+    pub services: Services,
+    /// the port the sender accepts connections at
+    pub listen_port: u16,
+}
+
+impl Version {
+    /// Why a node that sent `other` cannot talk to us, if it cannot
+    pub fn check(&self, other: &Version) -> Result<(), String> {
+        if other.protocol != self.protocol {
+            return Err(format!("protocol {} instead of {}", other.protocol, self.protocol));
+        }
+        if other.version != self.version {
+            return Err(format!("protocol version {} instead of {}", other.version, self.version));
+        }
+        if other.genesis_hash != self.genesis_hash {
+            return Err(format!("genesis block {} instead of {}", other.genesis_hash, self.genesis_hash));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
+    // handshake, first on every connection. These stay the first variants in every client so
+    // that nodes of other protocols decode them and get dropped.
+    Version(Version),
+    Verack(),
     Ping(String),
     Pong(String),
     NewBlockHashes(Vec<H256>),
@@ -192,6 +192,8 @@ pub fn new(
         writer: write_ctx,
         handle: handle.clone(),
         direction,
+        verified: false,
+        version: None,
     };
     Ok((ctx, handle))
 }
@@ -209,6 +211,10 @@ pub struct Context {
     pub writer: WriteContext,
     pub handle: Handle,
     pub direction: Direction,
+    /// whether the handshake is complete
+    pub verified: bool,
+    /// the handshake of the peer, once it has sent a compatible one
+    pub version: Option<message::Version>,
 }
 
 #[derive(Clone)]
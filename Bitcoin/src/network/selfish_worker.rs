@@ -200,9 +200,13 @@ impl Context {
                                         last_longest_chain.remove(0);
                                         longest_chain.remove(0);
                                     }
-                                    // let mut blocks = Vec::new();
+                                    let mut blocks = Vec::new();
                                     // update the state
-                                    // self.state.lock().unwrap().update_blocks(&blocks);
+                                    for blk_hash in longest_chain.iter() {
+                                        let block = self.blockchain.lock().unwrap().find_one_block(blk_hash).unwrap();
+                                        blocks.push(block);
+                                    }
+                                    self.state.lock().unwrap().update_blocks(&blocks);
                                     
 
                                     // add txns back to the mempool
@@ -13,8 +13,31 @@ use std::fs;
 
 type state = HashMap<H160, (usize, usize)>;
 
+/// Reward of every block, credited to the coinbase in its header
+#[derive(Debug, Clone, Default)]
+pub struct RewardSchedule {
+    pub reward: usize,
+    /// the reward halves every `halving_interval` blocks, 0 never halves
+    pub halving_interval: u128,
+}
+
+impl RewardSchedule {
+    /// Reward of a block at `height`
+    pub fn reward(&self, height: u128) -> usize {
+        if self.halving_interval == 0 {
+            return self.reward;
+        }
+        let halvings = height / self.halving_interval;
+        if halvings >= usize::BITS as u128 { 0 } else { self.reward >> halvings }
+    }
+}
+
 pub struct State {
     pub state_per_block: HashMap<H256, state>,
+    height_per_block: HashMap<H256, u128>,
+    /// hash of the genesis block, transactions for other chains are invalid
+    chain_id: H256,
+    pub rewards: RewardSchedule,
 }
 
 pub fn file_to_vec(filename: String) -> io::Result<Vec<String>> {
@@ -49,35 +72,38 @@ pub fn compute_key_hash(key: Vec<u8>) -> H256 {
     ring::digest::digest(&ring::digest::SHA256, bytes).into()
 }
 
+/// Apply a transaction to the state if it is valid; the receiver account is created on its first
+/// incoming transfer
 pub fn transaction_check(current_state: &mut state, tx: &SignedTransaction) -> bool {
-	if verify_signedtxn(&tx) {
-		let copy = tx.clone();
-        let pubk = copy.sign.pubk.clone();
-        let nonce = copy.transaction.nonce.clone();
-        let value = copy.transaction.value.clone();
-        let recv = copy.transaction.recv.clone();
-
-        let sender: H160 = compute_key_hash(pubk).into();
-        let (s_nonce, s_amount) = current_state.get(&sender).unwrap().clone();
-        let (r_nonce, r_amount) = current_state.get(&recv).unwrap().clone();
-
-        if nonce == s_nonce+1 &&  s_amount >= value{
-            current_state.insert(sender, (s_nonce+1, s_amount-value));
-            current_state.insert(recv, (r_nonce, r_amount+value));
-            return true;
-        } else {
-            return false;
-        }
-    } else {
-    	return false;
-    }
+	if !verify_signedtxn(&tx) {
+		return false;
+	}
+	let nonce = tx.transaction.nonce;
+	let value = tx.transaction.value;
+	let recv = tx.transaction.recv;
+	let sender = tx.transaction.sender;
+	let (s_nonce, s_amount) = match current_state.get(&sender) {
+		Some(account) => *account,
+		None => return false,
+	};
+	if nonce != s_nonce+1 || s_amount < value {
+		return false;
+	}
+	current_state.insert(sender, (s_nonce+1, s_amount-value));
+	credit(current_state, &recv, value);
+	true
+}
 
+fn credit(current_state: &mut state, account: &H160, value: usize) {
+	let (nonce, amount) = current_state.get(account).cloned().unwrap_or((0, 0));
+	current_state.insert(*account, (nonce, amount+value));
 }
 
 impl State {
     pub fn new() -> Self {
         let state_per_block = HashMap::new();
-        State{state_per_block}
+        let height_per_block = HashMap::new();
+        State{state_per_block, height_per_block, chain_id: Default::default(), rewards: Default::default()}
     }
 
     pub fn ico(&mut self, genesis_hash: H256, accounts: &Vec<H160>, amount: usize) {
@@ -90,8 +116,21 @@ impl State {
             s.insert(*account, (0, amount));
         }
         self.state_per_block.insert(genesis_hash, s);
+        self.height_per_block.insert(genesis_hash, 0);
+        self.chain_id = genesis_hash;
+    }
+
+    pub fn chain_id(&self) -> H256 {
+        self.chain_id
     }
 
+    /// Whether a transaction is for this chain and not expired at `height`
+    pub fn envelope_check(&self, tx: &SignedTransaction, height: u128) -> bool {
+        tx.transaction.chain_id == self.chain_id && tx.transaction.expiry.map_or(true, |expiry| height <= expiry)
+    }
+
+    /// Execute a block on top of its parent's state, skipping invalid transactions, and pay the
+    /// block reward to its coinbase
     pub fn update_block(&mut self, block: &Block) {
         if self.state_per_block.contains_key(&block.hash()) {
             return;
@@ -102,17 +141,17 @@ impl State {
             return;
         }
         let mut parent_state = self.state_per_block.get(&parent_hash).unwrap().clone();
-        let txns = block.content.data.clone();
-        for txn in txns {
-            let sender: H160 = compute_key_hash(txn.sign.pubk).into();
-            let recv = txn.transaction.recv;
-            // todo: add txn check, if the account exists or amount is enough
-            
-            let (s_nonce, s_amount) = parent_state.get(&sender).unwrap().clone();
-            let (r_nonce, r_amount) = parent_state.get(&recv).unwrap().clone();
-            parent_state.insert(sender, (s_nonce+1, s_amount-txn.transaction.value));
-            parent_state.insert(recv, (r_nonce, r_amount+txn.transaction.value));
+        let height = self.height_per_block.get(&parent_hash).cloned().unwrap_or(0) + 1;
+        for txn in block.content.data.iter() {
+            if !self.envelope_check(txn, height) || !transaction_check(&mut parent_state, txn) {
+                debug!("Skip invalid txn {} in block {}", txn.hash(), block.hash());
+            }
+        }
+        let reward = self.rewards.reward(height);
+        if reward > 0 {
+            credit(&mut parent_state, &block.header.coinbase, reward);
         }
+        self.height_per_block.insert(block.hash(), height);
         self.state_per_block.insert(block.hash(), parent_state);
     }
 
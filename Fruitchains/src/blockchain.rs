@@ -327,6 +327,40 @@ impl Blockchain {
 		chain_quality
     }
 
+    /// Fruits a block includes that none of its ancestors included, in reference order
+    pub fn get_referenced_fruits(&self, block: &Block) -> Vec<Block> {
+		let mut fruits: Vec<Block> = Vec::new();
+		for h in block.content.transaction_ref.iter() {
+			let fruit = match self.chain.get(h) {
+				Some(data) => data,
+				None => {
+					warn!("Referenced fruit {} is not in blockchain", h);
+					continue;
+				}
+			};
+			if fruits.iter().any(|f| f.hash() == *h) {
+				continue;
+			}
+			// only blocks above the one the fruit hangs from can include it
+			let mut current_hash = block.header.parent;
+			let mut included = false;
+			while let Some(data) = self.chain.get(&current_hash) {
+				if data.height < fruit.height {
+					break;
+				}
+				if data.blk.content.transaction_ref.contains(h) {
+					included = true;
+					break;
+				}
+				current_hash = data.blk.header.parent;
+			}
+			if !included {
+				fruits.push(fruit.blk.clone());
+			}
+		}
+		fruits
+    }
+
     pub fn find_one_block(&self,hash: &H256) -> Option<Block> {
     	match self.chain.get(&hash) {
 			None => return None,
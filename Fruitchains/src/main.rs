@@ -25,12 +25,13 @@ use crossbeam::channel;
 use log::{error, info};
 use api::Server as ApiServer;
 use network::{server, worker, spv_worker, selfish_worker};
+use network::message::{Version, Services, PROTOCOL, PROTOCOL_VERSION};
 use std::net;
 use std::process;
 use std::thread;
 use std::time;
 use crate::crypto::hash::H160;
-use ring::signature::Ed25519KeyPair;
+use ring::signature::{Ed25519KeyPair, KeyPair};
 
 use vrf::openssl::{CipherSuite, ECVRF};
 use vrf::VRF;  
@@ -54,6 +55,10 @@ fn main() {
      (@arg txn_numerator: --txnn [usize] default_value("1") "txn generator numerator, range: [0,denominator)" )
      (@arg txn_denominator: --txnd [usize] default_value("1") "txn generator denominator" )
      (@arg selfish_node: --selfish [BOOL] default_value("false") "Whether selfish or honest node") // false for honest node, true for selfish node
+     (@arg coinbase: --coinbase [ADDR] "Sets the hex account credited with the rewards of mined blocks and fruits, the first ICO account if not given")
+     (@arg block_reward: --("block-reward") [INT] default_value("50") "Sets the reward of a block, shared with the fruits it includes")
+     (@arg halving_interval: --("halving-interval") [INT] default_value("0") "Sets the number of blocks after which the block reward halves, 0 never halves")
+     (@arg block_share: --("block-share") [INT] default_value("10") "Sets the percentage of the block reward kept by the block, the rest is split among its fruits")
     )
     .get_matches();
 
@@ -153,10 +158,6 @@ fn main() {
     // create block update channels
     let (block_context_update_send, block_context_update_recv) = channel::unbounded();
 
-    // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
-
     // start the worker
     let p2p_workers = matches
         .value_of("p2p_workers")
@@ -177,6 +178,30 @@ fn main() {
     let mut all_txns = HashMap::new();
     let spam_recorder = SpamRecorder::new();
     let mut state = state::State::new();
+    state.rewards.reward = matches
+        .value_of("block_reward")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block reward: {}", e);
+            process::exit(1);
+        });
+    state.rewards.halving_interval = matches
+        .value_of("halving_interval")
+        .unwrap()
+        .parse::<u128>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing halving interval: {}", e);
+            process::exit(1);
+        });
+    state.rewards.block_share = matches
+        .value_of("block_share")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block share: {}", e);
+            process::exit(1);
+        });
     let blockchain = Arc::new(std::sync::Mutex::new(blockchain));
     let buffer = Arc::new(std::sync::Mutex::new(buffer));
     let all_blocks = Arc::new(std::sync::Mutex::new(all_blocks));
@@ -186,17 +211,37 @@ fn main() {
     let all_txns = Arc::new(std::sync::Mutex::new(all_txns));
     let spam_recorder= Arc::new(std::sync::Mutex::new(spam_recorder));
 
+    // start the p2p server
+    let version = Version {
+        protocol: PROTOCOL.to_string(),
+        version: PROTOCOL_VERSION,
+        genesis_hash: blockchain.lock().unwrap().tip(),
+        best_height: 0,
+        services: if spv_client { Services::SPV } else { Services::Full },
+        listen_port: p2p_addr.port(),
+    };
+    let (server_ctx, server) = server::new(p2p_addr, msg_tx, version, &blockchain).unwrap();
+    server_ctx.start().unwrap();
+
     // ico 
     let ico_account_number = 2;
     let keypairs = state::create_ico_keys(ico_account_number);
-    // let accounts = state::create_ico_accounts(keypairs);
-    // let amount = 10000;
+    let accounts: Vec<H160> = keypairs.iter().map(|key| state::compute_key_hash(key.public_key().as_ref().to_vec()).into()).collect();
+    let amount = 10000;
     //let genesis_block = block::generate_genesis_block();
-    //let genesis_block_hash = blockchain.lock().unwrap().tip();
-    //state.ico(genesis_block_hash, &accounts, amount);
-    //info!("***** State After ICO *****");
-    ///state.print_last_block_state(&genesis_block_hash);
+    let genesis_block_hash = blockchain.lock().unwrap().tip();
+    state.ico(genesis_block_hash, &accounts, amount);
+    info!("***** State After ICO *****");
+    state.print_last_block_state(&genesis_block_hash);
     info!("***************************");
+    let coinbase: H160 = match matches.value_of("coinbase") {
+        Some(coinbase) => parse_h160(coinbase).unwrap_or_else(|e| {
+            error!("Error parsing coinbase: {}", e);
+            process::exit(1);
+        }),
+        None => accounts[0],
+    };
+    info!("Coinbase of mined blocks and fruits: {:?}", coinbase);
     
 
 
@@ -303,6 +348,7 @@ fn main() {
         &vrf_secret_key,
         &vrf_public_key,
         selfish_node,
+        coinbase,
     );
     miner_ctx.start();
 
@@ -375,3 +421,14 @@ fn main() {
 
     
 }
+
+/// Parse a 20 byte hex account
+fn parse_h160(input: &str) -> Result<H160, String> {
+    let bytes = hex::decode(input).map_err(|e| format!("invalid hex {}: {}", input, e))?;
+    if bytes.len() != 20 {
+        return Err(format!("{} is not 20 bytes long", input));
+    }
+    let mut raw = [0u8; 20];
+    raw.copy_from_slice(&bytes);
+    Ok(raw.into())
+}
@@ -64,6 +64,7 @@ pub struct Context {
     vrf_secret_key: Vec<u8>,
     vrf_public_key: Vec<u8>,
     selfish_miner: bool,
+    coinbase: H160,
 }
 
 #[derive(Clone)]
@@ -87,6 +88,7 @@ pub fn new(
     vrf_secret_key: &Vec<u8>,
     vrf_public_key: &Vec<u8>,
     selfish_miner: bool,
+    coinbase: H160,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
 
@@ -107,6 +109,7 @@ pub fn new(
         vrf_secret_key: vrf_secret_key.clone(),
         vrf_public_key: vrf_public_key.clone(),
         selfish_miner: selfish_miner,
+        coinbase,
     };
 
     let handle = Handle {
@@ -340,6 +343,7 @@ impl Context {
             if enough_txn || enough_fruit {
                 let mut blk = generate_block(&data, &transaction_ref, &parent, rng.gen(), &difficulty, ts, &vrf_proof, &vrf_hash, 
                     &self.vrf_public_key, rand, self.selfish_miner);
+                blk.header.coinbase = self.coinbase;
                 loop {
                     // info!("Start mining!");
                     handle_fruit_context_update!(blk); 
@@ -372,7 +376,10 @@ impl Context {
                                 let block = self.blockchain.lock().unwrap().find_one_block(&blk_hash).unwrap();
                                 blocks.push(block);
                             }
-                            // self.state.lock().unwrap().update_blocks(&blocks);
+                            for block in &blocks {
+                                let fruits = self.blockchain.lock().unwrap().get_referenced_fruits(block);
+                                self.state.lock().unwrap().update_block(block, &fruits);
+                            }
 
                             // add txn_blocks back to the tranpool
                             for blk_hash in last_longest_chain {
@@ -1,10 +1,11 @@
 use super::message;
 use super::peer::{self, ReadResult, WriteResult};
+use crate::blockchain::Blockchain;
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 
 const MAX_INCOMING_CLIENT: usize = 256;
@@ -13,8 +14,11 @@ const MAX_EVENT: usize = 1024;
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    mut version: message::Version,
+    blockchain: &Arc<Mutex<Blockchain>>,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = channel::channel();
+    version.listen_port = addr.port();
     let handle = Handle {
         control_chan: control_signal_sender,
     };
@@ -25,6 +29,8 @@ pub fn new(
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
+        version,
+        blockchain: Arc::clone(blockchain),
         _handle: handle.clone(),
     };
     Ok((ctx, handle))
@@ -37,6 +43,9 @@ pub struct Context {
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
     new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    // our handshake, the best height is filled in for every connection
+    version: message::Version,
+    blockchain: Arc<Mutex<Blockchain>>,
     _handle: Handle,
 }
 
@@ -89,6 +98,11 @@ impl Context {
             mio::PollOpt::edge() | mio::PollOpt::oneshot(),
         )?;
 
+        // the handshake goes out before anything else
+        let mut version = self.version.clone();
+        version.best_height = self.blockchain.lock().unwrap().get_depth();
+        handle.write(message::Message::Version(version));
+
         // insert the context and return the handle
         vacant.insert(ctx);
         // record the key of this peer
@@ -134,7 +148,10 @@ impl Context {
             ControlSignal::BroadcastMessage(msg) => {
                 trace!("Processing BroadcastMessage command");
                 for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                    // peers still in the handshake only get the handshake
+                    if self.peers[*peer_id].verified {
+                        self.peers[*peer_id].handle.write(msg.clone());
+                    }
                 }
             }
         }
@@ -177,6 +194,36 @@ impl Context {
                 Ok(ReadResult::Message(m)) => {
                     trace!("Peer {} yield message", peer_id);
                     // we just received a full message
+                    if !peer.verified {
+                        // the peer sends its Version, then a Verack once it accepted ours
+                        let failure = match (bincode::deserialize(&m), &peer.version) {
+                            (Ok(message::Message::Version(version)), None) => match self.version.check(&version) {
+                                Ok(()) => {
+                                    debug!("Peer {} runs {} version {} at height {}", peer.addr, version.protocol, version.version, version.best_height);
+                                    peer.version = Some(version);
+                                    peer.handle.write(message::Message::Verack());
+                                    None
+                                }
+                                Err(reason) => Some(reason),
+                            },
+                            (Ok(message::Message::Verack()), Some(_)) => {
+                                peer.verified = true;
+                                None
+                            }
+                            (Ok(_), _) => Some("unexpected message before the handshake completed".to_string()),
+                            (Err(e), _) => Some(format!("undecodable handshake: {}", e)),
+                        };
+                        match failure {
+                            None => continue,
+                            Some(reason) => {
+                                warn!("Handshake with peer {} failed: {}, disconnecting", peer.addr, reason);
+                                self.peers.remove(peer_id);
+                                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+                                self.peer_list.swap_remove(index);
+                                break;
+                            }
+                        }
+                    }
                     self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
                     continue;
                 }
@@ -225,7 +225,10 @@ impl Context {
                                             let block = self.blockchain.lock().unwrap().find_one_block(&blk_hash).unwrap();
                                             blocks.push(block);
                                         }
-                                        // self.state.lock().unwrap().update_blocks(&blocks);
+                                        for block in &blocks {
+                                            let fruits = self.blockchain.lock().unwrap().get_referenced_fruits(block);
+                                            self.state.lock().unwrap().update_block(block, &fruits);
+                                        }
                                         
 
                                         // add txn_blocks back to the tranpool
@@ -4,13 +4,30 @@ use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm,
 use rand::Rng;
 use crate::crypto::hash::{self, Hashable, H256,generate_random_hash};
 use crate::crypto::key_pair;
+use crate::state::compute_key_hash;
 
 
+/// Version of the transaction format, transactions of other versions are invalid
+pub const TX_VERSION: u16 = 1;
+/// Longest memo a transaction may carry, in bytes
+pub const MAX_MEMO_SIZE: usize = 256;
+/// Prefix of the signed bytes of a transaction, so that signatures of other messages or other
+/// protocols are never valid transaction signatures
+const SIGN_DOMAIN: &[u8] = b"fruitchains/transaction";
+
 #[derive(Serialize, Deserialize, Debug, Default,Hash, Eq, PartialEq,Clone)]
 pub struct Transaction {
+    pub version: u16,
+    /// hash of the genesis block of the network the transaction is valid on
+    pub chain_id: H256,
+    /// account paying the value, the hash of the signing key
+    pub sender: hash::H160,
     pub recv: hash::H160,
     pub value: usize,
     pub nonce: usize,
+    /// last block height the transaction may be executed at
+    pub expiry: Option<u128>,
+    pub memo: Option<Vec<u8>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default,Hash, Eq, PartialEq,Clone)]
@@ -42,29 +59,38 @@ impl Hashable for SignedTransaction {
 }
 
 
+/// Bytes a transaction signature covers: the signing domain, then the bincode encoding
+pub fn signing_bytes(t: &Transaction) -> Vec<u8> {
+    [SIGN_DOMAIN, &bincode::serialize(&t).unwrap()].concat()
+}
+
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
-    let serialized: Vec<u8> = bincode::serialize(&t).unwrap();
-    key.sign(&serialized)
+    key.sign(&signing_bytes(t))
 }
 
 /// Verify digital signature of a transaction, using public key instead of secret key
 pub fn verify(t: &Transaction, public_key: &<Ed25519KeyPair as KeyPair>::PublicKey, signature: &Signature) -> bool {
-    let serialized: Vec<u8> = bincode::serialize(&t).unwrap();
-    let bytes: &[u8] = &serialized;
-    match VerificationAlgorithm::verify(&EdDSAParameters, public_key.as_ref().into(), bytes.into(), signature.as_ref().into()) {
+    let bytes = signing_bytes(t);
+    match VerificationAlgorithm::verify(&EdDSAParameters, public_key.as_ref().into(), bytes[..].into(), signature.as_ref().into()) {
         Ok(_) => true,
         Err(_e) => false,
     }
 }
 
+/// Whether a transaction has the current version, a memo of at most `MAX_MEMO_SIZE` bytes, and a
+/// valid signature by the key of its sender. The chain id and the expiry are checked by the state.
 pub fn verify_signedtxn(t: &SignedTransaction) -> bool {
-    let transaction = t.transaction.clone();
-    let pubk = t.sign.pubk.clone();
-    let sig = t.sign.sig.clone();
-    let serialized: Vec<u8> = bincode::serialize(&transaction).unwrap();
-    let bytes: &[u8] = &serialized;
-    match VerificationAlgorithm::verify(&EdDSAParameters, pubk[..].into(), bytes.into(), sig[..].into()) {
+    let transaction = &t.transaction;
+    if transaction.version != TX_VERSION || transaction.memo.as_ref().map_or(false, |memo| memo.len() > MAX_MEMO_SIZE) {
+        return false;
+    }
+    let sender: H160 = compute_key_hash(t.sign.pubk.clone()).into();
+    if sender != transaction.sender {
+        return false;
+    }
+    let bytes = signing_bytes(transaction);
+    match VerificationAlgorithm::verify(&EdDSAParameters, t.sign.pubk[..].into(), bytes[..].into(), t.sign.sig[..].into()) {
         Ok(_) => true,
         Err(_e) => false,
     }
@@ -76,21 +102,14 @@ pub fn generate_random_transaction() -> Transaction {
     recv:  hash::generate_random_hash().into(),
     value: rng.gen(),
     nonce: rng.gen(),
+    ..Default::default()
     }
 }
 
 pub fn generate_random_signed_transaction() -> SignedTransaction {
     let transaction = generate_random_transaction();
     let pubk = key_pair::random();
-    let sig = sign(&transaction, &pubk);
-    let sign = Sign {
-        pubk: pubk.public_key().as_ref().to_vec(),
-        sig: sig.as_ref().to_vec(),
-    };
-    SignedTransaction {
-        transaction,
-        sign,
-    }
+    sign_transaction(transaction, &pubk)
 }
 
 pub fn generate_valid_transaction(recv: H160, value: usize, nonce: usize) -> Transaction {
@@ -99,13 +118,21 @@ pub fn generate_valid_transaction(recv: H160, value: usize, nonce: usize) -> Tra
     recv:  recv,
     value: value,
     nonce: nonce,
+    ..Default::default()
     }
 }
 
 pub fn generate_valid_signed_transaction(recv: H160, value: usize, nonce: usize, pubk:&Ed25519KeyPair) -> SignedTransaction {
     let transaction = generate_valid_transaction(recv,value,nonce);
     //let pubk = key_pair::random();
-    let sig = sign(&transaction, &pubk);
+    sign_transaction(transaction, pubk)
+}
+
+/// Sign a transaction with the key of its sender, filling in the version and the sender
+pub fn sign_transaction(mut transaction: Transaction, pubk: &Ed25519KeyPair) -> SignedTransaction {
+    transaction.version = TX_VERSION;
+    transaction.sender = compute_key_hash(pubk.public_key().as_ref().to_vec()).into();
+    let sig = sign(&transaction, pubk);
     let sign = Sign {
         pubk: pubk.public_key().as_ref().to_vec(),
         sig: sig.as_ref().to_vec(),
@@ -119,15 +146,14 @@ pub fn generate_valid_signed_transaction(recv: H160, value: usize, nonce: usize,
 #[derive(PartialEq, Eq, Hash, Serialize)]
 pub struct SpamId {
     pub nonce: usize,
-    pub pubk: String,
+    pub sender: H160,
 }
 
 impl std::convert::From<&SignedTransaction> for SpamId {
     fn from(t: &SignedTransaction) -> Self {
-        let hash: H256 = t.sign.pubk.clone().into();
         SpamId {
             nonce: t.transaction.nonce,
-            pubk: hash.to_string(),
+            sender: t.transaction.sender,
         }
     }
 }
@@ -143,6 +169,8 @@ mod tests {
             recv: hash::generate_random_hash().into(),
             value: rng.gen(),
             nonce: rng.gen(),
+            memo: Some(b"memo".to_vec()),
+            ..Default::default()
         }
     }
 
@@ -158,5 +186,11 @@ mod tests {
     fn sign_verify2() {
         let t = generate_random_signed_transaction();
         assert!(verify_signedtxn(&t));
+        let mut other_sender = t.clone();
+        other_sender.transaction.sender = hash::generate_random_hash().into();
+        assert!(!verify_signedtxn(&other_sender));
+        let mut long_memo = t.clone();
+        long_memo.transaction.memo = Some(vec![0; MAX_MEMO_SIZE + 1]);
+        assert!(!verify_signedtxn(&long_memo));
     }
 }
@@ -4,7 +4,7 @@ use crate::transaction::generate_random_transaction;
 use crate::block::{Block, Header, Content};
 use crate::crypto::merkle::MerkleTree;
 use crate::crypto::hash::{H256,Hashable,generate_random_hash};
-use crate::transaction::{Transaction,SignedTransaction,generate_valid_signed_transaction,generate_random_signed_transaction};
+use crate::transaction::{Transaction,SignedTransaction,generate_valid_transaction,sign_transaction,generate_random_signed_transaction};
 use crate::network::server::Handle as ServerHandle;
 use crate::blockchain::Blockchain;
 use crate::network::message::Message;
@@ -175,7 +175,8 @@ impl Context {
             let recv:H160 = compute_key_hash(self.key_pairs[recv_index].public_key().as_ref().to_vec()).into();
             
             let value:usize = rng.gen_range(1, 10000001);
-            let tx = generate_valid_signed_transaction(recv, value, s_nonce, &pubk);
+            let chain_id = self.state.lock().unwrap().chain_id();
+            let tx = sign_transaction(Transaction { chain_id, ..generate_valid_transaction(recv, value, s_nonce) }, &pubk);
 
             self.mempool.lock().expect("txgenerator error 3").push(tx.clone());
             self.all_txns.lock().expect("txgenerator error 4").insert(tx.clone().hash(), tx.clone());
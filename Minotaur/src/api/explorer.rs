@@ -0,0 +1,179 @@
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H256, Hashable};
+use crate::resource::{self, STAKE};
+use crate::state::{SkipReason, State};
+use crate::transaction::SignedTransaction;
+use serde::Serialize;
+
+/// Most blocks `/chain/blocks` returns at once
+pub const MAX_PAGE: u128 = 100;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BlockInfo {
+    pub hash: String,
+    pub height: u128,
+    /// resource the block proves, `stake` for PoS blocks
+    pub resource: String,
+    /// PoS blocks only, whether the block is on the longest chain
+    pub on_longest_chain: bool,
+    pub parent: String,
+    pub timestamp: u128,
+    pub difficulties: Vec<String>,
+    pub merkle_root: String,
+    pub vrf_pub_key: String,
+    pub coinbase: String,
+    pub selfish: bool,
+    pub transaction_refs: Vec<String>,
+    pub transactions: Vec<String>,
+}
+
+impl BlockInfo {
+    pub fn new(blockchain: &Blockchain, block: &Block) -> Self {
+        let hash = block.hash();
+        BlockInfo {
+            hash: hash.to_string(),
+            height: blockchain.find_one_depth(&hash).unwrap_or(0),
+            resource: resource::builtin().get(block.block_type).map(|r| r.name().to_string()).unwrap_or_else(|| block.block_type.to_string()),
+            on_longest_chain: block.block_type == STAKE && blockchain.on_longest_chain(&hash),
+            parent: block.header.parent.to_string(),
+            timestamp: block.header.timestamp,
+            difficulties: block.header.difficulties.iter().map(|d| d.to_string()).collect(),
+            merkle_root: block.header.merkle_root.to_string(),
+            vrf_pub_key: hex::encode(&block.header.vrf_pub_key),
+            coinbase: block.header.coinbase.to_string(),
+            selfish: block.selfish_block,
+            transaction_refs: block.content.transaction_ref.iter().map(|h| h.to_string()).collect(),
+            transactions: block.content.data.iter().map(|t| t.hash().to_string()).collect(),
+        }
+    }
+}
+
+/// Where a transaction is in the ledger
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Inclusion {
+    /// only in the mempool
+    Pending,
+    /// in resource blocks that no PoS block of the longest chain references yet
+    Mined { blocks: Vec<String> },
+    /// executed by the first PoS block of the longest chain referencing one of its blocks
+    Confirmed { block: String, pos_block: String, height: u128, confirmations: u128 },
+    /// referenced by the longest chain but invalid when executed
+    Skipped { block: String, pos_block: String, height: u128, reason: SkipReason },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TxnInfo {
+    pub hash: String,
+    pub sender: String,
+    pub recv: String,
+    pub value: usize,
+    pub nonce: usize,
+    pub fee: usize,
+    pub bond: Option<String>,
+    pub chain_id: String,
+    pub expiry: Option<u128>,
+    /// hex encoded
+    pub memo: Option<String>,
+    #[serde(flatten)]
+    pub inclusion: Inclusion,
+}
+
+/// Look a transaction up in the blocks, then in `pending`, its mempool entry. `None` if the node
+/// has not seen it.
+pub fn find_txn(blockchain: &Blockchain, state: &State, pending: Option<&SignedTransaction>, hash: &H256) -> Option<TxnInfo> {
+    let blocks = blockchain.find_txn_blocks(hash);
+    let txn = match blocks.first() {
+        Some(block) => blockchain.find_one_block(block)?.content.data.into_iter().find(|t| t.hash() == *hash)?,
+        None => pending?.clone(),
+    };
+    // the first reference on the longest chain executes it
+    let mut first: Option<(u128, H256, H256)> = None;
+    for block in blocks.iter() {
+        for pos_block in blockchain.find_referencing_blocks(block) {
+            if !blockchain.on_longest_chain(&pos_block) {
+                continue;
+            }
+            let height = blockchain.find_one_depth(&pos_block).unwrap();
+            if first.map_or(true, |(h, _, _)| height < h) {
+                first = Some((height, *block, pos_block));
+            }
+        }
+    }
+    let inclusion = match first {
+        None if blocks.is_empty() => Inclusion::Pending,
+        None => Inclusion::Mined { blocks: blocks.iter().map(|h| h.to_string()).collect() },
+        Some((height, block, pos_block)) => {
+            match state.skipped_txns(&pos_block).into_iter().find(|(t, _)| t == hash) {
+                Some((_, reason)) => Inclusion::Skipped {
+                    block: block.to_string(),
+                    pos_block: pos_block.to_string(),
+                    height,
+                    reason,
+                },
+                None => Inclusion::Confirmed {
+                    block: block.to_string(),
+                    pos_block: pos_block.to_string(),
+                    height,
+                    confirmations: blockchain.get_depth() - height + 1,
+                },
+            }
+        }
+    };
+    Some(TxnInfo {
+        hash: hash.to_string(),
+        sender: txn.transaction.sender.to_string(),
+        recv: txn.transaction.recv.to_string(),
+        value: txn.transaction.value,
+        nonce: txn.transaction.nonce,
+        fee: txn.transaction.fee,
+        bond: txn.transaction.bond.as_ref().map(hex::encode),
+        chain_id: txn.transaction.chain_id.to_string(),
+        expiry: txn.transaction.expiry,
+        memo: txn.transaction.memo.as_ref().map(hex::encode),
+        inclusion,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{generate_pos_block, generate_pow_block};
+    use crate::blockchain::ForkChoice;
+    use crate::genesis::GenesisSpec;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn txn_inclusion() {
+        let mut blockchain = Blockchain::new(0, &GenesisSpec::default(), ForkChoice::LongestChain);
+        let state = State::new();
+        let genesis = blockchain.tip();
+        let txn = SignedTransaction {
+            transaction: Transaction { value: 5, nonce: 1, ..Default::default() },
+            sign: Default::default(),
+        };
+        let hash = txn.hash();
+        assert_eq!(find_txn(&blockchain, &state, None, &hash), None);
+        let pending = find_txn(&blockchain, &state, Some(&txn), &hash).unwrap();
+        assert_eq!(pending.inclusion, Inclusion::Pending);
+        assert_eq!(pending.value, 5);
+
+        let pow = generate_pow_block(&vec![txn.clone()], &vec![], &genesis, 0, &[genesis, genesis], 1, &vec![], &vec![], &vec![], 0, false);
+        blockchain.insert_pow(&pow).unwrap();
+        assert_eq!(find_txn(&blockchain, &state, None, &hash).unwrap().inclusion, Inclusion::Mined { blocks: vec![pow.hash().to_string()] });
+
+        let pos = generate_pos_block(&vec![], &vec![pow.hash()], &genesis, 0, &[], 2, &vec![], &vec![], &[], 0, false);
+        assert!(blockchain.insert_pos(&pos, false).unwrap());
+        let next = generate_pos_block(&vec![], &vec![], &pos.hash(), 0, &[], 3, &vec![], &vec![], &[], 0, false);
+        assert!(blockchain.insert_pos(&next, false).unwrap());
+        assert_eq!(find_txn(&blockchain, &state, None, &hash).unwrap().inclusion, Inclusion::Confirmed {
+            block: pow.hash().to_string(),
+            pos_block: pos.hash().to_string(),
+            height: 1,
+            confirmations: 2,
+        });
+        assert_eq!(blockchain.longest_chain_range(1, 10), vec![pos.hash(), next.hash()]);
+        assert_eq!(blockchain.longest_chain_range(0, 1), vec![genesis]);
+    }
+}
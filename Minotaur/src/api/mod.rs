@@ -1,23 +1,38 @@
+pub mod explorer;
+
 use serde::Serialize;
 use crate::blockchain::Blockchain;
-use crate::crypto::hash::H256;
+use crate::crypto::hash::{H256, Hashable};
 use crate::miner::Handle as MinerHandle;
 use crate::staker::Handle as StakerHandle;
 use crate::spv::Handle as SPVHandle;
-use crate::transaction::SignedTransaction;
+use crate::transaction::{SignedTransaction, MAX_TXN_BYTES};
 use crate::transaction::SpamId;
+use crate::state::{State, SkipReason};
+use crate::mempool::{self, Mempool, MempoolError};
+use crate::wallet;
 //use crate::fly::Handle as FlyHandle;
 use crate::txgenerator::Handle as TxgeneratorHandle;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::network::sync::HeaderSync;
+use crate::network::addrbook::{AddrBook, AddrEntry};
+use crate::network::server::PeerInfo;
+use crate::network::scores::{Ban, PeerScores};
+use crate::metrics::{Metrics, Stats};
+use crate::block::Block;
+use crate::genesis::{parse_h160, parse_h256};
+use self::explorer::{BlockInfo, MAX_PAGE};
 
 use log::info;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::io::Read;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
 use tiny_http::Header;
+use tiny_http::Method;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
@@ -31,6 +46,16 @@ pub struct Server {
     spv: SPVHandle,
     //fly: FlyHandle
     blockchain: Arc<Mutex<Blockchain>>,
+    state: Arc<Mutex<State>>,
+    sync: Arc<Mutex<HeaderSync>>,
+    addr_book: Arc<Mutex<AddrBook>>,
+    scores: Arc<Mutex<PeerScores>>,
+    metrics: Arc<Mutex<Metrics>>,
+    buffer: Arc<Mutex<HashMap<H256,Block>>>,
+    mempool: Arc<Mutex<Mempool>>,
+    tranpool: Arc<Mutex<Vec<H256>>>,
+    delays: Arc<Mutex<Vec<u128>>>,
+    all_txns: Arc<Mutex<HashMap<H256,SignedTransaction>>>,
 }
 
 #[derive(Serialize)]
@@ -70,6 +95,16 @@ impl Server {
         network: &NetworkServerHandle,
         spv: &SPVHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
+        state: &Arc<Mutex<State>>,
+        sync: &Arc<Mutex<HeaderSync>>,
+        addr_book: &Arc<Mutex<AddrBook>>,
+        scores: &Arc<Mutex<PeerScores>>,
+        metrics: &Arc<Mutex<Metrics>>,
+        buffer: &Arc<Mutex<HashMap<H256,Block>>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        tranpool: &Arc<Mutex<Vec<H256>>>,
+        delays: &Arc<Mutex<Vec<u128>>>,
+        all_txns: &Arc<Mutex<HashMap<H256,SignedTransaction>>>,
         //fly: &FlyHandle,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
@@ -82,6 +117,16 @@ impl Server {
             spv: spv.clone(),
             //fly: fly.clone(),
             blockchain: Arc::clone(blockchain),
+            state: Arc::clone(state),
+            sync: Arc::clone(sync),
+            addr_book: Arc::clone(addr_book),
+            scores: Arc::clone(scores),
+            metrics: Arc::clone(metrics),
+            buffer: Arc::clone(buffer),
+            mempool: Arc::clone(mempool),
+            tranpool: Arc::clone(tranpool),
+            delays: Arc::clone(delays),
+            all_txns: Arc::clone(all_txns),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
@@ -91,6 +136,16 @@ impl Server {
                 let network = server.network.clone();
                 let spv = server.spv.clone();
                 let blockchain = Arc::clone(&server.blockchain);
+                let state = Arc::clone(&server.state);
+                let sync = Arc::clone(&server.sync);
+                let addr_book = Arc::clone(&server.addr_book);
+                let scores = Arc::clone(&server.scores);
+                let metrics = Arc::clone(&server.metrics);
+                let buffer = Arc::clone(&server.buffer);
+                let mempool = Arc::clone(&server.mempool);
+                let tranpool = Arc::clone(&server.tranpool);
+                let delays = Arc::clone(&server.delays);
+                let all_txns = Arc::clone(&server.all_txns);
                 //let fly = server.fly.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
@@ -255,6 +310,308 @@ impl Server {
                                 spam_ratio: 1f32-(unique_num as f32)/(total_num as f32),
                             });
                         }
+                        "/tx" => {
+                            let mut req = req;
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            // hex takes 2 characters per byte of a transaction, JSON up to 4; read at
+                            // most one byte more than that to spot larger bodies
+                            let max_body = 4 * MAX_TXN_BYTES as u64;
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().take(max_body + 1).read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            if body.len() as u64 > max_body {
+                                respond_result!(req, false, format!("body larger than {} bytes", max_body));
+                                return;
+                            }
+                            let txn = match wallet::decode(&body) {
+                                Ok(txn) => txn,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let hash = txn.hash();
+                            if all_txns.lock().unwrap().contains_key(&hash) {
+                                respond_result!(req, false, format!("transaction {} is already known", hash));
+                                return;
+                            }
+                            let inserted = mempool::insert(&mempool, &blockchain, &state, txn.clone()).map_err(|e| match e {
+                                MempoolError::Known => format!("transaction {} is already in the mempool", hash),
+                                MempoolError::Invalid(reason) => format!("invalid transaction: {:?}", reason),
+                                MempoolError::NonceTaken => format!("another transaction of the sender with this nonce is pending, replacing it takes a {}% higher fee", mempool::REPLACE_BUMP),
+                                MempoolError::Full => "mempool is full".to_string(),
+                                MempoolError::NoState => "state of the tip is not computed yet".to_string(),
+                            });
+                            if let Err(e) = inserted {
+                                respond_result!(req, false, e);
+                                return;
+                            }
+                            all_txns.lock().unwrap().insert(hash, txn);
+                            network.broadcast(Message::NewTransactionHashes(vec![hash]));
+                            respond_result!(req, true, hash);
+                        }
+                        "/ledger/tx" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let hash = match params.get("hash").map(|h| parse_h256(h)) {
+                                Some(Ok(h)) => h,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing hash: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing hash");
+                                    return;
+                                }
+                            };
+                            let pending = mempool.lock().unwrap().get(&hash).cloned();
+                            let txn = {
+                                let blockchain = blockchain.lock().unwrap();
+                                let state = state.lock().unwrap();
+                                explorer::find_txn(&blockchain, &state, pending.as_ref(), &hash)
+                            };
+                            match txn {
+                                Some(txn) => respond_json!(req, txn),
+                                None => respond_result!(req, false, "transaction not found"),
+                            }
+                        }
+                        "/ledger/account" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let account = match params.get("address").map(|a| parse_h160(a)) {
+                                Some(Ok(a)) => a,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let tip = blockchain.lock().unwrap().tip();
+                            let state = state.lock().unwrap();
+                            let (nonce, balance) = match state.state_per_block.get(&tip) {
+                                Some(accounts) => accounts.get(&account).cloned().unwrap_or((0, 0)),
+                                None => {
+                                    respond_result!(req, false, "state of the tip is not computed yet");
+                                    return;
+                                }
+                            };
+                            #[derive(Serialize)]
+                            struct Account {
+                                account: String,
+                                tip: String,
+                                nonce: usize,
+                                balance: usize,
+                            }
+                            respond_json!(req, Account {
+                                account: account.to_string(),
+                                tip: tip.to_string(),
+                                nonce,
+                                balance,
+                            });
+                        }
+                        "/chain/tip" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let tip = blockchain.find_one_block(&blockchain.tip()).unwrap();
+                            respond_json!(req, BlockInfo::new(&blockchain, &tip));
+                        }
+                        "/chain/block" | "/chain/block/refs" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let blockchain = blockchain.lock().unwrap();
+                            let hash = if let Some(hash) = params.get("hash") {
+                                match parse_h256(hash) {
+                                    Ok(h) => h,
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing hash: {}", e));
+                                        return;
+                                    }
+                                }
+                            } else if let Some(height) = params.get("height") {
+                                let height = match height.parse::<u128>() {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing height: {}", e));
+                                        return;
+                                    }
+                                };
+                                match blockchain.longest_chain_range(height, height + 1).pop() {
+                                    Some(h) => h,
+                                    None => {
+                                        respond_result!(req, false, "height is above the tip");
+                                        return;
+                                    }
+                                }
+                            } else {
+                                respond_result!(req, false, "missing hash or height");
+                                return;
+                            };
+                            let block = match blockchain.find_one_block(&hash) {
+                                Some(b) => b,
+                                None => {
+                                    respond_result!(req, false, "block not found");
+                                    return;
+                                }
+                            };
+                            if url.path() == "/chain/block" {
+                                respond_json!(req, BlockInfo::new(&blockchain, &block));
+                            } else {
+                                let refs: Vec<BlockInfo> = block.content.transaction_ref.iter()
+                                    .filter_map(|h| blockchain.find_one_block(h))
+                                    .map(|b| BlockInfo::new(&blockchain, &b))
+                                    .collect();
+                                respond_json!(req, refs);
+                            }
+                        }
+                        "/chain/blocks" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let start = match params.get("start").map(|v| v.parse::<u128>()).unwrap_or(Ok(0)) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing start: {}", e));
+                                    return;
+                                }
+                            };
+                            let limit = match params.get("limit").map(|v| v.parse::<u128>()).unwrap_or(Ok(MAX_PAGE)) {
+                                Ok(v) => v.min(MAX_PAGE),
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing limit: {}", e));
+                                    return;
+                                }
+                            };
+                            #[derive(Serialize)]
+                            struct Page {
+                                depth: u128,
+                                blocks: Vec<BlockInfo>,
+                            }
+                            let blockchain = blockchain.lock().unwrap();
+                            let blocks = blockchain.longest_chain_range(start, start.saturating_add(limit)).iter()
+                                .map(|h| BlockInfo::new(&blockchain, &blockchain.find_one_block(h).unwrap()))
+                                .collect();
+                            respond_json!(req, Page {
+                                depth: blockchain.get_depth(),
+                                blocks,
+                            });
+                        }
+                        "/ledger/state" => {
+                            let (tip, pos_blocks) = {
+                                let blockchain = blockchain.lock().unwrap();
+                                (blockchain.tip(), blockchain.all_blocks_in_longest_chain())
+                            };
+                            let state = state.lock().unwrap();
+                            if !state.state_per_block.contains_key(&tip) {
+                                respond_result!(req, false, "state of the tip is not computed yet");
+                                return;
+                            }
+                            #[derive(Serialize)]
+                            struct Account {
+                                account: String,
+                                nonce: usize,
+                                balance: usize,
+                            }
+                            #[derive(Serialize)]
+                            struct Stake {
+                                vrf_pub_key: String,
+                                stake: usize,
+                            }
+                            #[derive(Serialize)]
+                            struct SkippedTxn {
+                                block: String,
+                                txn: String,
+                                reason: SkipReason,
+                            }
+                            #[derive(Serialize)]
+                            struct LedgerState {
+                                tip: String,
+                                accounts: Vec<Account>,
+                                stakes: Vec<Stake>,
+                                skipped: Vec<SkippedTxn>,
+                            }
+                            let accounts = state.state_per_block.get(&tip).unwrap().iter()
+                                .map(|(account, (nonce, balance))| Account {
+                                    account: account.to_string(),
+                                    nonce: *nonce,
+                                    balance: *balance,
+                                }).collect();
+                            let stakes = state.one_block_stake(&tip).unwrap_or_default().into_iter()
+                                .map(|(vrf_pub_key, stake)| Stake {
+                                    vrf_pub_key: hex::encode(vrf_pub_key),
+                                    stake,
+                                }).collect();
+                            let skipped = pos_blocks.iter()
+                                .map(|h| state.skipped_txns(h).into_iter().map(move |(txn, reason)| SkippedTxn {
+                                    block: h.to_string(),
+                                    txn: txn.to_string(),
+                                    reason,
+                                }))
+                                .flatten().collect();
+                            respond_json!(req, LedgerState {
+                                tip: tip.to_string(),
+                                accounts,
+                                stakes,
+                                skipped,
+                            });
+                        }
+                        "/network/sync" => {
+                            let progress = {
+                                let blockchain = blockchain.lock().unwrap();
+                                sync.lock().unwrap().progress(&blockchain)
+                            };
+                            respond_json!(req, progress);
+                        }
+                        "/network/peers" => {
+                            #[derive(Serialize)]
+                            struct Peers {
+                                connected: Vec<PeerInfo>,
+                                known: Vec<AddrEntry>,
+                            }
+                            let connected = network.peers();
+                            let known = addr_book.lock().unwrap().entries();
+                            respond_json!(req, Peers { connected, known });
+                        }
+                        "/network/bans" => {
+                            #[derive(Serialize)]
+                            struct Score {
+                                addr: std::net::SocketAddr,
+                                score: i64,
+                            }
+                            #[derive(Serialize)]
+                            struct Bans {
+                                bans: Vec<Ban>,
+                                scores: Vec<Score>,
+                            }
+                            let peers = network.peers();
+                            let scores = scores.lock().unwrap();
+                            respond_json!(req, Bans {
+                                bans: scores.bans(),
+                                scores: peers.iter().map(|peer| Score {
+                                    addr: peer.listen_addr.unwrap_or(peer.addr),
+                                    score: scores.score(&peer.listen_addr.unwrap_or(peer.addr)),
+                                }).collect(),
+                            });
+                        }
+                        "/stats" | "/metrics" => {
+                            let metrics = metrics.lock().unwrap().clone();
+                            let orphan_buffer = buffer.lock().unwrap().len();
+                            let mempool = mempool.lock().unwrap().len();
+                            let tranpool = tranpool.lock().unwrap().len();
+                            let delays = delays.lock().unwrap().clone();
+                            let stats = {
+                                let blockchain = blockchain.lock().unwrap();
+                                Stats::new(&metrics, &blockchain, orphan_buffer, mempool, tranpool, &delays)
+                            };
+                            if url.path() == "/stats" {
+                                respond_json!(req, stats);
+                            } else {
+                                let content_type = "Content-Type: text/plain; version=0.0.4".parse::<Header>().unwrap();
+                                let resp = Response::from_string(stats.to_prometheus()).with_header(content_type);
+                                req.respond(resp).unwrap();
+                            }
+                        }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
@@ -1,20 +1,39 @@
-use crate::state::compute_key_hash;
-use crate::transaction::{Transaction, SignedTransaction, generate_random_transaction, generate_random_signed_transaction};
+use crate::transaction::{Transaction, SignedTransaction, generate_random_transaction, generate_random_signed_transaction, verify_signedtxns, VerifyPool};
 use serde::{Serialize, Deserialize};
-use crate::crypto::hash::{self, H256, Hashable,generate_random_hash};
+use crate::crypto::hash::{self, H160, H256, Hashable,generate_random_hash};
 use rand::Rng;
 use crate::crypto::merkle::MerkleTree;
+use crate::genesis::GenesisSpec;
+use crate::resource::{ResourceId, STAKE, WORK};
 use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
 use tari_mmr::{MerkleMountainRange, MerkleProof, Hash};
 use sha2::{Digest, Sha256};
 use log::info;
+use vrf::openssl::ECVRF;
+use vrf::VRF;
 
 
+/// Most transactions a PoW block may carry
+pub const MAX_BLOCK_TXNS: usize = 4096;
+/// Most bytes the transactions of a PoW block may take, serialized
+pub const MAX_BLOCK_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Why the transactions of a block do not match its header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidBlock {
+    NoTxns,
+    TooManyTxns(usize),
+    TooLarge(u64),
+    BadSignature(H256),
+    BadMerkleRoot { expected: H256, got: H256 },
+    BadHeaderSignature,
+}
+
 #[derive(Serialize, Deserialize, Debug,Hash, Eq, PartialEq,Clone)]
 pub struct Block {
     pub header: Header,
     pub content: Content,
-    pub block_type: bool,  // true for PoS, false for PoW
+    pub block_type: ResourceId,  // STAKE for PoS, WORK for PoW
     pub selfish_block: bool,
 }
 
@@ -22,15 +41,16 @@ pub struct Block {
 pub struct Header {
     pub parent: H256,
     pub nonce: u32,
-    pub pow_difficulty: H256,
-    pub pos_difficulty: H256,
+    pub difficulties: Vec<H256>,  // target of every resource, indexed by ResourceId
     pub timestamp: u128,  // TODO: use current time
     pub merkle_root: H256,
     //pub mmr_root: Hash,  //ignore this for now
     pub vrf_proof: Vec<u8>,
     pub vrf_hash: Vec<u8>,
     pub vrf_pub_key: Vec<u8>,
-    pub rand: u128,     // randomness for PoS leader election. TODO: update rand every epoch 
+    pub rand: u128,     // randomness for PoS leader election, the beacon of the block's epoch
+    pub signature: Vec<u8>,  // PoS only, VRF proof by vrf_pub_key over signing_hash()
+    pub coinbase: H160,  // account credited with the fees of the block's transactions
 }
 
 #[derive(Serialize, Deserialize, Debug,Hash, Eq, PartialEq,Clone)]
@@ -54,24 +74,104 @@ impl Hashable for Content {
     }
 }
 
+impl Content {
+    /// Commitment to the whole body, PoS headers carry it as merkle root
+    pub fn commitment(&self) -> H256 {
+        let serialized: Vec<u8> = bincode::serialize(&self).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &serialized).into()
+    }
+}
+
 impl Hashable for Block {
     fn hash(&self) -> H256 {
         self.header.hash()
     }
 }
 
+impl Header {
+    /// Target of a resource, the easiest one if the header does not carry it
+    pub fn difficulty(&self, resource: ResourceId) -> H256 {
+        self.difficulties.get(resource).cloned().unwrap_or_else(|| [255u8; 32].into())
+    }
+
+    /// Hash of the header without its signature, this is what PoS stakers sign
+    pub fn signing_hash(&self) -> H256 {
+        let mut unsigned = self.clone();
+        unsigned.signature = Vec::new();
+        unsigned.hash()
+    }
+
+    /// Whether the signature of a PoS header is by its VRF key
+    pub fn verify_signature(&self, vrf: &mut ECVRF) -> bool {
+        vrf.verify(&self.vrf_pub_key, &self.signature, self.signing_hash().as_ref()).is_ok()
+    }
+
+    /// Whether a PoS header carries a VRF output for its slot that meets its own stake target
+    pub fn verify_vrf(&self, vrf: &mut ECVRF, slot: u128) -> bool {
+        let message = [self.rand.to_be_bytes(), slot.to_be_bytes()].concat();
+        match vrf.verify(&self.vrf_pub_key, &self.vrf_proof, &message) {
+            Ok(vrf_hash) if vrf_hash == self.vrf_hash => {
+                let vrf_hash_sha256: H256 = ring::digest::digest(&ring::digest::SHA256, &vrf_hash).into();
+                vrf_hash_sha256 <= self.difficulty(STAKE)
+            }
+            _ => false,
+        }
+    }
+}
+
 impl Block {
     pub fn print_txns(&self) {
         let txns = self.content.data.clone();
         info!("***** Print txns in block {:?} *****", self.hash());
         for txn in txns {
-            let sender = compute_key_hash(txn.sign.pubk);
+            let sender = txn.transaction.sender;
             let recv = txn.transaction.recv;
             info!("{:?} sends {:?} value {:?}", sender, recv, txn.transaction.value);
         }
         info!("*************************************");
     }
 
+    /// Check the transactions of a PoW block: count and size limits, signatures and merkle root
+    pub fn validate_txns(&self, pool: &VerifyPool) -> Result<(), InvalidBlock> {
+        let data = &self.content.data;
+        if data.is_empty() {
+            return Err(InvalidBlock::NoTxns);
+        }
+        if data.len() > MAX_BLOCK_TXNS {
+            return Err(InvalidBlock::TooManyTxns(data.len()));
+        }
+        let size = bincode::serialized_size(data).unwrap();
+        if size > MAX_BLOCK_BYTES {
+            return Err(InvalidBlock::TooLarge(size));
+        }
+        if let Some(bad) = verify_signedtxns(pool, data).into_iter().position(|ok| !ok) {
+            return Err(InvalidBlock::BadSignature(data[bad].hash()));
+        }
+        let root = MerkleTree::new(data).root();
+        if root != self.header.merkle_root {
+            return Err(InvalidBlock::BadMerkleRoot { expected: self.header.merkle_root, got: root });
+        }
+        Ok(())
+    }
+
+    /// Sign the header of a PoS block with the VRF secret key of its staker
+    pub fn sign(&mut self, vrf: &mut ECVRF, vrf_secret_key: &[u8]) {
+        let signing_hash = self.header.signing_hash();
+        self.header.signature = vrf.prove(vrf_secret_key, signing_hash.as_ref()).unwrap();
+    }
+
+    /// Check that the body of a PoS block is the one its staker signed
+    pub fn validate_signature(&self, vrf: &mut ECVRF) -> Result<(), InvalidBlock> {
+        let commitment = self.content.commitment();
+        if commitment != self.header.merkle_root {
+            return Err(InvalidBlock::BadMerkleRoot { expected: self.header.merkle_root, got: commitment });
+        }
+        if !self.header.verify_signature(vrf) {
+            return Err(InvalidBlock::BadHeaderSignature);
+        }
+        Ok(())
+    }
+
     pub fn clear_txns(&mut self) {
         self.content.data = Vec::new();
     }
@@ -79,11 +179,11 @@ impl Block {
 
 
 
-pub fn generate_pos_block(data: &Vec<SignedTransaction>, transaction_ref: &Vec<H256>, parent: &H256, nonce: u32, pow_difficulty: &H256, pos_difficulty: &H256,
+pub fn generate_pos_block(data: &Vec<SignedTransaction>, transaction_ref: &Vec<H256>, parent: &H256, nonce: u32, difficulties: &[H256],
                       timestamp: u128, vrf_proof: &Vec<u8>, vrf_hash: &Vec<u8>, 
                       vrf_pub_key: &[u8], rand: u128, selfish_block: bool) -> Block {
     // let mt: MerkleTree = MerkleTree::new(transaction_ref);
-    let block_type = true; 
+    let block_type = STAKE;
     let content = Content {
         data: data.to_vec(),
         transaction_ref: transaction_ref.to_vec()
@@ -91,15 +191,16 @@ pub fn generate_pos_block(data: &Vec<SignedTransaction>, transaction_ref: &Vec<H
     let header = Header {
         parent: *parent,
         nonce: nonce,
-        pow_difficulty: *pow_difficulty, 
-        pos_difficulty: *pos_difficulty, 
+        difficulties: difficulties.to_vec(),
         timestamp: timestamp,
-        merkle_root: Default::default(),//use default instead of merkle tree to avoid merkle tree bug of empty tree
+        merkle_root: content.commitment(),  // the staker signs the header, so it has to commit to the body
         //mmr_root: parent_mmr.get_merkle_root().unwrap(),
         vrf_proof: vrf_proof.to_vec(),
         vrf_hash: vrf_hash.to_vec(),
         vrf_pub_key: vrf_pub_key.to_vec(),
         rand: rand,
+        signature: Vec::new(),
+        coinbase: Default::default(),
     };
     Block {
         header,
@@ -109,11 +210,11 @@ pub fn generate_pos_block(data: &Vec<SignedTransaction>, transaction_ref: &Vec<H
    }
 }
 
-pub fn generate_pow_block(data: &Vec<SignedTransaction>, transaction_ref: &Vec<H256>, parent: &H256, nonce: u32, pow_difficulty: &H256, pos_difficulty: &H256, 
+pub fn generate_pow_block(data: &Vec<SignedTransaction>, transaction_ref: &Vec<H256>, parent: &H256, nonce: u32, difficulties: &[H256],
                       timestamp: u128, vrf_proof: &Vec<u8>, vrf_hash: &Vec<u8>, 
                       vrf_pub_key: &[u8], rand: u128, selfish_block: bool) -> Block {
     let mt: MerkleTree = MerkleTree::new(data);
-    let block_type = false; 
+    let block_type = WORK;
     let content = Content {
         data: data.to_vec(),
         transaction_ref: transaction_ref.to_vec()
@@ -121,8 +222,7 @@ pub fn generate_pow_block(data: &Vec<SignedTransaction>, transaction_ref: &Vec<H
     let header = Header {
         parent: *parent,
         nonce: nonce,
-        pow_difficulty: *pow_difficulty, 
-        pos_difficulty: *pos_difficulty,
+        difficulties: difficulties.to_vec(),
         timestamp: timestamp,
         merkle_root: mt.root(),
         //mmr_root: parent_mmr.get_merkle_root().unwrap(),
@@ -130,6 +230,8 @@ pub fn generate_pow_block(data: &Vec<SignedTransaction>, transaction_ref: &Vec<H
         vrf_hash: vrf_hash.to_vec(),
         vrf_pub_key: vrf_pub_key.to_vec(),
         rand: rand,
+        signature: Vec::new(),
+        coinbase: Default::default(),
     };
     Block {
         header,
@@ -139,29 +241,27 @@ pub fn generate_pow_block(data: &Vec<SignedTransaction>, transaction_ref: &Vec<H
    }
 }
 
-pub fn generate_genesis_block(initial_time:u128) -> Block {
+/// The genesis block commits to the genesis spec through its merkle root
+pub fn generate_genesis_block(initial_time:u128, spec: &GenesisSpec) -> Block {
     let content = Content {
         data: Default::default(),
         transaction_ref: Default::default(),
     };
-    let block_type = true;
+    let block_type = STAKE;
     let selfish_block = false;
     let header = Header {
         parent: Default::default(),
         nonce: Default::default(),
-        //pow_difficulty: <H256>::from([1; 32]), 
-        pow_difficulty: <H256>::from([
-            0, 40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
-        ]),
-        pos_difficulty: <H256>::from([1; 32]), 
+        difficulties: spec.difficulties(),
         timestamp: initial_time,
-        merkle_root: Default::default(),
+        merkle_root: spec.hash(),
         // mmr_root: MerkleMountainRange::<Sha256, Vec<Hash>>::new(Vec::new()).get_merkle_root().unwrap(),
         vrf_proof: Default::default(),
         vrf_hash: Default::default(),
         vrf_pub_key: Default::default(),
         rand: Default::default(),
+        signature: Default::default(),
+        coinbase: Default::default(),
     };
     Block {
         header,
@@ -224,3 +324,30 @@ pub fn generate_genesis_block(initial_time:u128) -> Block {
 //        }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vrf::openssl::CipherSuite;
+
+    #[test]
+    fn pos_signature() {
+        let mut vrf = ECVRF::from_suite(CipherSuite::SECP256K1_SHA256_TAI).unwrap();
+        let secret_key = hex::decode("c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721").unwrap();
+        let public_key = vrf.derive_public_key(&secret_key).unwrap();
+        let mut blk = generate_pos_block(&vec![], &vec![generate_random_hash()], &generate_random_hash(), 0, &[],
+            1, &vec![], &vec![], &public_key, 0, false);
+        blk.sign(&mut vrf, &secret_key);
+        assert_eq!(blk.validate_signature(&mut vrf), Ok(()));
+
+        // another parent invalidates the signature
+        let mut moved = blk.clone();
+        moved.header.parent = generate_random_hash();
+        assert_eq!(moved.validate_signature(&mut vrf), Err(InvalidBlock::BadHeaderSignature));
+
+        // so does another body
+        let mut altered = blk.clone();
+        altered.content.transaction_ref.push(generate_random_hash());
+        assert!(altered.validate_signature(&mut vrf).is_err());
+    }
+}
@@ -1,7 +1,12 @@
 use crate::block::generate_genesis_block;
 use crate::block::{Block,Header};
 use crate::crypto::hash::{H256,Hashable,hash_divide_by};
+use crate::genesis::GenesisSpec;
+use crate::resource::{ResourceId, STAKE, WORK};
+use crate::store::{BlockStore, MemoryStore};
+use crate::transaction::SignedTransaction;
 use std::collections::{HashMap,HashSet};
+use std::io;
 use serde::{Serialize, Deserialize};
 //use crate::block::generate_random_block;
 use log::{debug, warn,info};
@@ -11,95 +16,152 @@ use rand::Rng;
 
 #[derive(Serialize, Deserialize,Hash, Eq, PartialEq, Debug,Clone)]
 pub struct Data {
-    blk: Block,
-    height: u128,
+    pub blk: Block,
+    pub height: u128,
+}
+
+/// How the tip is chosen among competing PoS forks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForkChoice {
+    /// the highest PoS block wins
+    LongestChain,
+    /// the fork with the largest cumulative weight wins: one per PoS block plus the resource weight
+    /// of every block it references for the first time on the fork
+    Weighted,
+}
+
+impl std::str::FromStr for ForkChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "longest" => Ok(ForkChoice::LongestChain),
+            "weighted" => Ok(ForkChoice::Weighted),
+            _ => Err(format!("unknown fork choice rule {}, expected longest or weighted", s)),
+        }
+    }
 }
 
 pub struct Blockchain { 
-	chain: HashMap<H256,Data>,
-	map: HashMap<H256,MerkleMountainRange<Sha256, Vec<Hash>>>,
+	chain: Box<dyn BlockStore>,
+    fork_choice: ForkChoice,
+    resource_weights: Vec<f64>,
+    // resource of every non PoS block
+    block_types: HashMap<H256,ResourceId>,
+    // cumulative weight of every PoS block
+    weights: HashMap<H256,f64>,
+    // epoch boundary of every PoS block, the last block before its epoch on its chain
+    boundaries: HashMap<H256,H256>,
+    // PoS blocks referencing each resource block
+    pow_referenced_by: HashMap<H256,Vec<H256>>,
+    // resource blocks carrying each transaction
+    txn_blocks: HashMap<H256,Vec<H256>>,
     tip: H256,
     depth: u128,
     num_pos: u128,
     num_pow: u128,
+    num_rejected_pow: u128,
     epoch_size: u128,
     epoch_time: u128,
+    slot_time: u128,
     genesis_time: u128,
     pub_len: u128,
     private_lead: u128,
+    // block signed by each (VRF public key, slot)
+    slot_producers: HashMap<(Vec<u8>,u128),H256>,
+    // (VRF public key, slot, first block, second block)
+    equivocations: Vec<(Vec<u8>,u128,H256,H256)>,
+    // VRF public keys of the producers that equivocated, their later blocks are refused
+    equivocators: HashSet<Vec<u8>>,
 }
 
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
-    pub fn new(initial_time:u128) -> Self {
-        //unimplemented!()
-		let genesis = generate_genesis_block(initial_time);
+    pub fn new(initial_time:u128, spec: &GenesisSpec, fork_choice: ForkChoice) -> Self {
+		Self::with_store(initial_time, spec, fork_choice, false, Box::new(MemoryStore::new())).unwrap()
+    }
+
+    /// Create a blockchain on top of a block store, resuming from the blocks already in it.
+    /// `selfish` replays the blocks the way a selfish miner inserted them.
+    pub fn with_store(initial_time:u128, spec: &GenesisSpec, fork_choice: ForkChoice, selfish: bool, mut chain: Box<dyn BlockStore>) -> Result<Self, String> {
+		let genesis = generate_genesis_block(initial_time, spec);
 		info!("Timestamp of the genesis block: {}", genesis.header.timestamp);
-		let blockinfo = Data{blk:genesis.clone(),height:0}; 
-		let hash: H256 = genesis.clone().hash();
-		let mut chain = HashMap::new();
-		chain.insert(hash,blockinfo);
-		let mut map = HashMap::new();
-		map.insert(hash, MerkleMountainRange::<Sha256, Vec<Hash>>::new(Vec::new()));
-		let tip:H256 = hash;
-		//info!("0:{}",tip);
-		Blockchain{chain, map, tip, depth:0, num_pos:0, num_pow:0, epoch_size:400, epoch_time: 120_000_000,genesis_time: initial_time,pub_len: 0, private_lead: 0}
-	
+		let hash: H256 = genesis.hash();
+		let stored = chain.hashes();
+		match stored.first() {
+			None => {
+				let blockinfo = Data{blk:genesis,height:0}; 
+				chain.insert(hash,&blockinfo).map_err(|e| format!("cannot store genesis block {}: {}", hash, e))?;
+			}
+			Some(stored_genesis) if *stored_genesis != hash => {
+				return Err(format!("stored genesis block {} does not match genesis block {}", stored_genesis, hash));
+			}
+			Some(_) => {}
+		}
+		let mut weights = HashMap::new();
+		weights.insert(hash, 0f64);
+		let mut blockchain = Blockchain{chain, fork_choice, resource_weights: spec.weights(), block_types: HashMap::new(), weights, boundaries: HashMap::new(), pow_referenced_by: HashMap::new(), txn_blocks: HashMap::new(), tip: hash, depth:0, num_pos:0, num_pow:0, num_rejected_pow:0, epoch_size: spec.epoch_size, epoch_time: spec.epoch_time, slot_time: spec.slot_time,genesis_time: initial_time,pub_len: 0, private_lead: 0, slot_producers: HashMap::new(), equivocations: Vec::new(), equivocators: HashSet::new()};
+		// replay the stored blocks to recover the counters and the tip
+		for h in stored.iter().skip(1) {
+			let data = blockchain.chain.get(h).ok_or(format!("cannot read stored block {}", h))?;
+			if data.blk.block_type == STAKE {
+				blockchain.record_slot(&data.blk);
+				blockchain.extend_pos(&data.blk, data.height, selfish);
+			} else {
+				blockchain.num_pow += 1;
+				blockchain.block_types.insert(*h, data.blk.block_type);
+				blockchain.index_txns(&data.blk);
+			}
+		}
+		if stored.len() > 1 {
+			info!("Recovered {} PoS and {} PoW blocks, tip {} at height {}", blockchain.num_pos, blockchain.num_pow, blockchain.tip, blockchain.depth);
+		}
+		Ok(blockchain)
     }
 
-    /// Insert a PoS block into blockchain
-    pub fn insert_pos(&mut self, block: &Block, selfish: bool) -> bool {
+    /// Insert a PoS block into blockchain, returns whether it became the tip or the error of the
+    /// block store
+    pub fn insert_pos(&mut self, block: &Block, selfish: bool) -> io::Result<bool> {
 		//unimplemented!()
+		if self.chain.contains(&block.hash()) {
+			return Ok(false);
+		}
+		let header:Header = block.header.clone();
+		let parenthash: H256 = header.parent;
+		let parentdata: Data;
+		match self.chain.get(&parenthash) {
+			Some(data) => parentdata = data,
+			None => return Ok(false),
+		}
+		let parentheight = parentdata.height;
+		let newheight = parentheight+1;
+		let newdata = Data{blk:block.clone(),height:newheight};
+		let newhash = block.hash();
+		self.chain.insert(newhash,&newdata)?;
+		Ok(self.extend_pos(block, newheight, selfish))
+    }
+
+    /// Account for a stored PoS block, returns whether it became the tip
+    fn extend_pos(&mut self, block: &Block, newheight: u128, selfish: bool) -> bool {
+		let newhash = block.hash();
+		self.num_pos = self.num_pos + 1;
+		let boundary = self.epoch_boundary(&block.header.parent, block.header.timestamp);
+		self.boundaries.insert(newhash, boundary);
 		if !selfish {
-			if self.chain.contains_key(&block.hash()) {
-			return false;
-			}
-			let header:Header = block.header.clone();
-			let parenthash: H256 = header.parent;
-			let parentdata: Data;
-			match self.chain.get(&parenthash) {
-				Some(data) => parentdata = data.clone(),
-				None => return false,
-			}
-			let parentheight = parentdata.height;
-			let newheight = parentheight+1;
-			let newdata = Data{blk:block.clone(),height:newheight};
-			let newhash = block.hash();
-			let mut new_mmr = self.get_mmr(&parenthash);
-			mmr_push_leaf(&mut new_mmr, newhash.as_ref().to_vec().clone());
-			self.chain.insert(newhash,newdata);
-			self.map.insert(newhash, new_mmr);
-			self.num_pos = self.num_pos + 1;
+			let heavier = self.add_weight(block);
 
 			let mut rng = rand::thread_rng();
 			let p: f64 = rng.gen::<f64>();  // toss a coin
 
-			if newheight > self.depth || (newheight == self.depth && block.selfish_block == true && p < 1.0){
+			if heavier || (self.fork_choice == ForkChoice::LongestChain && newheight == self.depth && block.selfish_block == true && p < 1.0){
 				self.depth = newheight;
 				self.tip = newhash;
 				return true;
 			} 
 			return false;
 		} else {       /// Insert a block into blockchain as a selfish miner
-			if self.chain.contains_key(&block.hash()) {
-				return false;
-			}
-			let header:Header = block.header.clone();
-			let parenthash: H256 = header.parent;
-			let parentdata: Data;
-			match self.chain.get(&parenthash) {
-				Some(data) => parentdata = data.clone(),
-				None => return false,
-			}
-			let parentheight = parentdata.height;
-			let newheight = parentheight+1;
-			let newdata = Data{blk:block.clone(),height:newheight};
-			let newhash = block.hash();
-			let mut new_mmr = self.get_mmr(&parenthash);
-			mmr_push_leaf(&mut new_mmr, newhash.as_ref().to_vec().clone());
-			self.chain.insert(newhash,newdata);
-			self.map.insert(newhash, new_mmr);
-			self.num_pos = self.num_pos + 1;
+			// the private attack is defined on heights, whatever the fork choice rule
+			self.add_weight(block);
 			if newheight > self.depth && block.selfish_block == true {
 				self.private_lead = self.private_lead + 1;
 				self.depth = newheight;
@@ -119,33 +181,114 @@ impl Blockchain {
 			}
 			return false;
 		}
-		
     }
 
-    /// Insert a PoW block into blockchain
-    pub fn insert_pow(&mut self, block: &Block) -> bool {
+    /// Record the weight of a newly stored PoS block, returns whether its fork beats the current tip
+    fn add_weight(&mut self, block: &Block) -> bool {
+		let hash = block.hash();
+		let parent = block.header.parent;
+		let mut new_refs: HashSet<H256> = HashSet::new();
+		for pow_hash in block.content.transaction_ref.iter() {
+			let counted = match self.pow_referenced_by.get(pow_hash) {
+				Some(referencing) => referencing.iter().any(|pos_hash| self.is_ancestor(pos_hash, &parent)),
+				None => false,
+			};
+			if !counted {
+				new_refs.insert(*pow_hash);
+			}
+		}
+		for pow_hash in block.content.transaction_ref.iter() {
+			self.pow_referenced_by.entry(*pow_hash).or_insert_with(Vec::new).push(hash);
+		}
+		let parent_weight = self.weights.get(&parent).cloned().unwrap_or(0f64);
+		let ref_weight: f64 = new_refs.iter()
+			.map(|h| self.block_types.get(h).and_then(|r| self.resource_weights.get(*r)).cloned().unwrap_or(0f64))
+			.sum();
+		let weight = parent_weight + 1f64 + ref_weight;
+		self.weights.insert(hash, weight);
+		match self.fork_choice {
+			ForkChoice::LongestChain => self.chain.get_height(&hash).unwrap() > self.depth,
+			ForkChoice::Weighted => weight > self.get_weight(&self.tip),
+		}
+    }
+
+    /// Whether `ancestor` is on the chain ending at `block` (included)
+    fn is_ancestor(&self, ancestor: &H256, block: &H256) -> bool {
+		let target = match self.chain.get_height(ancestor) {
+			Some(height) => height,
+			None => return false,
+		};
+		let mut hash = *block;
+		loop {
+			let height = match self.chain.get_height(&hash) {
+				Some(height) => height,
+				None => return false,
+			};
+			if height < target {
+				return false;
+			}
+			if height == target {
+				return hash == *ancestor;
+			}
+			hash = self.chain.get_header(&hash).unwrap().parent;
+		}
+    }
+
+    fn index_txns(&mut self, block: &Block) {
+		let hash = block.hash();
+		for txn in block.content.data.iter() {
+			self.txn_blocks.entry(txn.hash()).or_insert_with(Vec::new).push(hash);
+		}
+    }
+
+    /// Resource blocks carrying a transaction
+    pub fn find_txn_blocks(&self, txn: &H256) -> Vec<H256> {
+		self.txn_blocks.get(txn).cloned().unwrap_or_default()
+    }
+
+    /// PoS blocks referencing a resource block, on any fork
+    pub fn find_referencing_blocks(&self, hash: &H256) -> Vec<H256> {
+		self.pow_referenced_by.get(hash).cloned().unwrap_or_default()
+    }
+
+    /// Whether a PoS block is on the longest chain
+    pub fn on_longest_chain(&self, hash: &H256) -> bool {
+		self.is_ancestor(hash, &self.tip)
+    }
+
+    /// Cumulative weight of the chain ending at a PoS block
+    pub fn get_weight(&self, hash: &H256) -> f64 {
+		self.weights.get(hash).cloned().unwrap_or(0f64)
+    }
+
+    pub fn get_fork_choice(&self) -> ForkChoice {
+		self.fork_choice
+    }
+
+    /// Insert a PoW block, or a block of any other non stake resource, into blockchain, returns
+    /// whether it is new or the error of the block store
+    pub fn insert_pow(&mut self, block: &Block) -> io::Result<bool> {
 		//unimplemented!()
-		if self.chain.contains_key(&block.hash()) {
-			return false;
+		if self.chain.contains(&block.hash()) {
+			return Ok(false);
 		}
 		let header:Header = block.header.clone();
 		let parenthash: H256 = header.parent;
 		let parentdata: Data;
 		match self.chain.get(&parenthash) {
-			Some(data) => parentdata = data.clone(),
-			None => return false,
+			Some(data) => parentdata = data,
+			None => return Ok(false),
 		}
 		let parentheight = parentdata.height;
 		let newheight = parentheight+1;
 		let newdata = Data{blk:block.clone(),height:newheight};
 		let newhash = block.hash();
-		let mut new_mmr = self.get_mmr(&parenthash);
-		mmr_push_leaf(&mut new_mmr, newhash.as_ref().to_vec().clone());
-		self.chain.insert(newhash,newdata);
-		self.map.insert(newhash, new_mmr);
+		self.chain.insert(newhash,&newdata)?;
+		self.block_types.insert(newhash, block.block_type);
+		self.index_txns(block);
 		self.num_pow = self.num_pow + 1;
 
-		return true;
+		return Ok(true);
     }
 
     /// Get the last block's hash of the longest chain
@@ -156,14 +299,15 @@ impl Blockchain {
 	
 	pub fn get_pow_difficulty(&self, current_ts:u128, parent: H256) -> H256 {
 			let epoch_size = self.epoch_size;
-			let depth = self.depth;
+			// depends on the parent only, so that every node computes the same difficulty
+			let depth = self.chain.get_height(&parent).unwrap();
 			let epoch_time = self.epoch_time;
-			let parent_time = self.chain.get(&parent).unwrap().blk.header.timestamp;
+			let parent_time = self.chain.get_header(&parent).unwrap().timestamp;
 			let genesis_time = self.genesis_time;
-			let parent_epoch = (parent_time - genesis_time)/epoch_time;
-			let curent_epoch = (current_ts - genesis_time)/epoch_time;
+			let parent_epoch = self.epoch(parent_time);
+			let curent_epoch = self.epoch(current_ts);
 			if curent_epoch > parent_epoch && depth > 1 {
-				let old_diff: H256 = self.chain.get(&parent).unwrap().blk.header.pow_difficulty;
+				let old_diff: H256 = self.chain.get_header(&parent).unwrap().difficulty(WORK);
 				let mut hash = parent.clone();
 				let mut all_hashs = Vec::new(); 
 				while true {
@@ -174,8 +318,8 @@ impl Blockchain {
                             all_hashs.push(pow_blk);
                         }
 					}
-					hash = self.chain.get(&hash).unwrap().blk.header.parent;
-					let blk_time = self.chain.get(&hash).unwrap().blk.header.timestamp;
+					hash = self.chain.get_header(&hash).unwrap().parent;
+					let blk_time = self.chain.get_header(&hash).unwrap().timestamp;
 					let blk_epoch = (blk_time - genesis_time)/epoch_time;
 					if blk_epoch < parent_epoch || blk_time == self.genesis_time {
 						break;
@@ -194,7 +338,7 @@ impl Blockchain {
 				debug!("Mining difficulty changes from {} to {}",old_diff, new_diff);
 				new_diff
 			} else {
-				self.chain.get(&parent).unwrap().blk.header.pow_difficulty
+				self.chain.get_header(&parent).unwrap().difficulty(WORK)
 			}
 	}
 
@@ -206,54 +350,135 @@ impl Blockchain {
 			//let tip_time = self.chain.get(&tip).unwrap().blk.header.timestamp;
 			let genesis_time = self.genesis_time;
 			//let tip_epoch = (tip_time - genesis_time)/epoch_time;
-			let current_epoch = (current_ts - genesis_time)/epoch_time;
+			let current_epoch = current_ts.saturating_sub(genesis_time)/epoch_time;
 			current_epoch
 		}
 
-	pub fn is_new_epoch_and_count_blocks(&self, current_ts:u128) -> Option<HashMap<Vec<u8>,HashSet<H256>>> {
-			let epoch_size = self.epoch_size;
-			let depth = self.depth;
-			let epoch_time = self.epoch_time;
-			let tip = self.tip;
-			let tip_time = self.chain.get(&tip).unwrap().blk.header.timestamp;
-			let genesis_time = self.genesis_time;
-			let tip_epoch = (tip_time - genesis_time)/epoch_time;
-			let curent_epoch = (current_ts - genesis_time)/epoch_time;
-			// if it is new epoch, count last epoch's blocks
-			let mut tip_iter = tip;
-			if curent_epoch > tip_epoch {
-				let mut cnt: HashMap<Vec<u8>,HashSet<H256>>= HashMap::new();
-				loop {
-					let b = &self.chain.get(&tip_iter).unwrap().blk;
-					if b.header.timestamp-genesis_time == 0 {
+	/// Slot of a timestamp, slots are counted from the genesis time
+	pub fn slot(&self, ts:u128) -> u128 {
+			ts.saturating_sub(self.genesis_time)/self.slot_time
+	}
+
+	/// Start time of a slot
+	pub fn slot_start(&self, slot:u128) -> u128 {
+			self.genesis_time + slot*self.slot_time
+	}
+
+	/// Remember the PoS block a producer signed in its slot. If the producer already signed a
+	/// different block in the same slot, the equivocation is recorded and the other block returned.
+	pub fn record_slot(&mut self, block: &Block) -> Option<H256> {
+			let key = (block.header.vrf_pub_key.clone(), self.slot(block.header.timestamp));
+			let hash = block.hash();
+			match self.slot_producers.get(&key) {
+				Some(other) if *other != hash => {
+					let other = *other;
+					if !self.equivocations.iter().any(|(_, _, a, b)| (*a == other && *b == hash) || (*a == hash && *b == other)) {
+						self.equivocators.insert(key.0.clone());
+						self.equivocations.push((key.0, key.1, other, hash));
+					}
+					Some(other)
+				}
+				Some(_) => None,
+				None => {
+					self.slot_producers.insert(key, hash);
+					None
+				}
+			}
+	}
+
+	/// Pairs of PoS blocks signed by the same producer in the same slot
+	pub fn get_equivocations(&self) -> Vec<(Vec<u8>,u128,H256,H256)> {
+			self.equivocations.clone()
+	}
+
+	/// Whether the producer with this VRF public key signed two blocks in one slot
+	pub fn is_equivocator(&self, vrf_pub_key: &[u8]) -> bool {
+			self.equivocators.contains(vrf_pub_key)
+	}
+
+	/// Last block on the chain ending at `parent` (included) that belongs to an epoch before the
+	/// epoch of `current_ts`. Stake and PoW block counts are taken from this block.
+	pub fn epoch_boundary(&self, parent: &H256, current_ts:u128) -> H256 {
+			let current_epoch = self.epoch(current_ts);
+			let mut hash = *parent;
+			loop {
+				let header = match self.chain.get_header(&hash) {
+					Some(header) => header,
+					None => return hash,
+				};
+				if self.chain.get_height(&hash).unwrap() == 0 || self.epoch(header.timestamp) < current_epoch {
+					return hash;
+				}
+				// skip the rest of the block's epoch
+				hash = self.boundaries.get(&hash).cloned().unwrap_or(header.parent);
+			}
+	}
+
+	/// Randomness beacon of the epoch of `current_ts` on the chain ending at `parent`: the first 16
+	/// bytes of the SHA256 of the epoch number and the VRF outputs of the PoS blocks of epoch e-2,
+	/// in chain order. Those blocks are buried by a whole epoch, so nobody can grind the beacon.
+	pub fn epoch_rand(&self, parent: &H256, current_ts:u128) -> u128 {
+			let current_epoch = self.epoch(current_ts);
+			let mut vrf_hashes: Vec<Vec<u8>> = Vec::new();
+			if current_epoch >= 2 {
+				let mut hash = *parent;
+				while let Some(header) = self.chain.get_header(&hash) {
+					if self.chain.get_height(&hash).unwrap() == 0 {
 						break;
 					}
-					let this_epoch = (b.header.timestamp-genesis_time)/epoch_time;
-					if this_epoch!=tip_epoch {
+					let blk_epoch = self.epoch(header.timestamp);
+					if blk_epoch < current_epoch - 2 {
 						break;
 					}
-					for h in  b.content.transaction_ref.iter() {
-						let ref_b = &self.chain.get(h).expect("error, transaction ref is not in blockchain!!!").blk;
-						let miner = ref_b.header.vrf_pub_key.clone();
-						if let Some(m) = cnt.get_mut(&miner) {
-							m.insert(h.clone());
-						} else {
-							let mut m = HashSet::new();
-							m.insert(h.clone());
-							cnt.insert(miner, m);
-						}
+					if blk_epoch == current_epoch - 2 {
+						vrf_hashes.push(header.vrf_hash);
+						hash = header.parent;
+					} else {
+						hash = self.boundaries.get(&hash).cloned().unwrap_or(header.parent);
 					}
-					tip_iter = b.header.parent;
 				}
-				return Some(cnt);
 			}
-			// is not new epoch, return none
-			None
+			vrf_hashes.reverse();
+			let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+			ctx.update(&current_epoch.to_be_bytes());
+			for vrf_hash in vrf_hashes.iter() {
+				ctx.update(vrf_hash);
+			}
+			let digest = ctx.finish();
+			let mut rand = [0u8; 16];
+			rand.copy_from_slice(&digest.as_ref()[0..16]);
+			u128::from_be_bytes(rand)
+	}
+
+	/// Number of blocks of a resource referenced in the epoch of `boundary`, on the chain ending at
+	/// `boundary`, per producer VRF public key
+	pub fn count_resource_blocks(&self, boundary: &H256, resource: ResourceId) -> HashMap<Vec<u8>,usize> {
+			let mut cnt: HashMap<Vec<u8>,HashSet<H256>>= HashMap::new();
+			let boundary_epoch = self.epoch(self.chain.get_header(boundary).unwrap().timestamp);
+			let mut tip_iter = *boundary;
+			loop {
+				if self.chain.get_height(&tip_iter).unwrap() == 0 {
+					break;
+				}
+				let b = &self.chain.get(&tip_iter).unwrap().blk;
+				if self.epoch(b.header.timestamp) != boundary_epoch {
+					break;
+				}
+				for h in b.content.transaction_ref.iter() {
+					if self.block_types.get(h) != Some(&resource) {
+						continue;
+					}
+					let miner = self.chain.get_header(h).expect("error, transaction ref is not in blockchain!!!").vrf_pub_key;
+					cnt.entry(miner).or_insert_with(HashSet::new).insert(*h);
+				}
+				tip_iter = b.header.parent;
+			}
+			cnt.into_iter().map(|(k,v)|(k,v.len())).collect()
 	}
 
 	pub fn get_pos_difficulty(&self) -> H256 {
 		// should be parent, but it's okay since all pos are the same
-		self.chain.get(&self.tip).unwrap().blk.header.pos_difficulty
+		self.chain.get_header(&self.tip).unwrap().difficulty(STAKE)
 	}
 	
 	pub fn get_depth(&self) -> u128 {
@@ -268,6 +493,15 @@ impl Blockchain {
 		self.num_pow
 	}
 
+	/// Count a PoW block rejected for claiming an easier difficulty than the epoch schedule
+	pub fn count_rejected_pow(&mut self) {
+		self.num_rejected_pow += 1;
+	}
+
+	pub fn get_num_rejected_pow(&self) -> u128 {
+		self.num_rejected_pow
+	}
+
 	pub fn get_size(&self) -> usize {
 		self.chain.len()
 	}
@@ -280,16 +514,44 @@ impl Blockchain {
 		self.private_lead
 	}
 
+	/// Targets of every resource in the last PoS block of each epoch of the longest chain, oldest
+	/// epoch first
+	pub fn epoch_difficulties(&self) -> Vec<(u128,Vec<H256>)> {
+		let mut epochs: Vec<(u128,Vec<H256>)> = Vec::new();
+		let mut hash = self.tip;
+		while let Some(header) = self.chain.get_header(&hash) {
+			let epoch = self.epoch(header.timestamp);
+			if epochs.last().map(|(e, _)| *e) != Some(epoch) {
+				epochs.push((epoch, header.difficulties.clone()));
+			}
+			if self.chain.get_height(&hash).unwrap() == 0 {
+				break;
+			}
+			hash = header.parent;
+		}
+		epochs.reverse();
+		epochs
+	}
+
 	pub fn get_mmr(&self, hash: &H256) -> MerkleMountainRange<Sha256, Vec<Hash>> {
-		let mmr_ref = self.map.get(hash).unwrap();
-		let leaf_hashes = mmr_ref.get_leaf_hashes(0, mmr_ref.get_leaf_count().unwrap()+1).unwrap().clone();
+		// the leaves are the hashes of all blocks from the genesis (excluded) to this block
+		let mut leaf_hashes: Vec<Hash> = Vec::new();
+		let mut current_hash = *hash;
+		while let Some(header) = self.chain.get_header(&current_hash) {
+			if self.chain.get_height(&current_hash).unwrap() == 0 {
+				break;
+			}
+			leaf_hashes.push(current_hash.as_ref().to_vec());
+			current_hash = header.parent;
+		}
+		leaf_hashes.reverse();
 		let mut mmr_ret = MerkleMountainRange::<Sha256, Vec<Hash>>::new(Vec::new());
 		mmr_ret.assign(leaf_hashes).unwrap();
 		mmr_ret
 	}
 	
 	pub fn contains_hash(&self, hash: &H256) -> bool {
-		self.chain.contains_key(hash)
+		self.chain.contains(hash)
 	}
 	
 	pub fn print_longest_chain(&self) {
@@ -306,15 +568,15 @@ impl Blockchain {
 		let mut all_block : Vec<H256> = vec![];
 		let mut current_hash = self.tip;
 		//let mut parent_hash;
-		let mut parentdata: Data;
+		let mut parentheader: Header;
 
 		loop {
-			match self.chain.get(&current_hash) {
+			match self.chain.get_header(&current_hash) {
 				None => break,
-				Some(data) => parentdata = data.clone(),
+				Some(header) => parentheader = header,
 			}
 			all_block.push(current_hash);
-			current_hash = parentdata.blk.header.parent;
+			current_hash = parentheader.parent;
 			// debug!("current_hash {:?}!", current_hash);
 			// debug!("contains {:?}!", self.chain.get(&current_hash));
 			
@@ -325,17 +587,38 @@ impl Blockchain {
 		all_block
 	}
 
+	/// Hashes of the longest chain blocks at heights `start` to `end` (excluded), lowest first
+	pub fn longest_chain_range(&self, start: u128, end: u128) -> Vec<H256> {
+		let end = end.min(self.depth + 1);
+		if start >= end {
+			return vec![];
+		}
+		let mut hashes = Vec::new();
+		let mut hash = self.tip;
+		let mut height = self.depth;
+		loop {
+			if height < end {
+				hashes.push(hash);
+			}
+			if height == start {
+				break;
+			}
+			hash = self.chain.get_header(&hash).unwrap().parent;
+			height -= 1;
+		}
+		hashes.reverse();
+		hashes
+	}
+
 	pub fn find_one_height(&self,height:u128) -> H256 {
 		let mut current_hash = self.tip;
 		//let parent_hash: H256 = hash.clone();
-		let mut childdata: Data;
 
 		loop {
-			childdata = self.chain.get(&current_hash).unwrap().clone();
-			if childdata.height == height {
-				return childdata.blk.hash().clone();
+			if self.chain.get_height(&current_hash).unwrap() == height {
+				return current_hash;
 			}
-			current_hash = childdata.blk.header.parent.clone();
+			current_hash = self.chain.get_header(&current_hash).unwrap().parent;
 			
 		}
 	}
@@ -345,15 +628,15 @@ impl Blockchain {
 		let mut all_block : Vec<H256> = vec![];
 		let mut current_hash = self.tip;
 		//let mut parent_hash;
-		let mut parentdata: Data;
+		let mut parentheader: Header;
 
 		loop {
-			match self.chain.get(&current_hash) {
+			match self.chain.get_header(&current_hash) {
 				None => break,
-				Some(data) => parentdata = data.clone(),
+				Some(header) => parentheader = header,
 			}
 			all_block.push(current_hash);
-			current_hash = parentdata.blk.header.parent;
+			current_hash = parentheader.parent;
 			// debug!("current_hash {:?}!", current_hash);
 			// debug!("contains {:?}!", self.chain.get(&current_hash));
 			
@@ -375,18 +658,17 @@ impl Blockchain {
 		let mut parentdata: Data;
 		let mut count = 0;
 		let mut count_selfish = 0;
-		let mut all_pow_hash: Vec<H256> = Vec::new();
+		let mut all_pow_hash: HashSet<H256> = HashSet::new();
 
 		loop {
 			match self.chain.get(&current_hash) {
 				None => break,
-				Some(data) => parentdata = data.clone(),
+				Some(data) => parentdata = data,
 			}
 			//all_block.push(current_hash);
 			let pow_hashes = parentdata.blk.content.transaction_ref.clone();
 			for pow_hash in pow_hashes{
-                if !all_pow_hash.contains(&pow_hash) {
-                    all_pow_hash.push(pow_hash);
+                if all_pow_hash.insert(pow_hash) {
                     count = count +1;
                     let pow_block = self.find_one_block(&pow_hash).unwrap().clone();
                     if pow_block.selfish_block == true {
@@ -401,6 +683,60 @@ impl Blockchain {
 		chain_quality
     }
 
+    /// PoW blocks a PoS block references for the first time on its chain, in reference order
+    pub fn get_referenced_blocks(&self, block: &Block) -> Vec<Block> {
+		let parent = block.header.parent;
+		let mut seen: HashSet<H256> = HashSet::new();
+		let mut blocks = Vec::new();
+		for h in block.content.transaction_ref.iter() {
+			let referenced = match self.pow_referenced_by.get(h) {
+				Some(referencing) => referencing.iter().any(|pos_hash| self.is_ancestor(pos_hash, &parent)),
+				None => false,
+			};
+			if !seen.insert(*h) || referenced {
+				continue;
+			}
+			match self.chain.get(h) {
+				Some(data) => blocks.push(data.blk),
+				None => warn!("Referenced PoW block {} is not in blockchain", h),
+			}
+		}
+		blocks
+    }
+
+    /// Transactions of the PoW blocks referenced by `old_branch`, PoS blocks that left the
+    /// longest chain, and by no PoS block of the longest chain
+    pub fn orphaned_txns(&self, old_branch: &[H256]) -> Vec<SignedTransaction> {
+		let mut seen: HashSet<H256> = HashSet::new();
+		let mut txns = Vec::new();
+		for pos in old_branch {
+			let block = match self.find_one_block(pos) {
+				Some(block) => block,
+				None => continue,
+			};
+			for h in block.content.transaction_ref.iter() {
+				if !seen.insert(*h) || self.find_referencing_blocks(h).iter().any(|r| self.on_longest_chain(r)) {
+					continue;
+				}
+				if let Some(data) = self.chain.get(h) {
+					txns.extend(data.blk.content.data);
+				}
+			}
+		}
+		txns
+    }
+
+    /// PoW blocks that are not referenced by any PoS block of the longest chain
+    pub fn get_unreferenced_pow_blocks(&self) -> Vec<H256> {
+		let mut referenced: HashSet<H256> = HashSet::new();
+		for blk in self.get_longest_chain() {
+			referenced.extend(blk.content.transaction_ref);
+		}
+		self.chain.hashes().into_iter().filter(|h| {
+			!referenced.contains(h) && self.block_types.contains_key(h)
+		}).collect()
+    }
+
     pub fn find_one_block(&self,hash: &H256) -> Option<Block> {
     	match self.chain.get(&hash) {
 			None => return None,
@@ -408,17 +744,43 @@ impl Blockchain {
 		}
     }
 
-    pub fn find_one_header(&self,hash: &H256) -> Option<Header> {
-    	match self.chain.get(&hash) {
-			None => return None,
-			Some(data) => return Some(data.blk.header.clone()),
+	/// Hashes of the longest chain a peer can find the fork point from, the tip and the ten
+	/// blocks below it first, then exponentially further apart down to the genesis block
+	pub fn locator(&self) -> Vec<H256> {
+		let chain = self.all_blocks_in_longest_chain();
+		let mut locator = vec![];
+		let mut step = 1;
+		let mut index = chain.len() - 1;
+		loop {
+			locator.push(chain[index]);
+			if index == 0 {
+				break;
+			}
+			if locator.len() >= 10 {
+				step *= 2;
+			}
+			index = index.saturating_sub(step);
 		}
+		locator
+	}
+
+	/// Headers of the longest chain after the first locator hash on it, at most `max` of them
+	pub fn headers_after(&self, locator: &[H256], max: usize) -> Vec<Header> {
+		let chain = self.all_blocks_in_longest_chain();
+		let start = locator.iter()
+			.filter_map(|hash| chain.iter().position(|h| h == hash))
+			.next()
+			.unwrap_or(0);
+		chain[start + 1..].iter().take(max)
+			.map(|hash| self.chain.get_header(hash).unwrap())
+			.collect()
+	}
+
+    pub fn find_one_header(&self,hash: &H256) -> Option<Header> {
+    	self.chain.get_header(hash)
     }
     pub fn find_one_depth(&self,hash: &H256) -> Option<u128> {
-    	match self.chain.get(&hash) {
-			None => return None,
-			Some(data) => return Some(data.height),
-		}
+    	self.chain.get_height(hash)
     }
 }
 
@@ -495,6 +857,164 @@ impl FlyClientProof {
 	}
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{generate_pos_block, generate_pow_block};
+    use crate::transaction::generate_random_signed_transaction;
+    use crate::crypto::hash::generate_random_hash;
+
+    const EPOCH: u128 = 120_000_000;
+
+    fn pos_child(parent: &H256, ts: u128, vrf_hash: u8) -> Block {
+        generate_pos_block(&vec![], &vec![], parent, 0, &[],
+            ts, &vec![], &vec![vrf_hash], &[], 0, false)
+    }
+
+    #[test]
+    fn epoch_boundary() {
+        let spec = GenesisSpec::default();
+        let mut blockchain = Blockchain::new(0, &spec, ForkChoice::LongestChain);
+        let genesis = blockchain.tip();
+        let a1 = pos_child(&genesis, 1, 1);
+        let a2 = pos_child(&a1.hash(), 2, 2);
+        let a3 = pos_child(&a2.hash(), EPOCH + 1, 3);
+        let a4 = pos_child(&a3.hash(), EPOCH + 2, 4);
+        let f3 = pos_child(&a2.hash(), EPOCH + 3, 5);
+        for b in [&a1, &a2, &a3, &a4, &f3].iter() {
+            blockchain.insert_pos(b, false).unwrap();
+        }
+
+        assert_eq!(blockchain.epoch_boundary(&a2.hash(), 3), genesis);
+        assert_eq!(blockchain.epoch_boundary(&a4.hash(), EPOCH + 5), a2.hash());
+        assert_eq!(blockchain.epoch_boundary(&f3.hash(), EPOCH + 5), a2.hash());
+        assert_eq!(blockchain.epoch_boundary(&a4.hash(), 2 * EPOCH + 1), a4.hash());
+    }
+
+    #[test]
+    fn epoch_rand() {
+        let spec = GenesisSpec::default();
+        let mut blockchain = Blockchain::new(0, &spec, ForkChoice::LongestChain);
+        let mut other = Blockchain::new(0, &spec, ForkChoice::LongestChain);
+        let genesis = blockchain.tip();
+        let b1 = pos_child(&genesis, 1, 1);
+        let b2 = pos_child(&b1.hash(), EPOCH + 1, 2);
+        let b3 = pos_child(&b2.hash(), 2 * EPOCH + 1, 3);
+        for b in [&b1, &b2, &b3].iter() {
+            blockchain.insert_pos(b, false).unwrap();
+        }
+        let c1 = pos_child(&genesis, 1, 9);
+        other.insert_pos(&c1, false).unwrap();
+
+        // epochs 0 and 1 have no beacon blocks
+        assert_eq!(blockchain.epoch_rand(&b1.hash(), EPOCH + 2), other.epoch_rand(&c1.hash(), EPOCH + 2));
+        // epoch 2 depends on the VRF outputs of epoch 0 only
+        let rand = blockchain.epoch_rand(&b3.hash(), 2 * EPOCH + 2);
+        assert_eq!(rand, blockchain.epoch_rand(&b1.hash(), 2 * EPOCH + 2));
+        assert_ne!(rand, other.epoch_rand(&c1.hash(), 2 * EPOCH + 2));
+        assert_ne!(rand, blockchain.epoch_rand(&b3.hash(), 3 * EPOCH + 2));
+    }
+
+    #[test]
+    fn equivocation() {
+        let spec = GenesisSpec::default();
+        let mut blockchain = Blockchain::new(0, &spec, ForkChoice::LongestChain);
+        let genesis = blockchain.tip();
+        let b1 = pos_child(&genesis, spec.slot_time + 1, 1);
+        let b2 = pos_child(&genesis, spec.slot_time + 2, 2);
+        let b3 = pos_child(&genesis, 2 * spec.slot_time, 3);
+        assert_eq!(blockchain.record_slot(&b1), None);
+        assert_eq!(blockchain.record_slot(&b1), None);
+        assert_eq!(blockchain.record_slot(&b3), None);
+        assert!(!blockchain.is_equivocator(&[]));
+        assert_eq!(blockchain.record_slot(&b2), Some(b1.hash()));
+        assert_eq!(blockchain.get_equivocations(), vec![(vec![], 1, b1.hash(), b2.hash())]);
+        assert!(blockchain.is_equivocator(&[]));
+    }
+
+    #[test]
+    fn weighted_fork_choice() {
+        let mut spec = GenesisSpec::default();
+        spec.set_omega(0.5);
+        let mut blockchain = Blockchain::new(0, &spec, ForkChoice::Weighted);
+        let genesis = blockchain.tip();
+        let pow: Vec<H256> = (0..3).map(|i| {
+            let blk = generate_pow_block(&vec![generate_random_signed_transaction()], &vec![], &genesis, i, &[],
+                i as u128, &vec![], &vec![], &[], 0, false);
+            blockchain.insert_pow(&blk).unwrap();
+            blk.hash()
+        }).collect();
+        // a short fork referencing three PoW blocks beats a longer one referencing none
+        let a1 = generate_pos_block(&vec![], &pow, &genesis, 1, &[],
+            1, &vec![], &vec![], &[], 0, false);
+        let b1 = pos_child(&genesis, 2, 2);
+        let b2 = pos_child(&b1.hash(), 3, 3);
+        assert!(blockchain.insert_pos(&b1, false).unwrap());
+        assert!(blockchain.insert_pos(&b2, false).unwrap());
+        assert!(blockchain.insert_pos(&a1, false).unwrap());
+        assert_eq!(blockchain.tip(), a1.hash());
+        assert_eq!(blockchain.get_weight(&a1.hash()), 2.5);
+        // PoW blocks already referenced on the fork are not counted twice
+        let a2 = generate_pos_block(&vec![], &pow, &a1.hash(), 2, &[],
+            4, &vec![], &vec![], &[], 0, false);
+        assert!(blockchain.insert_pos(&a2, false).unwrap());
+        assert_eq!(blockchain.get_weight(&a2.hash()), 3.5);
+        assert!(blockchain.get_referenced_blocks(&a2).is_empty());
+        // the same PoW blocks count on a competing fork
+        let b3 = generate_pos_block(&vec![], &pow, &b2.hash(), 3, &[],
+            5, &vec![], &vec![], &[], 0, false);
+        assert!(blockchain.insert_pos(&b3, false).unwrap());
+        assert_eq!(blockchain.get_weight(&b3.hash()), 4.5);
+        assert_eq!(blockchain.get_referenced_blocks(&b3).len(), 3);
+    }
+
+    #[test]
+    fn headers_after_locator() {
+        let spec = GenesisSpec::default();
+        let mut blockchain = Blockchain::new(0, &spec, ForkChoice::LongestChain);
+        let mut chain = vec![blockchain.tip()];
+        for i in 1..30 {
+            let blk = pos_child(chain.last().unwrap(), i, 0);
+            blockchain.insert_pos(&blk, false).unwrap();
+            chain.push(blk.hash());
+        }
+        let locator = blockchain.locator();
+        assert_eq!(locator[0], chain[29]);
+        assert_eq!(locator[9], chain[20]);
+        assert_eq!(*locator.last().unwrap(), chain[0]);
+
+        // a peer that only has the first 5 blocks gets the rest
+        let headers = blockchain.headers_after(&[generate_random_hash(), chain[4], chain[0]], 10);
+        let hashes: Vec<H256> = headers.iter().map(|h| h.hash()).collect();
+        assert_eq!(hashes, chain[5..15].to_vec());
+        // an unknown locator starts from the genesis block
+        assert_eq!(blockchain.headers_after(&[generate_random_hash()], 100).len(), 29);
+    }
+
+    #[test]
+    fn selfish_replay() {
+        let spec = GenesisSpec::default();
+        let mut blockchain = Blockchain::new(0, &spec, ForkChoice::LongestChain);
+        let genesis = blockchain.tip();
+        let private1 = generate_pos_block(&vec![], &vec![], &genesis, 1, &[], 1, &vec![], &vec![], &[], 0, true);
+        let private2 = generate_pos_block(&vec![], &vec![], &private1.hash(), 2, &[], 2, &vec![], &vec![], &[], 0, true);
+        let public1 = pos_child(&genesis, 3, 0);
+        for b in [&private1, &private2, &public1].iter() {
+            blockchain.insert_pos(b, true).unwrap();
+        }
+        assert_eq!((blockchain.private_lead, blockchain.pub_len), (1, 1));
+
+        // a restart replays the stored blocks into the same selfish mining state
+        let mut store = MemoryStore::new();
+        for h in blockchain.chain.hashes() {
+            store.insert(h, &blockchain.chain.get(&h).unwrap());
+        }
+        let replayed = Blockchain::with_store(0, &spec, ForkChoice::LongestChain, true, Box::new(store)).unwrap();
+        assert_eq!((replayed.private_lead, replayed.pub_len), (1, 1));
+        assert_eq!((replayed.tip(), replayed.depth), (private2.hash(), 2));
+    }
+}
+
 
 
 // #[cfg(any(test, test_utilities))]
@@ -45,6 +45,15 @@ impl std::convert::From<H256> for H160 {
     }
 }
 
+impl std::fmt::Display for H160 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for byte_idx in 0..20 {
+            write!(f, "{:>02x}", &self.0[byte_idx])?;
+        }
+        Ok(())
+    }
+}
+
 impl std::fmt::Debug for H160 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -208,6 +217,12 @@ pub fn hash_multiply_by(input: &H256, multiply: f64) -> H256 {
         (&result_bytes).into()
     }
 
+/// Probability that a uniform 256 bit hash is at most `target`
+pub fn hash_probability(target: &H256) -> f64 {
+    let bytes: [u8; 32] = target.into();
+    bytes.iter().rev().fold(0f64, |p, byte| (p + *byte as f64) / 256f64)
+}
+
 
 #[cfg(any(test, test_utilities))]
 pub mod tests {
@@ -0,0 +1,193 @@
+use crate::crypto::hash::{H160, H256, Hashable};
+use crate::resource::{self, STAKE, WORK};
+use serde::{Serialize, Deserialize};
+use std::fs;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GenesisAccount {
+    /// hex encoded account address
+    pub address: String,
+    pub balance: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GenesisStake {
+    /// hex encoded VRF public key of the staker
+    pub vrf_pub_key: String,
+    pub stake: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GenesisResource {
+    /// must match the name of the resource with the same index in `resource::builtin`
+    pub name: String,
+    /// hex encoded 32 byte target of the first epoch
+    pub difficulty: String,
+    /// weight of the resource in the PoS leader election and in the weighted fork choice
+    pub weight: f64,
+}
+
+/// Block rewards, credited to the coinbase in the header of the rewarded block
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct RewardSchedule {
+    /// reward of a PoS block
+    pub pos: usize,
+    /// reward of a PoW block, paid by the first PoS block of a chain that references it
+    pub pow: usize,
+    /// both rewards halve every `halving_interval` PoS blocks, 0 never halves
+    pub halving_interval: u128,
+}
+
+impl RewardSchedule {
+    // `base` halved once per interval completed before PoS height `height`
+    fn at(&self, base: usize, height: u128) -> usize {
+        if self.halving_interval == 0 {
+            return base;
+        }
+        let halvings = height / self.halving_interval;
+        if halvings >= usize::BITS as u128 { 0 } else { base >> halvings }
+    }
+
+    pub fn pos_reward(&self, height: u128) -> usize {
+        self.at(self.pos, height)
+    }
+
+    pub fn pow_reward(&self, height: u128) -> usize {
+        self.at(self.pow, height)
+    }
+}
+
+/// Genesis configuration shared by all nodes of a network. Its hash is committed to by the
+/// genesis block, so two nodes only agree on the genesis block if they use the same spec.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GenesisSpec {
+    pub accounts: Vec<GenesisAccount>,
+    pub stakes: Vec<GenesisStake>,
+    /// one entry per resource, in `ResourceId` order
+    pub resources: Vec<GenesisResource>,
+    pub epoch_size: u128,
+    /// epoch length in micro seconds
+    pub epoch_time: u128,
+    /// slot length in micro seconds, stakers evaluate one VRF per slot
+    pub slot_time: u128,
+    #[serde(default)]
+    pub rewards: RewardSchedule,
+}
+
+impl Default for GenesisSpec {
+    fn default() -> Self {
+        let mut pow_difficulty = [0u8; 32];
+        pow_difficulty[1] = 40;
+        GenesisSpec {
+            accounts: vec![],
+            stakes: vec![],
+            resources: vec![
+                GenesisResource { name: "stake".to_string(), difficulty: hex::encode([1u8; 32]), weight: 1.0 },
+                GenesisResource { name: "work".to_string(), difficulty: hex::encode(pow_difficulty), weight: 0.0 },
+            ],
+            epoch_size: 400,
+            epoch_time: 120_000_000,
+            slot_time: 1_000,
+            rewards: RewardSchedule::default(),
+        }
+    }
+}
+
+/// Parse a 32 byte hex string
+pub fn parse_h256(input: &str) -> Result<H256, String> {
+    let bytes = hex::decode(input).map_err(|e| format!("invalid hex {}: {}", input, e))?;
+    if bytes.len() != 32 {
+        return Err(format!("{} is not 32 bytes long", input));
+    }
+    Ok(bytes.into())
+}
+
+/// Parse a 20 byte hex string
+pub fn parse_h160(input: &str) -> Result<H160, String> {
+    let bytes = hex::decode(input).map_err(|e| format!("invalid hex {}: {}", input, e))?;
+    if bytes.len() != 20 {
+        return Err(format!("{} is not 20 bytes long", input));
+    }
+    let mut raw = [0u8; 20];
+    raw.copy_from_slice(&bytes);
+    Ok(raw.into())
+}
+
+impl GenesisSpec {
+    /// Read a genesis spec from a JSON file
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(filename).map_err(|e| format!("cannot read {}: {}", filename, e))?;
+        let spec: GenesisSpec = serde_json::from_str(&content).map_err(|e| format!("cannot parse {}: {}", filename, e))?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let builtin = resource::builtin();
+        if self.resources.len() != builtin.len() {
+            return Err(format!("expected {} resources, got {}", builtin.len(), self.resources.len()));
+        }
+        for (spec, resource) in self.resources.iter().zip(builtin.iter()) {
+            if spec.name != resource.name() {
+                return Err(format!("resource {} should be {}", spec.name, resource.name()));
+            }
+            parse_h256(&spec.difficulty)?;
+            if !(0.0..=1.0).contains(&spec.weight) {
+                return Err(format!("weight {} of {} is not in [0, 1]", spec.weight, spec.name));
+            }
+        }
+        let total: f64 = self.weights().iter().sum();
+        if (total - 1.0).abs() > 1e-9 {
+            return Err(format!("resource weights sum to {} instead of 1", total));
+        }
+        for account in &self.accounts {
+            parse_h160(&account.address)?;
+        }
+        for stake in &self.stakes {
+            hex::decode(&stake.vrf_pub_key).map_err(|e| format!("invalid hex {}: {}", stake.vrf_pub_key, e))?;
+        }
+        if self.epoch_size == 0 || self.epoch_time == 0 || self.slot_time == 0 {
+            return Err("epoch_size, epoch_time and slot_time must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// Initial target of every resource
+    pub fn difficulties(&self) -> Vec<H256> {
+        self.resources.iter().map(|r| parse_h256(&r.difficulty).unwrap()).collect()
+    }
+
+    /// Weight of every resource
+    pub fn weights(&self) -> Vec<f64> {
+        self.resources.iter().map(|r| r.weight).collect()
+    }
+
+    /// Weight work with omega and stake with 1 - omega, like the two resource Minotaur
+    pub fn set_omega(&mut self, omega: f64) {
+        for (id, resource) in self.resources.iter_mut().enumerate() {
+            resource.weight = match id {
+                STAKE => 1f64 - omega,
+                WORK => omega,
+                _ => 0f64,
+            };
+        }
+    }
+
+    /// Initial balance of every ICO account
+    pub fn accounts(&self) -> Vec<(H160, usize)> {
+        self.accounts.iter().map(|a| (parse_h160(&a.address).unwrap(), a.balance)).collect()
+    }
+
+    /// Initial stake of every VRF public key
+    pub fn stakes(&self) -> Vec<(Vec<u8>, usize)> {
+        self.stakes.iter().map(|s| (hex::decode(&s.vrf_pub_key).unwrap(), s.stake)).collect()
+    }
+}
+
+impl Hashable for GenesisSpec {
+    fn hash(&self) -> H256 {
+        let serialized: Vec<u8> = bincode::serialize(&self).unwrap();
+        let bytes: &[u8] = &serialized;
+        ring::digest::digest(&ring::digest::SHA256, bytes).into()
+    }
+}
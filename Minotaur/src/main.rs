@@ -4,6 +4,8 @@ extern crate hex_literal;
 
 pub mod api;
 pub mod block;
+pub mod genesis;
+pub mod resource;
 pub mod blockchain;
 pub mod crypto;
 pub mod miner;
@@ -15,14 +17,20 @@ pub mod transaction;
 pub mod txgenerator;
 pub mod state;
 pub mod spam_recorder;
+pub mod mempool;
+pub mod store;
+pub mod metrics;
+pub mod simulator;
+pub mod validation;
+pub mod wallet;
 
 use crate::crypto::hash::Hashable;
 use std::collections::{HashMap, HashSet};
-use crate::blockchain::Blockchain;
+use crate::blockchain::{Blockchain, ForkChoice};
 use std::sync::{Arc, Mutex};
 use clap::clap_app;
 use crossbeam::channel;
-use log::{error, info};
+use log::{error, info, warn};
 use api::Server as ApiServer;
 use network::{server, worker, spv_worker, selfish_worker};
 use std::net;
@@ -36,6 +44,18 @@ use vrf::openssl::{CipherSuite, ECVRF};
 use vrf::VRF;  
 
 use crate::spam_recorder::SpamRecorder;
+use crate::mempool::Mempool;
+use crate::metrics::Metrics;
+use crate::transaction::VerifyPool;
+use crate::store::DiskStore;
+use crate::genesis::GenesisSpec;
+use crate::resource::Resources;
+use crate::network::sync::HeaderSync;
+use crate::network::addrbook::AddrBook;
+use crate::network::scores::PeerScores;
+use crate::network::emulation::NetemConfig;
+use crate::simulator::{SimConfig, Simulation};
+use crate::network::message::{Version, Services, PROTOCOL, PROTOCOL_VERSION};
 
 fn main() {
     // parse command line arguments
@@ -46,21 +66,84 @@ fn main() {
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg outbound: --outbound [INT] default_value("8") "Sets how many outgoing connections to keep, peers are found through the address book")
+     (@arg netem: --netem [FILE] "Sets the network emulation config (per-link latency, bandwidth and message loss), off if not given")
+     (@arg ban_time: --("ban-time") [SECS] default_value("3600") "Sets how many seconds misbehaving peers are banned for")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg verify_threads: --("verify-threads") [INT] "Sets the number of threads checking transaction signatures, the number of cores if not given")
      (@arg spv_client: --spv [BOOL] default_value("false") "Whether spv client or full node") // false for full node, true for spv client
      //(@arg fly_client: --fly [BOOL] default_value("false") "Whether fly client or full node") // false for full node, true for fly client
      (@arg vrf_secret_key: --sk [String] "Secret key to be used to print or validate proof" )
      (@arg initial_time: --ts [u128] "Timestamp of the genesis block" )
      (@arg txn_numerator: --txnn [usize] default_value("1") "txn generator numerator, range: [0,denominator)" )
      (@arg txn_denominator: --txnd [usize] default_value("1") "txn generator denominator" )
+     (@arg txn_fee: --txfee [usize] default_value("0") "Sets the highest fee of generated transactions, fees are drawn uniformly up to it" )
+     (@arg coinbase: --coinbase [ADDR] "Sets the account credited with the fees and rewards of produced blocks, the first --txkeys account if not given" )
      (@arg selfish_node: --selfish [BOOL] default_value("false") "Whether selfish or honest node") // false for honest node, true for selfish node
-     (@arg omega: -w --weight [f64] default_value("0.0") "Omega, the weight of PoW and virtual stake, chosen by developers. ")
-     (@arg betas: --betas [f64] default_value("1.0") "beta_s, the stake fraction this node has. set to 1.0 if the experiment is not about attacks")
+     (@arg omega: -w --weight [f64] "Omega, the weight of PoW, stake gets 1 - omega. Overrides the resource weights of the genesis spec")
+     (@arg betas: --betas [f64] "Ignored, the stake fraction of a node now comes from the stake bonded on chain")
      (@arg betaw: --betaw [f64] default_value("1.0") "beta_w, the computational power fraction this node has. set to 1.0 if the experiment is not about attacks")
+     (@arg fork_choice: --("fork-choice") [RULE] default_value("longest") "Sets the fork choice rule, longest or weighted")
+     (@arg drift: --drift [INT] default_value("1000000") "Sets how many micro seconds a PoS block may be ahead of local time")
+     (@arg genesis: --genesis [FILE] default_value("genesis.json") "Sets the genesis spec (ICO balances, initial stakes, resource difficulties and weights, epochs)")
+     (@arg mempool_size: --("mempool-size") [INT] default_value("100000") "Sets how many transactions the mempool holds")
+     (@arg txkeys: --txkeys [FILE] default_value("pubkeys.txt") "Sets the file of pkcs8 keys the transaction generator signs with")
+     (@arg datadir: --datadir [DIR] "Sets the directory where blocks are persisted, the node resumes from it on restart")
+     (@arg simulate: --simulate [FILE] "Runs the deterministic multi-node simulation of a config file and prints its report instead of starting a node")
      (@arg atttime: --atttime [u128] default_value("0") "attack starts from this time (plus genesis time), micro sec, if 0, no attack")
+     (@subcommand wallet =>
+      (about: "Creates Ed25519 keys, queries balances and signs transfers")
+      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the API server of the node to query and submit to")
+      (@subcommand new =>
+       (about: "Creates a key and prints its address")
+       (@arg out: --out [FILE] "Writes the key to a new file in the --txkeys format instead of printing it, an existing file is refused"))
+      (@subcommand address =>
+       (about: "Prints the address of a key")
+       (@arg key: +required "File with the hex encoded pkcs8 key"))
+      (@subcommand balance =>
+       (about: "Prints the balance and nonce of an account at the node's tip")
+       (@arg address: +required "Account address"))
+      (@subcommand send =>
+       (about: "Signs a transfer and submits it to the node")
+       (@arg key: +required "File with the hex encoded pkcs8 key of the sender")
+       (@arg value: +required "Amount to send")
+       (@arg to: --to [ADDR] "Receiver address")
+       (@arg bond: --bond [VRFKEY] "Bonds the amount to the stake of a hex VRF public key instead")
+       (@arg fee: --fee [INT] default_value("0") "Fee paid to the miner of the PoW block carrying the transaction")
+       (@arg nonce: --nonce [INT] "Nonce of the transaction, the next one at the node's tip if not given")
+       (@arg chain: --chain [HASH] "Genesis hash of the network, the node's if not given")
+       (@arg expiry: --expiry [HEIGHT] "Last PoS height the transaction may be executed at")
+       (@arg memo: --memo [TEXT] "Memo attached to the transaction")
+       (@arg print: --print "Prints the signed transaction in hex instead of submitting it")))
     )
     .get_matches();
 
+    if let Some(wallet_matches) = matches.subcommand_matches("wallet") {
+        let verbosity = matches.occurrences_of("verbose") as usize;
+        stderrlog::new().verbosity(verbosity).init().unwrap();
+        if let Err(e) = wallet::run(wallet_matches) {
+            error!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(sim_file) = matches.value_of("simulate") {
+        let verbosity = matches.occurrences_of("verbose") as usize;
+        stderrlog::new().verbosity(verbosity).init().unwrap();
+        let config = SimConfig::load(sim_file).unwrap_or_else(|e| {
+            error!("Error loading simulation config: {}", e);
+            process::exit(1);
+        });
+        let mut simulation = Simulation::new(config).unwrap_or_else(|e| {
+            error!("Error setting up the simulation: {}", e);
+            process::exit(1);
+        });
+        let report = simulation.run();
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return;
+    }
+
     let mut vrf = ECVRF::from_suite(CipherSuite::SECP256K1_SHA256_TAI).unwrap();
     // Inputs: Secret Key, Public Key (derived) & Message
     let vrf_secret_key = hex::decode(&matches.value_of("vrf_secret_key").unwrap()).unwrap();
@@ -146,20 +229,52 @@ fn main() {
             error!("Error parsing txn_denominator: {}", e);
             process::exit(1);
         });
-    let omega = matches
-        .value_of("omega")
+    let txn_fee = matches
+        .value_of("txn_fee")
         .unwrap()
-        .parse::<f64>()
+        .parse::<usize>()
         .unwrap_or_else(|e| {
+            error!("Error parsing txn_fee: {}", e);
+            process::exit(1);
+        });
+    let genesis_file = matches.value_of("genesis").unwrap();
+    let mut genesis_spec = GenesisSpec::load(genesis_file).unwrap_or_else(|e| {
+        error!("Error loading genesis spec: {}", e);
+        process::exit(1);
+    });
+    if let Some(omega) = matches.value_of("omega") {
+        let omega = omega
+            .parse::<f64>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing omega: {}", e);
+                process::exit(1);
+            });
+        genesis_spec.set_omega(omega);
+        genesis_spec.validate().unwrap_or_else(|e| {
             error!("Error parsing omega: {}", e);
             process::exit(1);
         });
-    let beta_s = matches
-        .value_of("betas")
+    }
+    let resources = Arc::new(Resources::new(&genesis_spec));
+    let genesis_hash = block::generate_genesis_block(initial_time, &genesis_spec).hash();
+    info!("Genesis block {} from spec {}", genesis_hash, genesis_file);
+    if matches.is_present("betas") {
+        warn!("--betas is ignored, set the stake of this node in the genesis spec or bond it on chain");
+    }
+    let fork_choice = matches
+        .value_of("fork_choice")
         .unwrap()
-        .parse::<f64>()
+        .parse::<ForkChoice>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing fork choice: {}", e);
+            process::exit(1);
+        });
+    let max_drift = matches
+        .value_of("drift")
+        .unwrap()
+        .parse::<u128>()
         .unwrap_or_else(|e| {
-            error!("Error parsing betas: {}", e);
+            error!("Error parsing drift: {}", e);
             process::exit(1);
         });
     let beta_w = matches
@@ -180,9 +295,9 @@ fn main() {
         });
     if atttime > 0 {
         atttime += initial_time;
-        info!("[PrivateAttack?] Attacker, attack time: {}, betas: {}, betaw: {}", atttime, beta_s, beta_w);
+        info!("[PrivateAttack?] Attacker, attack time: {}, betaw: {}", atttime, beta_w);
     } else {
-        info!("[PrivateAttack?] Honest, attack time: {}, betas: {}, betaw: {}", atttime, beta_s, beta_w);
+        info!("[PrivateAttack?] Honest, attack time: {}, betaw: {}", atttime, beta_w);
     }
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::unbounded();
@@ -191,9 +306,37 @@ fn main() {
     // create staker update channels
     let (context_update_send, context_update_recv) = channel::unbounded();
 
-    // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
+    let outbound = matches
+        .value_of("outbound")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing outbound peer count: {}", e);
+            process::exit(1);
+        });
+    let addr_book = match matches.value_of("datadir") {
+        Some(datadir) => AddrBook::open(datadir).unwrap_or_else(|e| {
+            error!("Error opening the address book in {}: {}", datadir, e);
+            process::exit(1);
+        }),
+        None => AddrBook::new(),
+    };
+    let addr_book = Arc::new(std::sync::Mutex::new(addr_book));
+    let ban_time = matches
+        .value_of("ban_time")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ban time: {}", e);
+            process::exit(1);
+        });
+    let scores = Arc::new(std::sync::Mutex::new(PeerScores::new(ban_time)));
+    let netem = matches.value_of("netem").map(|netem_file| {
+        NetemConfig::load(netem_file).unwrap_or_else(|e| {
+            error!("Error loading network emulation config: {}", e);
+            process::exit(1);
+        })
+    });
 
     // start the worker
     let p2p_workers = matches
@@ -204,38 +347,100 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
+    let verify_threads = match matches.value_of("verify_threads") {
+        Some(threads) => threads.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing verify threads: {}", e);
+            process::exit(1);
+        }),
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
 
     
-    let mut blockchain = Blockchain::new(initial_time);
+    let mut blockchain = match matches.value_of("datadir") {
+        Some(datadir) => {
+            let store = DiskStore::open(datadir).unwrap_or_else(|e| {
+                error!("Error opening datadir {}: {}", datadir, e);
+                process::exit(1);
+            });
+            Blockchain::with_store(initial_time, &genesis_spec, fork_choice, selfish_node, Box::new(store)).unwrap_or_else(|e| {
+                error!("Error loading blockchain from datadir {}: {}", datadir, e);
+                process::exit(1);
+            })
+        }
+        None => Blockchain::new(initial_time, &genesis_spec, fork_choice),
+    };
     let mut buffer = HashMap::new();
-    let mut all_blocks = HashMap::new();
+    let all_blocks = HashMap::new();
     let mut delays = Vec::new();
-    let mut mempool = Vec::new();
+    let mempool_size = matches
+        .value_of("mempool_size")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool size: {}", e);
+            process::exit(1);
+        });
+    let mempool = Mempool::new(mempool_size);
     let mut tranpool = Vec::new();
+    // blocks recovered from the datadir, if any
+    if blockchain.get_size() > 1 {
+        tranpool.extend(blockchain.get_unreferenced_pow_blocks());
+    }
     let mut all_txns = HashMap::new();
     let spam_recorder = SpamRecorder::new();
     let mut state = state::State::new();
+    state.rewards = genesis_spec.rewards.clone();
     let blockchain = Arc::new(std::sync::Mutex::new(blockchain));
     let buffer = Arc::new(std::sync::Mutex::new(buffer));
     let all_blocks = Arc::new(std::sync::Mutex::new(all_blocks));
     let delays = Arc::new(std::sync::Mutex::new(delays));
+    let sync = Arc::new(std::sync::Mutex::new(HeaderSync::new()));
     let mempool = Arc::new(std::sync::Mutex::new(mempool));
     let tranpool = Arc::new(std::sync::Mutex::new(tranpool));
     let all_txns = Arc::new(std::sync::Mutex::new(all_txns));
     let spam_recorder= Arc::new(std::sync::Mutex::new(spam_recorder));
+    let metrics = Arc::new(std::sync::Mutex::new(Metrics::new()));
+    let verify_pool = Arc::new(VerifyPool::new(verify_threads));
+
+    // start the p2p server
+    let version = Version {
+        protocol: PROTOCOL.to_string(),
+        version: PROTOCOL_VERSION,
+        genesis_hash,
+        best_height: 0,
+        services: if spv_client { Services::SPV } else { Services::Full },
+        listen_port: p2p_addr.port(),
+    };
+    let (server_ctx, server) = server::new(p2p_addr, msg_tx, version, &blockchain, &addr_book, &scores, outbound, netem).unwrap();
+    server_ctx.start().unwrap();
 
     // ico 
-    let ico_account_number = 2;
-    let keypairs = state::create_ico_keys(ico_account_number);
-    // let accounts = state::create_ico_accounts(keypairs);
-    // let amount = 10000;
-    //let genesis_block = block::generate_genesis_block();
-    //let genesis_block_hash = blockchain.lock().unwrap().tip();
-    //state.ico(genesis_block_hash, &accounts, amount);
-    //info!("***** State After ICO *****");
-    ///state.print_last_block_state(&genesis_block_hash);
+    let ico_accounts = genesis_spec.accounts();
+    let accounts: Vec<H160> = ico_accounts.iter().map(|(account, _)| *account).collect();
+    let keypairs = state::create_ico_keys(matches.value_of("txkeys").unwrap(), &accounts);
+    if keypairs.len() < 2 {
+        warn!("Only {} ICO account keys found, the transaction generator needs two", keypairs.len());
+    }
+    let coinbase = match matches.value_of("coinbase") {
+        Some(coinbase) => genesis::parse_h160(coinbase).unwrap_or_else(|e| {
+            error!("Error parsing coinbase: {}", e);
+            process::exit(1);
+        }),
+        None => keypairs.first().map(wallet::address).unwrap_or_default(),
+    };
+    info!("Coinbase of produced blocks: {}", coinbase);
+    state.ico(genesis_hash, &ico_accounts, &genesis_spec.stakes());
+    info!("***** State After ICO *****");
+    state.print_last_block_state(&genesis_hash);
     info!("***************************");
-    
+    // re-execute the longest chain recovered from the datadir
+    {
+        let blockchain = blockchain.lock().unwrap();
+        for block in blockchain.get_longest_chain().iter().skip(1) {
+            let pow_blocks = blockchain.get_referenced_blocks(block);
+            state.update_block(block, &pow_blocks);
+        }
+    }
 
 
     let state = Arc::new(std::sync::Mutex::new(state));
@@ -289,6 +494,13 @@ fn main() {
             &tranpool,
             context_update_send.clone(),
             context_update_send_pow.clone(),
+            &resources,
+            max_drift,
+            &sync,
+            &addr_book,
+            &scores,
+            &metrics,
+            &verify_pool,
         );
         selfish_worker_ctx.start();
     } else {
@@ -307,6 +519,13 @@ fn main() {
             &tranpool,
             context_update_send.clone(),
             context_update_send_pow.clone(),
+            &resources,
+            max_drift,
+            &sync,
+            &addr_book,
+            &scores,
+            &metrics,
+            &verify_pool,
         );
         worker_ctx.start();
     }
@@ -322,6 +541,7 @@ fn main() {
         //&accounts,
         txnn,
         txnd,
+        txn_fee,
     );
     txgenerator_ctx.start();
 
@@ -341,6 +561,8 @@ fn main() {
         selfish_node,
         beta_w,
         atttime,
+        &metrics,
+        coinbase,
     );
     miner_ctx.start();
 
@@ -350,16 +572,18 @@ fn main() {
         context_update_recv,
         context_update_send,
         &server,
-        //&mempool,
+        &mempool,
+        &spam_recorder,
         &state,
         &all_blocks,
         &tranpool,
         &vrf_secret_key,
         &vrf_public_key,
         selfish_node,
-        omega,
-        beta_s,
+        &resources,
         atttime,
+        &metrics,
+        coinbase,
     );
     staker_ctx.start();
 
@@ -367,6 +591,7 @@ fn main() {
     if let Some(known_peers) = matches.values_of("known_peer") {
         let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
         let server = server.clone();
+        let addr_book = Arc::clone(&addr_book);
         thread::spawn(move || {
             for peer in known_peers {
                 loop {
@@ -377,6 +602,7 @@ fn main() {
                             break;
                         }
                     };
+                    addr_book.lock().unwrap().add(None, &[addr]);
                     match server.connect(addr) {
                         Ok(_) => {
                             info!("Connected to outgoing peer {}", &addr);
@@ -406,6 +632,16 @@ fn main() {
         &server,
         &spv,
         &blockchain,
+        &state,
+        &sync,
+        &addr_book,
+        &scores,
+        &metrics,
+        &buffer,
+        &mempool,
+        &tranpool,
+        &delays,
+        &all_txns,
         //&fly,
     );
 
@@ -0,0 +1,356 @@
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H160, H256, Hashable};
+use crate::spam_recorder::SpamRecorder;
+use crate::state::{pending_check, SkipReason, State};
+use crate::transaction::SignedTransaction;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::sync::Mutex;
+
+/// Why a transaction was not added to the mempool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    Known,
+    Invalid(SkipReason),
+    /// another transaction of the sender with the same nonce is queued, and the new fee is not at
+    /// least `REPLACE_BUMP` percent higher
+    NonceTaken,
+    Full,
+    /// the state of the tip is not computed yet
+    NoState,
+}
+
+/// Percentage a replacement must raise the fee of a queued transaction by
+pub const REPLACE_BUMP: usize = 10;
+
+struct Entry {
+    txn: SignedTransaction,
+    sender: H160,
+    // arrival order
+    seq: u64,
+    fee_rate: u128,
+}
+
+/// Pending transactions, queued per sender by nonce. Transactions are checked against the state
+/// of the tip when they arrive, and dropped once the tip state has executed their nonce.
+pub struct Mempool {
+    capacity: usize,
+    seq: u64,
+    txns: HashMap<H256, Entry>,
+    queues: HashMap<H160, BTreeMap<usize, H256>>,
+    // nonce and balance at the tip of every sender with queued transactions
+    accounts: HashMap<H160, (usize, usize)>,
+    // last nonce and total cost of each sender's run of transactions in PoW blocks that the tip
+    // has not executed yet
+    included: HashMap<H160, (usize, usize)>,
+}
+
+pub fn sender(txn: &SignedTransaction) -> H160 {
+    txn.transaction.sender
+}
+
+/// Fee per thousand bytes
+pub fn fee_rate(txn: &SignedTransaction) -> u128 {
+    txn.transaction.fee as u128 * 1000 / txn.size() as u128
+}
+
+/// What executing a transaction takes from its sender
+fn cost(txn: &SignedTransaction) -> usize {
+    txn.transaction.value.saturating_add(txn.transaction.fee)
+}
+
+impl Mempool {
+    pub fn new(capacity: usize) -> Self {
+        Mempool {
+            capacity,
+            seq: 0,
+            txns: HashMap::new(),
+            queues: HashMap::new(),
+            accounts: HashMap::new(),
+            included: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.txns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txns.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.txns.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&SignedTransaction> {
+        self.txns.get(hash).map(|entry| &entry.txn)
+    }
+
+    /// Add a transaction valid on top of `accounts`, the state of the tip, if the sender can pay
+    /// for it together with its other queued transactions. It replaces a queued transaction with
+    /// the same sender and nonce if it pays a high enough fee. A full mempool evicts the highest
+    /// nonce of the longest sender queue, unless the new transaction would make its own sender's
+    /// queue the longest.
+    pub fn insert(&mut self, txn: SignedTransaction, accounts: &HashMap<H160, (usize, usize)>) -> Result<(), MempoolError> {
+        let hash = txn.hash();
+        if self.txns.contains_key(&hash) {
+            return Err(MempoolError::Known);
+        }
+        pending_check(accounts, &txn).map_err(MempoolError::Invalid)?;
+        let sender = sender(&txn);
+        let nonce = txn.transaction.nonce;
+        let replaced = self.queues.get(&sender).and_then(|queue| queue.get(&nonce)).cloned();
+        if let Some(replaced) = replaced {
+            let fee = self.txns[&replaced].txn.transaction.fee;
+            let bumped = fee.saturating_add((fee.saturating_mul(REPLACE_BUMP) / 100).max(1));
+            if txn.transaction.fee < bumped {
+                return Err(MempoolError::NonceTaken);
+            }
+        }
+        let account = accounts[&sender];
+        let included_cost = self.included.get(&sender).map_or(0, |(_, cost)| *cost);
+        let queued_cost = self.queues.get(&sender).map_or(0, |queue| queue.iter()
+            .filter(|(n, _)| **n != nonce)
+            .fold(included_cost, |sum, (_, hash)| sum.saturating_add(cost(&self.txns[hash].txn))));
+        let total = queued_cost.saturating_add(cost(&txn));
+        if total > account.1 {
+            return Err(MempoolError::Invalid(SkipReason::InsufficientBalance { balance: account.1, value: total }));
+        }
+        if let Some(replaced) = replaced {
+            self.remove(&replaced);
+        }
+        let queued = self.queues.get(&sender).map_or(0, |queue| queue.len());
+        if self.txns.len() >= self.capacity {
+            let victim = self.queues.iter()
+                .map(|(_, queue)| (queue.len(), *queue.values().next_back().unwrap()))
+                .max_by_key(|(len, hash)| (*len, self.txns[hash].seq));
+            match victim {
+                Some((len, victim)) if len > queued + 1 => {
+                    self.remove(&victim);
+                }
+                _ => return Err(MempoolError::Full),
+            }
+        }
+        self.seq += 1;
+        self.queues.entry(sender).or_insert_with(BTreeMap::new).insert(nonce, hash);
+        self.accounts.insert(sender, account);
+        let fee_rate = fee_rate(&txn);
+        self.txns.insert(hash, Entry { txn, sender, seq: self.seq, fee_rate });
+        Ok(())
+    }
+
+    pub fn remove(&mut self, hash: &H256) -> Option<SignedTransaction> {
+        let entry = self.txns.remove(hash)?;
+        let queue = self.queues.get_mut(&entry.sender).unwrap();
+        queue.remove(&entry.txn.transaction.nonce);
+        if queue.is_empty() {
+            self.queues.remove(&entry.sender);
+            self.accounts.remove(&entry.sender);
+        }
+        Some(entry.txn)
+    }
+
+    /// Drop the transactions of a new block, and the queued ones using the same sender and nonce
+    pub fn remove_included(&mut self, txns: &[SignedTransaction]) {
+        for txn in txns {
+            let sender = sender(txn);
+            let last = self.included.get(&sender).map(|(nonce, _)| *nonce)
+                .or_else(|| self.accounts.get(&sender).map(|(nonce, _)| *nonce));
+            if last.map_or(false, |last| txn.transaction.nonce == last + 1) {
+                let spent = self.included.get(&sender).map_or(0, |(_, cost)| *cost);
+                self.included.insert(sender, (txn.transaction.nonce, spent.saturating_add(cost(txn))));
+            }
+            let queued = self.queues.get(&sender).and_then(|queue| queue.get(&txn.transaction.nonce)).cloned();
+            if let Some(hash) = queued {
+                self.remove(&hash);
+            }
+        }
+    }
+
+    /// Up to `max` transactions the tip can execute in order: each sender's run of nonces without
+    /// gaps after its account nonce and its nonces already in PoW blocks, as far as its balance
+    /// pays for. Among the next transactions of all senders, the highest fee rate goes first, then
+    /// the earliest arrival.
+    pub fn select(&self, max: usize) -> Vec<SignedTransaction> {
+        // the first nonce and the balance left of every sender
+        let starts: Vec<(usize, usize)> = self.queues.keys()
+            .map(|sender| {
+                let (nonce, balance) = self.accounts[sender];
+                match self.included.get(sender) {
+                    Some((last, spent)) => (last + 1, balance.saturating_sub(*spent)),
+                    None => (nonce + 1, balance),
+                }
+            }).collect();
+        let queues: Vec<&BTreeMap<usize, H256>> = self.queues.values().collect();
+        // the transaction of queue `i` with `nonce`, if there is one and `balance` pays for it
+        let head = |i: usize, nonce: usize, balance: usize| {
+            let entry = &self.txns[queues[i].get(&nonce)?];
+            if cost(&entry.txn) > balance {
+                return None;
+            }
+            Some((entry.fee_rate, Reverse(entry.seq), i, nonce))
+        };
+        let mut balances: Vec<usize> = starts.iter().map(|(_, balance)| *balance).collect();
+        let mut heads: BinaryHeap<(u128, Reverse<u64>, usize, usize)> = starts.iter().enumerate()
+            .filter_map(|(i, (nonce, balance))| head(i, *nonce, *balance))
+            .collect();
+        let mut selected = Vec::new();
+        while let Some((_, _, i, nonce)) = heads.pop() {
+            if selected.len() >= max {
+                break;
+            }
+            let txn = &self.txns[&queues[i][&nonce]].txn;
+            balances[i] -= cost(txn);
+            selected.push(txn.clone());
+            if let Some(next) = head(i, nonce + 1, balances[i]) {
+                heads.push(next);
+            }
+        }
+        selected
+    }
+
+    /// Follow a new tip: drop the transactions its state executed and the ones expiring before
+    /// `height`, the height of its children, then add back `orphaned` transactions that are still
+    /// valid
+    pub fn update_tip(&mut self, accounts: &HashMap<H160, (usize, usize)>, height: u128, orphaned: Vec<SignedTransaction>) {
+        let mut executed: Vec<H256> = Vec::new();
+        for (sender, queue) in self.queues.iter() {
+            match accounts.get(sender) {
+                Some((nonce, _)) => executed.extend(queue.range(..=*nonce).map(|(_, hash)| *hash)),
+                None => executed.extend(queue.values().cloned()),
+            }
+        }
+        let expired = |txn: &SignedTransaction| txn.transaction.expiry.map_or(false, |expiry| expiry < height);
+        executed.extend(self.txns.iter().filter(|(_, entry)| expired(&entry.txn)).map(|(hash, _)| *hash));
+        for hash in executed {
+            self.remove(&hash);
+        }
+        for (sender, account) in self.accounts.iter_mut() {
+            *account = accounts[sender];
+        }
+        // the tip executed the runs in PoW blocks, or a reorg may have dropped them
+        self.included.retain(|sender, (last, _)| accounts.get(sender).map_or(false, |(nonce, _)| nonce < last));
+        for txn in orphaned.iter() {
+            self.included.remove(&sender(txn));
+        }
+        for txn in orphaned.into_iter().filter(|txn| !expired(txn)) {
+            let _ = self.insert(txn, accounts);
+        }
+    }
+}
+
+/// Add a transaction valid on top of the state of the current tip
+pub fn insert(mempool: &Mutex<Mempool>, blockchain: &Mutex<Blockchain>, state: &Mutex<State>, txn: SignedTransaction) -> Result<(), MempoolError> {
+    let tip = blockchain.lock().unwrap().tip();
+    let state = state.lock().unwrap();
+    state.envelope_check(&tip, &txn).map_err(MempoolError::Invalid)?;
+    match state.state_per_block.get(&tip) {
+        Some(accounts) => mempool.lock().unwrap().insert(txn, accounts),
+        None => Err(MempoolError::NoState),
+    }
+}
+
+/// Update the mempool after the tip changed. `old_branch` holds the PoS blocks that left the
+/// longest chain: transactions of the PoW blocks only they referenced go back to the mempool.
+pub fn update_tip(mempool: &Mutex<Mempool>, spam_recorder: &Mutex<SpamRecorder>, blockchain: &Mutex<Blockchain>, state: &Mutex<State>, old_branch: &[H256]) {
+    let (tip, orphaned) = {
+        let blockchain = blockchain.lock().unwrap();
+        (blockchain.tip(), blockchain.orphaned_txns(old_branch))
+    };
+    {
+        let mut spam_recorder = spam_recorder.lock().unwrap();
+        for txn in orphaned.iter() {
+            spam_recorder.forget(txn);
+        }
+    }
+    let state = state.lock().unwrap();
+    if let (Some(accounts), Some(height)) = (state.state_per_block.get(&tip), state.height(&tip)) {
+        mempool.lock().unwrap().update_tip(accounts, height + 1, orphaned);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+    use crate::state::compute_key_hash;
+    use crate::transaction::{generate_valid_signed_transaction, sign_transaction, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn account(key: &Ed25519KeyPair) -> H160 {
+        compute_key_hash(key.public_key().as_ref().to_vec()).into()
+    }
+
+    #[test]
+    fn nonce_queues() {
+        let alice = key_pair::random();
+        let bob = key_pair::random();
+        let carol = key_pair::random();
+        let mut accounts = HashMap::new();
+        accounts.insert(account(&alice), (0, 100));
+        accounts.insert(account(&bob), (0, 100));
+        accounts.insert(account(&carol), (0, 100));
+        let to = account(&carol);
+        let a: Vec<SignedTransaction> = (1..=3).map(|n| generate_valid_signed_transaction(to, 1, n, &alice)).collect();
+        let b: Vec<SignedTransaction> = (1..=2).map(|n| generate_valid_signed_transaction(to, 1, n, &bob)).collect();
+        let mut mempool = Mempool::new(5);
+
+        // alice's transactions arrive out of order
+        mempool.insert(a[1].clone(), &accounts).unwrap();
+        mempool.insert(b[0].clone(), &accounts).unwrap();
+        mempool.insert(a[0].clone(), &accounts).unwrap();
+        mempool.insert(b[1].clone(), &accounts).unwrap();
+        assert_eq!(mempool.insert(a[0].clone(), &accounts), Err(MempoolError::Known));
+        assert_eq!(mempool.insert(generate_valid_signed_transaction(to, 2, 1, &alice), &accounts), Err(MempoolError::NonceTaken));
+        assert_eq!(mempool.insert(generate_valid_signed_transaction(to, 200, 3, &bob), &accounts),
+            Err(MempoolError::Invalid(SkipReason::InsufficientBalance { balance: 100, value: 200 })));
+        // bob's first nonce arrived before alice's
+        assert_eq!(mempool.select(10), vec![b[0].clone(), a[0].clone(), a[1].clone(), b[1].clone()]);
+        assert_eq!(mempool.select(1), vec![b[0].clone()]);
+
+        // full: alice's third transaction evicts nothing, carol's evicts alice's highest nonce
+        mempool.insert(a[2].clone(), &accounts).unwrap();
+        assert_eq!(mempool.insert(generate_valid_signed_transaction(to, 1, 3, &bob), &accounts), Err(MempoolError::Full));
+        let c = generate_valid_signed_transaction(to, 1, 1, &carol);
+        mempool.insert(c.clone(), &accounts).unwrap();
+        assert!(!mempool.contains(&a[2].hash()));
+        assert_eq!(mempool.len(), 5);
+
+        // a block with bob's first nonce, then a tip that executed alice's first two
+        mempool.remove_included(&[generate_valid_signed_transaction(to, 7, 1, &bob)]);
+        assert!(!mempool.contains(&b[0].hash()));
+        accounts.insert(account(&alice), (2, 98));
+        mempool.update_tip(&accounts, 1, vec![a[2].clone()]);
+        assert_eq!(mempool.select(10), vec![b[1].clone(), c.clone(), a[2].clone()]);
+
+        // a fee replaces carol's transaction and puts it first, the same fee does not replace it
+        let paying = |fee| sign_transaction(Transaction { recv: to, value: 1, nonce: 1, fee, ..Default::default() }, &carol);
+        mempool.insert(paying(10), &accounts).unwrap();
+        assert!(!mempool.contains(&c.hash()));
+        assert_eq!(mempool.insert(paying(10), &accounts), Err(MempoolError::Known));
+        assert_eq!(mempool.insert(sign_transaction(Transaction { value: 2, ..paying(10).transaction }, &carol), &accounts), Err(MempoolError::NonceTaken));
+        assert_eq!(mempool.select(10), vec![paying(10), b[1].clone(), a[2].clone()]);
+
+        // bob replaces his transaction with one that expires after height 1
+        let expiring = sign_transaction(Transaction { expiry: Some(1), fee: 1, ..b[1].transaction.clone() }, &bob);
+        mempool.insert(expiring.clone(), &accounts).unwrap();
+        mempool.update_tip(&accounts, 2, vec![]);
+        assert_eq!(mempool.select(10), vec![paying(10), a[2].clone()]);
+
+        // a gap in dave's nonces is not selected, and carol's queue can't spend more than she has
+        let dave = key_pair::random();
+        accounts.insert(account(&dave), (0, 100));
+        let gap = generate_valid_signed_transaction(to, 1, 2, &dave);
+        mempool.update_tip(&accounts, 2, vec![]);
+        mempool.insert(gap.clone(), &accounts).unwrap();
+        assert_eq!(mempool.select(10), vec![paying(10), a[2].clone()]);
+        mempool.insert(generate_valid_signed_transaction(to, 89, 2, &carol), &accounts).unwrap();
+        assert_eq!(mempool.insert(generate_valid_signed_transaction(to, 1, 3, &carol), &accounts),
+            Err(MempoolError::Invalid(SkipReason::InsufficientBalance { balance: 100, value: 101 })));
+        let first = generate_valid_signed_transaction(to, 1, 1, &dave);
+        mempool.insert(first.clone(), &accounts).unwrap();
+        assert_eq!(mempool.select(10).len(), 5);
+        assert!(mempool.select(10).contains(&gap));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::hash_probability;
+use crate::resource::{self, STAKE};
+use serde::Serialize;
+use std::fmt::Display;
+
+/// Blocks this node mined and received, counted by the miner, the staker and the network workers
+#[derive(Default, Debug, Clone)]
+pub struct Metrics {
+    pub pow_mined: u64,
+    pub pos_mined: u64,
+    pub pow_received: u64,
+    pub pos_received: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn mined(&mut self, block: &Block) {
+        if block.block_type == STAKE {
+            self.pos_mined += 1;
+        } else {
+            self.pow_mined += 1;
+        }
+    }
+
+    /// Count a valid block received from a peer
+    pub fn received(&mut self, block: &Block) {
+        if block.block_type == STAKE {
+            self.pos_received += 1;
+        } else {
+            self.pow_received += 1;
+        }
+    }
+}
+
+/// Propagation delays of the received blocks in micro seconds, from their timestamp to their arrival
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Delays {
+    pub count: usize,
+    pub sum: u128,
+    pub p50: u128,
+    pub p90: u128,
+    pub p99: u128,
+    pub max: u128,
+}
+
+impl Delays {
+    pub fn new(delays: &[u128]) -> Self {
+        let mut sorted = delays.to_vec();
+        sorted.sort_unstable();
+        let quantile = |q: f64| -> u128 {
+            if sorted.is_empty() {
+                return 0;
+            }
+            sorted[((sorted.len() - 1) as f64 * q).round() as usize]
+        };
+        Delays {
+            count: sorted.len(),
+            sum: sorted.iter().sum(),
+            p50: quantile(0.5),
+            p90: quantile(0.9),
+            p99: quantile(0.99),
+            max: sorted.last().cloned().unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Difficulty {
+    pub resource: String,
+    pub target: String,
+    /// expected number of hashes or VRF evaluations per block
+    pub difficulty: f64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct EpochDifficulty {
+    pub epoch: u128,
+    pub difficulties: Vec<Difficulty>,
+}
+
+/// Snapshot of the node served by `/stats` and, in the Prometheus text format, by `/metrics`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub pow_mined: u64,
+    pub pos_mined: u64,
+    pub pow_received: u64,
+    pub pos_received: u64,
+    pub depth: u128,
+    pub public_length: u128,
+    pub private_lead: u128,
+    pub chain_quality: f32,
+    pub num_pos: u128,
+    pub num_pow: u128,
+    pub rejected_pow: u128,
+    /// PoS blocks off the longest chain
+    pub forked_pos: u128,
+    pub orphan_buffer: usize,
+    pub mempool: usize,
+    pub tranpool: usize,
+    pub delays: Delays,
+    pub epochs: Vec<EpochDifficulty>,
+}
+
+impl Stats {
+    pub fn new(metrics: &Metrics, blockchain: &Blockchain, orphan_buffer: usize, mempool: usize, tranpool: usize, delays: &[u128]) -> Self {
+        let resources = resource::builtin();
+        let epochs = blockchain.epoch_difficulties().into_iter()
+            .map(|(epoch, targets)| EpochDifficulty {
+                epoch,
+                difficulties: targets.iter().enumerate().map(|(id, target)| Difficulty {
+                    resource: resources.get(id).map(|r| r.name().to_string()).unwrap_or_else(|| id.to_string()),
+                    target: target.to_string(),
+                    difficulty: 1.0 / hash_probability(target),
+                }).collect(),
+            }).collect();
+        Stats {
+            pow_mined: metrics.pow_mined,
+            pos_mined: metrics.pos_mined,
+            pow_received: metrics.pow_received,
+            pos_received: metrics.pos_received,
+            depth: blockchain.get_depth(),
+            public_length: blockchain.get_pub_len(),
+            private_lead: blockchain.get_lead(),
+            chain_quality: blockchain.get_chain_quality(),
+            num_pos: blockchain.get_num_pos(),
+            num_pow: blockchain.get_num_pow(),
+            rejected_pow: blockchain.get_num_rejected_pow(),
+            forked_pos: blockchain.get_num_pos().saturating_sub(blockchain.get_depth()),
+            orphan_buffer,
+            mempool,
+            tranpool,
+            delays: Delays::new(delays),
+            epochs,
+        }
+    }
+
+    /// Prometheus text exposition format. Only the difficulty of the current epoch is exported,
+    /// the scrapes keep the history.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        metric(&mut out, "minotaur_pow_blocks_mined_total", "counter", "PoW blocks mined by this node", self.pow_mined);
+        metric(&mut out, "minotaur_pos_blocks_mined_total", "counter", "PoS blocks staked by this node", self.pos_mined);
+        metric(&mut out, "minotaur_pow_blocks_received_total", "counter", "valid PoW blocks received from peers", self.pow_received);
+        metric(&mut out, "minotaur_pos_blocks_received_total", "counter", "valid PoS blocks received from peers", self.pos_received);
+        metric(&mut out, "minotaur_chain_depth", "gauge", "height of the longest chain", self.depth);
+        metric(&mut out, "minotaur_public_length", "gauge", "height of the public chain seen by a selfish node", self.public_length);
+        metric(&mut out, "minotaur_private_lead", "gauge", "lead of the private chain of a selfish node", self.private_lead);
+        metric(&mut out, "minotaur_chain_quality", "gauge", "fraction of honest PoW blocks referenced by the longest chain", self.chain_quality);
+        metric(&mut out, "minotaur_pos_blocks", "gauge", "PoS blocks in the blockchain", self.num_pos);
+        metric(&mut out, "minotaur_pow_blocks", "gauge", "PoW blocks in the blockchain", self.num_pow);
+        metric(&mut out, "minotaur_rejected_pow_blocks_total", "counter", "PoW blocks rejected for an easier difficulty than the epoch schedule", self.rejected_pow);
+        metric(&mut out, "minotaur_forked_pos_blocks", "gauge", "PoS blocks off the longest chain", self.forked_pos);
+        metric(&mut out, "minotaur_orphan_buffer_size", "gauge", "blocks waiting for their parent", self.orphan_buffer);
+        metric(&mut out, "minotaur_mempool_size", "gauge", "transactions in the mempool", self.mempool);
+        metric(&mut out, "minotaur_tranpool_size", "gauge", "PoW blocks not referenced by the longest chain yet", self.tranpool);
+
+        out.push_str("# HELP minotaur_block_delay_seconds propagation delay of the received blocks\n");
+        out.push_str("# TYPE minotaur_block_delay_seconds summary\n");
+        for (quantile, delay) in [("0.5", self.delays.p50), ("0.9", self.delays.p90), ("0.99", self.delays.p99)].iter() {
+            out.push_str(&format!("minotaur_block_delay_seconds{{quantile=\"{}\"}} {}\n", quantile, seconds(*delay)));
+        }
+        out.push_str(&format!("minotaur_block_delay_seconds_sum {}\n", seconds(self.delays.sum)));
+        out.push_str(&format!("minotaur_block_delay_seconds_count {}\n", self.delays.count));
+
+        if let Some(current) = self.epochs.last() {
+            metric(&mut out, "minotaur_epoch", "gauge", "epoch of the tip", current.epoch);
+            out.push_str("# HELP minotaur_difficulty expected hashes or VRF evaluations per block in the epoch of the tip\n");
+            out.push_str("# TYPE minotaur_difficulty gauge\n");
+            for difficulty in current.difficulties.iter() {
+                out.push_str(&format!("minotaur_difficulty{{resource=\"{}\"}} {}\n", difficulty.resource, difficulty.difficulty));
+            }
+        }
+        out
+    }
+}
+
+fn metric<T: Display>(out: &mut String, name: &str, kind: &str, help: &str, value: T) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+}
+
+fn seconds(micros: u128) -> f64 {
+    micros as f64 / 1e6
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::ForkChoice;
+    use crate::genesis::GenesisSpec;
+
+    #[test]
+    fn prometheus() {
+        let blockchain = Blockchain::new(0, &GenesisSpec::default(), ForkChoice::LongestChain);
+        let metrics = Metrics { pow_mined: 3, pos_mined: 2, pow_received: 0, pos_received: 7 };
+        let delays: Vec<u128> = (1..=100).map(|d| d * 1000).collect();
+        let stats = Stats::new(&metrics, &blockchain, 1, 5, 4, &delays);
+
+        assert_eq!(stats.delays, Delays { count: 100, sum: 5_050_000, p50: 51_000, p90: 90_000, p99: 99_000, max: 100_000 });
+        assert_eq!(stats.epochs.len(), 1);
+        assert_eq!(stats.epochs[0].difficulties[1].resource, "work");
+
+        let text = stats.to_prometheus();
+        assert!(text.contains("# TYPE minotaur_pos_blocks_received_total counter\nminotaur_pos_blocks_received_total 7\n"));
+        assert!(text.contains("minotaur_mempool_size 5\n"));
+        assert!(text.contains("minotaur_block_delay_seconds{quantile=\"0.9\"} 0.09\n"));
+        assert!(text.contains("minotaur_block_delay_seconds_sum 5.05\n"));
+        assert!(text.contains("minotaur_difficulty{resource=\"stake\"} "));
+        // every sample line is a name, optional labels and a value
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            assert_eq!(line.split(' ').count(), 2, "{}", line);
+        }
+    }
+}
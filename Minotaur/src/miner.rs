@@ -1,7 +1,9 @@
 use std::collections::HashMap;
+use crate::mempool::Mempool;
 use crate::spam_recorder::SpamRecorder;
-use crate::transaction::SignedTransaction;
 use crate::transaction::generate_random_transaction;
+use crate::resource::WORK;
+use crate::metrics::Metrics;
 use crate::block::generate_pow_block;
 use crate::block::{Block, Header, Content};
 use crate::crypto::merkle::MerkleTree;
@@ -14,11 +16,13 @@ use crate::state::{State,transaction_check,compute_key_hash};
 
 use log::debug;
 use log::info;
+use log::error;
 use std::sync::{Arc, Mutex};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time;
 use std::time::{SystemTime, UNIX_EPOCH};
+use std::process;
 use std::thread;
 use rand::Rng;
 
@@ -50,7 +54,7 @@ pub struct Context {
     context_update_recv: Receiver<ContextUpdateSignal>,
     context_update_send: Sender<ContextUpdateSignal>,
     server: ServerHandle,
-    mempool: Arc<Mutex<Vec<SignedTransaction>>>,
+    mempool: Arc<Mutex<Mempool>>,
     spam_recorder: Arc<Mutex<SpamRecorder>>,
     state: Arc<Mutex<State>>,
     all_blocks: Arc<Mutex<HashMap<H256,Block>>>,
@@ -60,6 +64,8 @@ pub struct Context {
     selfish_miner: bool,
     beta: f64,
     atttime: u128,
+    metrics: Arc<Mutex<Metrics>>,
+    coinbase: H160,
 }
 
 #[derive(Clone)]
@@ -73,7 +79,7 @@ pub fn new(
     context_update_recv: Receiver<ContextUpdateSignal>,
     context_update_send: Sender<ContextUpdateSignal>,
     server: &ServerHandle,
-    mempool: &Arc<Mutex<Vec<SignedTransaction>>>,
+    mempool: &Arc<Mutex<Mempool>>,
     spam_recorder: &Arc<Mutex<SpamRecorder>>,
     state: &Arc<Mutex<State>>,
     all_blocks: &Arc<Mutex<HashMap<H256,Block>>>,
@@ -83,6 +89,8 @@ pub fn new(
     selfish_miner: bool,
     beta: f64,
     atttime: u128,
+    metrics: &Arc<Mutex<Metrics>>,
+    coinbase: H160,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
 
@@ -103,6 +111,8 @@ pub fn new(
         selfish_miner: selfish_miner,
         beta,
         atttime,
+        metrics: Arc::clone(metrics),
+        coinbase,
     };
 
     let handle = Handle {
@@ -154,6 +164,7 @@ impl Context {
         let txn_number = 32;
         let mut count = 0;
         let mut epoch:u128 = 0;
+        let mut epoch_rand: Option<(H256, u128, u128)> = None;
         let start: time::SystemTime = SystemTime::now();
         let mut vrf = ECVRF::from_suite(CipherSuite::SECP256K1_SHA256_TAI).unwrap();
         // //Inputs: Secret Key, Public Key (derived) & Message
@@ -164,36 +175,21 @@ impl Context {
         macro_rules! get_data_from_mempool {
             () => {
                 {
-                    let mut mem_snap = self.mempool.lock().unwrap();
-                    let mut spam_recorder= self.spam_recorder.lock().unwrap();
-                    let mut data: Vec<SignedTransaction> = vec![];
-                    let mut remove_index = vec![];
-                    let mut spam_buffer = SpamRecorder::new();
-                    let mut last_index = mem_snap.len()-1;
-                    for (index, txn) in mem_snap.iter().enumerate() {
-                        // filter out spam txn
-                        if spam_recorder.test(txn) && spam_buffer.test_and_set(txn) {
-                            data.push(txn.clone());
+                    let mut mempool = self.mempool.lock().unwrap();
+                    let spam_recorder = self.spam_recorder.lock().unwrap();
+                    loop {
+                        let data = mempool.select(txn_number);
+                        // drop spam, transactions whose sender and nonce are in a PoW block already
+                        let spam: Vec<H256> = data.iter().filter(|txn| !spam_recorder.test(txn)).map(|txn| txn.hash()).collect();
+                        if spam.is_empty() {
                             if data.len() >= txn_number {
-                                last_index = index;
-                                break
+                                break (true, data);
                             }
-                        } else {
-                            remove_index.push(index);
+                            break (false, vec![]);
+                        }
+                        for hash in spam {
+                            mempool.remove(&hash);
                         }
-                    }
-                    if data.len() >= txn_number {
-                        //info!("[Spam]Collect {} txns, filter {} spam", data.len(), remove_index.len());
-                        mem_snap.iter().take(last_index+1).for_each(|txn|{spam_recorder.test_and_set(txn);})
-                    }
-                    // remove txn that already recorded (hence is spam)
-                    for index in remove_index.into_iter().rev() {
-                        mem_snap.swap_remove(index);
-                    }
-                    if data.len() >= txn_number {
-                        (true, data)
-                    } else {
-                        (false, vec![])
                     }
                 }
             }
@@ -229,7 +225,7 @@ impl Context {
             let pow_difficulty = self.blockchain.lock().unwrap().get_pow_difficulty(ts,parent);
             let current_epoch = self.blockchain.lock().unwrap().epoch(ts);
             if current_epoch > epoch {
-                let old_diff = self.blockchain.lock().unwrap().find_one_header(&parent).unwrap().pow_difficulty;
+                let old_diff = self.blockchain.lock().unwrap().find_one_header(&parent).unwrap().difficulty(WORK);
                 debug!("Epoch {}: Mining difficulty changes from {} to {}",current_epoch,old_diff, pow_difficulty);
                 epoch = current_epoch;
             }
@@ -238,7 +234,15 @@ impl Context {
             let mut rng = rand::thread_rng();
             let transaction_ref: Vec<H256> = Default::default();
 
-            let rand: u128 = Default::default();  // TODO: update rand every epoch
+            // the beacon only changes with the parent or the epoch
+            let rand: u128 = match epoch_rand {
+                Some((h, e, rand)) if h == parent && e == current_epoch => rand,
+                _ => {
+                    let rand = self.blockchain.lock().unwrap().epoch_rand(&parent, ts);
+                    epoch_rand = Some((parent, current_epoch, rand));
+                    rand
+                }
+            };
             //let ts_slice = ts.to_be_bytes();
             //let rand_slice = rand.to_be_bytes();
             //let message = [rand_slice,ts_slice].concat();
@@ -273,8 +277,9 @@ impl Context {
             let (enough_txn, data) = get_data_from_mempool!();//TODO add this to handle context update as well!
 
             if enough_txn {
-                let mut blk = generate_pow_block(&data, &transaction_ref, &parent, rng.gen(), &pow_difficulty, &pos_difficulty, ts, &vrf_proof, &vrf_hash, 
+                let mut blk = generate_pow_block(&data, &transaction_ref, &parent, rng.gen(), &[pos_difficulty, pow_difficulty], ts, &vrf_proof, &vrf_hash, 
                     &self.vrf_public_key, rand, self.selfish_miner);
+                blk.header.coinbase = self.coinbase;
                 loop {
                     // info!("Start mining!");
                     handle_context_update!(blk); 
@@ -283,14 +288,22 @@ impl Context {
                     // if no requirement to change it, just use pow_difficulty
                     let difficulty_times_beta = hash_multiply_by(&pow_difficulty, self.beta);
                     if blk.hash() <= difficulty_times_beta {
-                        self.blockchain.lock().unwrap().insert_pow(&blk);
+                        if let Err(e) = self.blockchain.lock().unwrap().insert_pow(&blk) {
+                            error!("Failed to store PoW block {}: {}, shutting down", blk.hash(), e);
+                            process::exit(1);
+                        }
                         // let copy = blk.clone();
                         count += 1;
+                        self.metrics.lock().unwrap().mined(&blk);
                         info!("Mined {} PoW blocks!", count);
 
                         let txns = &blk.content.data;
                         let hash = blk.hash().clone();
-                        self.mempool.lock().unwrap().retain(|txn| !txns.contains(txn));
+                        {
+                            let mut spam_recorder = self.spam_recorder.lock().unwrap();
+                            txns.iter().for_each(|txn|{spam_recorder.test_and_set(txn);});
+                        }
+                        self.mempool.lock().unwrap().remove_included(txns);
                         if !self.tranpool.lock().unwrap().contains(&hash) {
                             self.tranpool.lock().unwrap().push(hash.clone());
                         }
@@ -0,0 +1,240 @@
+use log::{info, warn};
+use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File in the datadir the address book is kept in
+const ADDR_FILE: &str = "peers.json";
+/// Most addresses kept, and sent in one `Addr` message
+pub const MAX_ADDRS: usize = 1000;
+/// Most new addresses one `Addr` message adds
+pub const MAX_NEW_PER_MESSAGE: usize = 32;
+/// Most addresses kept that one peer IP told us about
+const MAX_PER_SOURCE: usize = 64;
+/// Addresses that failed this many connections in a row are forgotten
+const MAX_FAILURES: u32 = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddrEntry {
+    pub addr: SocketAddr,
+    /// unix time of the last successful handshake, 0 if never connected
+    pub last_seen: u64,
+    /// failed connections since the last successful one
+    pub failures: u32,
+    /// IP of the peer that gossiped the address, `None` for `-c` and handshakes
+    #[serde(default)]
+    pub source: Option<IpAddr>,
+    /// unix time the address was learned
+    #[serde(default)]
+    pub added: u64,
+}
+
+impl AddrEntry {
+    /// Whether a full book may drop the entry for a new one: it failed, or we never connected
+    fn evictable(&self) -> bool {
+        self.failures > 0 || self.last_seen == 0
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// P2P addresses learned from `-c`, from handshakes and from `Addr` gossip, kept in
+/// `peers.json` of the datadir if there is one.
+pub struct AddrBook {
+    addrs: HashMap<SocketAddr, AddrEntry>,
+    path: Option<PathBuf>,
+}
+
+impl AddrBook {
+    pub fn new() -> Self {
+        AddrBook {
+            addrs: HashMap::new(),
+            path: None,
+        }
+    }
+
+    /// Open the address book in `datadir`, creating it if needed
+    pub fn open<P: AsRef<Path>>(datadir: P) -> io::Result<Self> {
+        fs::create_dir_all(&datadir)?;
+        let path = datadir.as_ref().join(ADDR_FILE);
+        let mut book = AddrBook {
+            addrs: HashMap::new(),
+            path: Some(path.clone()),
+        };
+        if path.exists() {
+            let entries: Vec<AddrEntry> = serde_json::from_slice(&fs::read(&path)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            for entry in entries {
+                book.addrs.insert(entry.addr, entry);
+            }
+            info!("Loaded {} peer addresses from {:?}", book.addrs.len(), path);
+        }
+        Ok(book)
+    }
+
+    /// Learn addresses that `source` gossiped (`None` if they are from `-c`), returns how many
+    /// were new. A gossiping peer adds at most `MAX_NEW_PER_MESSAGE` addresses at once and keeps
+    /// at most `MAX_PER_SOURCE` in the book; a full book evicts the entry that failed most, then
+    /// the oldest one never connected to.
+    pub fn add(&mut self, source: Option<IpAddr>, addrs: &[SocketAddr]) -> usize {
+        let mut num_new = 0;
+        let mut from_source = match source {
+            Some(ip) => self.addrs.values().filter(|entry| entry.source == Some(ip)).count(),
+            None => 0,
+        };
+        for addr in addrs {
+            if source.is_some() && (num_new >= MAX_NEW_PER_MESSAGE || from_source >= MAX_PER_SOURCE) {
+                break;
+            }
+            if self.addrs.contains_key(addr) || !self.make_room() {
+                continue;
+            }
+            self.addrs.insert(*addr, AddrEntry {
+                addr: *addr,
+                last_seen: 0,
+                failures: 0,
+                source,
+                added: now(),
+            });
+            num_new += 1;
+            from_source += 1;
+        }
+        if num_new > 0 {
+            self.save();
+        }
+        num_new
+    }
+
+    /// Record a successful handshake with the node listening at `addr`
+    pub fn seen(&mut self, addr: SocketAddr) {
+        if !self.addrs.contains_key(&addr) && !self.make_room() {
+            return;
+        }
+        let added = self.addrs.get(&addr).map_or_else(now, |entry| entry.added);
+        self.addrs.insert(addr, AddrEntry {
+            addr,
+            last_seen: now(),
+            failures: 0,
+            source: None,
+            added,
+        });
+        self.save();
+    }
+
+    /// Evict an entry if the book is full, false if every entry is a working peer
+    fn make_room(&mut self) -> bool {
+        if self.addrs.len() < MAX_ADDRS {
+            return true;
+        }
+        let victim = self.addrs.values()
+            .filter(|entry| entry.evictable())
+            .max_by(|a, b| a.failures.cmp(&b.failures).then(b.added.cmp(&a.added)))
+            .map(|entry| entry.addr);
+        match victim {
+            Some(addr) => {
+                self.addrs.remove(&addr);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record a failed connection to `addr`, forgetting it after too many
+    pub fn failed(&mut self, addr: SocketAddr) {
+        let forget = match self.addrs.get_mut(&addr) {
+            Some(entry) => {
+                entry.failures += 1;
+                entry.failures >= MAX_FAILURES
+            }
+            None => false,
+        };
+        if forget {
+            self.addrs.remove(&addr);
+        }
+        self.save();
+    }
+
+    /// Up to `n` random addresses, for `Addr` replies
+    pub fn sample(&self, n: usize) -> Vec<SocketAddr> {
+        let addrs: Vec<SocketAddr> = self.addrs.keys().cloned().collect();
+        addrs.choose_multiple(&mut rand::thread_rng(), n).cloned().collect()
+    }
+
+    /// Addresses to connect to, not in `exclude`, the ones that failed least and were seen last first
+    pub fn candidates(&self, exclude: &HashSet<SocketAddr>) -> Vec<SocketAddr> {
+        let mut entries: Vec<&AddrEntry> = self.addrs.values()
+            .filter(|entry| !exclude.contains(&entry.addr))
+            .collect();
+        entries.sort_by(|a, b| a.failures.cmp(&b.failures).then(b.last_seen.cmp(&a.last_seen)));
+        entries.into_iter().map(|entry| entry.addr).collect()
+    }
+
+    pub fn entries(&self) -> Vec<AddrEntry> {
+        self.addrs.values().cloned().collect()
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            let entries = self.entries();
+            if let Err(e) = fs::write(path, serde_json::to_vec_pretty(&entries).unwrap()) {
+                warn!("Error saving peer addresses to {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidates() {
+        let a: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let c: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let mut book = AddrBook::new();
+        assert_eq!(book.add(None, &[a, b, c, a]), 3);
+        book.seen(b);
+        book.failed(a);
+        let mut exclude = HashSet::new();
+        assert_eq!(book.candidates(&exclude), vec![b, c, a]);
+        exclude.insert(b);
+        assert_eq!(book.candidates(&exclude), vec![c, a]);
+        for _ in 1..MAX_FAILURES {
+            book.failed(a);
+        }
+        assert_eq!(book.candidates(&exclude), vec![c]);
+    }
+
+    #[test]
+    fn flooding() {
+        let mut book = AddrBook::new();
+        let good: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        book.seen(good);
+        // one message and one source only add so many
+        let fake = |i: usize| SocketAddr::new([10, 1, (i >> 8) as u8, i as u8].into(), 6000);
+        let spam: Vec<SocketAddr> = (0..MAX_ADDRS).map(fake).collect();
+        let attacker: IpAddr = [10, 9, 9, 9].into();
+        assert_eq!(book.add(Some(attacker), &spam), MAX_NEW_PER_MESSAGE);
+        while book.add(Some(attacker), &spam) > 0 {}
+        assert_eq!(book.entries().len(), 1 + MAX_PER_SOURCE);
+
+        // a full book makes room for new addresses, but keeps the ones we connected to
+        for i in 0..MAX_ADDRS {
+            let source: IpAddr = [10, 2, (i >> 8) as u8, i as u8].into();
+            book.add(Some(source), &[SocketAddr::new(source, 6000)]);
+        }
+        assert_eq!(book.entries().len(), MAX_ADDRS);
+        let honest: SocketAddr = "10.3.0.1:6000".parse().unwrap();
+        assert_eq!(book.add(Some([10, 3, 0, 2].into()), &[honest]), 1);
+        assert!(book.candidates(&HashSet::new()).contains(&honest));
+        assert!(book.candidates(&HashSet::new()).contains(&good));
+    }
+}
@@ -0,0 +1,297 @@
+use crossbeam::channel as cbchannel;
+use log::trace;
+use mio_extras::channel;
+use rand::distributions::{Distribution, Exp, Normal};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// One way latency of a link in micro seconds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Latency {
+    Fixed(u64),
+    Uniform { min: u64, max: u64 },
+    Exponential { mean: u64 },
+    /// negative samples count as 0
+    Normal { mean: u64, std_dev: u64 },
+}
+
+impl Latency {
+    fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+        match self {
+            Latency::Fixed(micros) => *micros,
+            Latency::Uniform { min, max } => rng.gen_range(*min, max + 1),
+            Latency::Exponential { mean } => {
+                if *mean == 0 {
+                    return 0;
+                }
+                Exp::new(1.0 / *mean as f64).sample(rng) as u64
+            }
+            Latency::Normal { mean, std_dev } => {
+                Normal::new(*mean as f64, *std_dev as f64).sample(rng).max(0.0) as u64
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LinkProfile {
+    pub latency: Latency,
+    /// upper bound on the sampled latency in micro seconds, the Δ of a bounded-delay network
+    #[serde(default)]
+    pub max_latency: Option<u64>,
+    /// bytes per second, unlimited if not set
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+    /// probability that a message is dropped
+    #[serde(default)]
+    pub loss: f64,
+}
+
+impl LinkProfile {
+    /// Micro seconds a message of `size` bytes keeps the link busy
+    pub fn transmission_time(&self, size: usize) -> u64 {
+        match self.bandwidth {
+            Some(bandwidth) => size as u64 * 1_000_000 / bandwidth,
+            None => 0,
+        }
+    }
+
+    /// Latency of one message, None if it is lost. Messages that are not `droppable` are never lost.
+    pub fn sample_latency<R: Rng>(&self, rng: &mut R, droppable: bool) -> Option<u64> {
+        if droppable && self.loss > 0.0 && rng.gen::<f64>() < self.loss {
+            return None;
+        }
+        let latency = self.latency.sample(rng);
+        Some(match self.max_latency {
+            Some(max_latency) => latency.min(max_latency),
+            None => latency,
+        })
+    }
+}
+
+impl Default for LinkProfile {
+    fn default() -> Self {
+        LinkProfile {
+            latency: Latency::Fixed(0),
+            max_latency: None,
+            bandwidth: None,
+            loss: 0.0,
+        }
+    }
+}
+
+/// Network emulation of `--netem`: the profile of the links to every peer, and overrides for
+/// the peers listening at the given addresses
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NetemConfig {
+    #[serde(default)]
+    pub default: LinkProfile,
+    #[serde(default)]
+    pub links: HashMap<SocketAddr, LinkProfile>,
+}
+
+impl NetemConfig {
+    /// Read a network emulation config from a JSON file
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(filename).map_err(|e| format!("cannot read {}: {}", filename, e))?;
+        let config: NetemConfig = serde_json::from_str(&content).map_err(|e| format!("cannot parse {}: {}", filename, e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for profile in std::iter::once(&self.default).chain(self.links.values()) {
+            if !(0.0..=1.0).contains(&profile.loss) {
+                return Err(format!("loss {} is not in [0, 1]", profile.loss));
+            }
+            if let Latency::Uniform { min, max } = profile.latency {
+                if min > max {
+                    return Err(format!("uniform latency min {} is above max {}", min, max));
+                }
+            }
+            if profile.bandwidth == Some(0) {
+                return Err("bandwidth must be positive".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Profile of the link to the peer listening at `listen_addr`
+    pub fn profile(&self, listen_addr: &SocketAddr) -> LinkProfile {
+        self.links.get(listen_addr).unwrap_or(&self.default).clone()
+    }
+}
+
+// a message waiting for its delivery time
+struct Delayed {
+    at: Instant,
+    seq: u64,
+    buffer: Vec<u8>,
+    queue: channel::Sender<Vec<u8>>,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        self.at == other.at && self.seq == other.seq
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    // reversed, the heap pops the earliest message first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.at.cmp(&self.at).then(other.seq.cmp(&self.seq))
+    }
+}
+
+/// Hands delayed messages to the peer write queues when they are due
+pub struct Emulator {
+    config: NetemConfig,
+    scheduler: cbchannel::Sender<Delayed>,
+}
+
+impl Emulator {
+    pub fn new(config: NetemConfig) -> Self {
+        let (scheduler, delayed) = cbchannel::unbounded();
+        thread::Builder::new()
+            .name("netem".to_string())
+            .spawn(move || deliver(delayed))
+            .unwrap();
+        Emulator { config, scheduler }
+    }
+
+    /// A new link, with the default profile until the peer's listen address is known
+    pub fn link(&self) -> Link {
+        let now = Instant::now();
+        Link {
+            profile: self.config.default.clone(),
+            busy_until: now,
+            last_delivery: now,
+            scheduler: self.scheduler.clone(),
+        }
+    }
+
+    pub fn profile(&self, listen_addr: &SocketAddr) -> LinkProfile {
+        self.config.profile(listen_addr)
+    }
+}
+
+fn deliver(delayed: cbchannel::Receiver<Delayed>) {
+    let mut heap: BinaryHeap<Delayed> = BinaryHeap::new();
+    let mut seq = 0;
+    loop {
+        let received = match heap.peek() {
+            Some(next) => {
+                let now = Instant::now();
+                if next.at <= now {
+                    let next = heap.pop().unwrap();
+                    if next.queue.send(next.buffer).is_err() {
+                        trace!("Peer dropped before a delayed message was delivered");
+                    }
+                    continue;
+                }
+                match delayed.recv_timeout(next.at - now) {
+                    Ok(msg) => msg,
+                    Err(cbchannel::RecvTimeoutError::Timeout) => continue,
+                    Err(cbchannel::RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            None => match delayed.recv() {
+                Ok(msg) => msg,
+                Err(_) => return,
+            },
+        };
+        // keep the order messages with the same delivery time were sent in
+        seq += 1;
+        heap.push(Delayed { seq, ..received });
+    }
+}
+
+/// The emulated link to one peer. Messages queue behind each other for the bandwidth, then
+/// travel for a sampled latency, and are never reordered, like on a TCP connection.
+pub struct Link {
+    profile: LinkProfile,
+    // when the bytes sent so far have left the link
+    busy_until: Instant,
+    last_delivery: Instant,
+    scheduler: cbchannel::Sender<Delayed>,
+}
+
+impl Link {
+    pub fn set_profile(&mut self, profile: LinkProfile) {
+        self.profile = profile;
+    }
+
+    /// Delivery time of a message of `size` bytes sent at `now`, None if it is lost
+    fn schedule<R: Rng>(&mut self, size: usize, now: Instant, droppable: bool, rng: &mut R) -> Option<Instant> {
+        let latency = self.profile.sample_latency(rng, droppable)?;
+        self.busy_until = self.busy_until.max(now) + Duration::from_micros(self.profile.transmission_time(size));
+        self.last_delivery = self.last_delivery.max(self.busy_until + Duration::from_micros(latency));
+        Some(self.last_delivery)
+    }
+
+    /// Send `buffer` to `queue` once it crossed the link. Messages that are not `droppable`
+    /// are delayed but never lost. Returns false if the message was lost.
+    pub fn send(&mut self, buffer: Vec<u8>, queue: &channel::Sender<Vec<u8>>, droppable: bool) -> bool {
+        match self.schedule(buffer.len(), Instant::now(), droppable, &mut rand::thread_rng()) {
+            Some(at) => {
+                let delayed = Delayed {
+                    at,
+                    seq: 0,
+                    buffer,
+                    queue: queue.clone(),
+                };
+                // the scheduler only stops once every link is dropped
+                self.scheduler.send(delayed).unwrap();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_schedule() {
+        let config: NetemConfig = serde_json::from_str(r#"{
+            "default": {"latency": {"fixed": 1000}, "bandwidth": 1000000},
+            "links": {"127.0.0.1:6001": {"latency": {"uniform": {"min": 0, "max": 100000}}, "max_latency": 5000, "loss": 1.0}}
+        }"#).unwrap();
+        let emulator = Emulator::new(config);
+        let mut rng = rand::thread_rng();
+        let mut link = emulator.link();
+        let mut lossy = emulator.link();
+        lossy.set_profile(emulator.profile(&"127.0.0.1:6001".parse().unwrap()));
+        let now = Instant::now();
+
+        // 1000 bytes take 1ms at 1MB/s, then 1ms of latency; the second message waits for the first
+        assert_eq!(link.schedule(1000, now, true, &mut rng), Some(now + Duration::from_millis(2)));
+        assert_eq!(link.schedule(1000, now, true, &mut rng), Some(now + Duration::from_millis(3)));
+
+        let mut link = lossy;
+        assert_eq!(link.schedule(1000, now, true, &mut rng), None);
+        let mut last = now;
+        for _ in 0..100 {
+            let at = link.schedule(1000, now, false, &mut rng).unwrap();
+            assert!(at >= last && at <= now + Duration::from_micros(5000));
+            last = at;
+        }
+    }
+}
@@ -1,13 +1,57 @@
 use crate::transaction::SignedTransaction;
+use std::net::SocketAddr;
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::H256;
-use crate::block::Block;
+use crate::block::{Block, Header};
 use tari_mmr::{MerkleMountainRange, MerkleProof, Hash};
 use sha2::{Digest, Sha256};
 use crate::blockchain::{FlyClientProposal,FlyClientProof,FlyClientQuery};
 
+/// Protocol name sent in the handshake, nodes of other protocols are dropped
+pub const PROTOCOL: &str = "minotaur";
+/// Bumped on every incompatible change of the messages
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Services {
+    Full,
+    SPV,
+    Fly,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub protocol: String,
+    pub version: u32,
+    pub genesis_hash: H256,
+    pub best_height: u128,
+    pub services: Services,
+    /// the port the sender accepts connections at
+    pub listen_port: u16,
+}
+
+impl Version {
+    /// Why a node that sent `other` cannot talk to us, if it cannot
+    pub fn check(&self, other: &Version) -> Result<(), String> {
+        if other.protocol != self.protocol {
+            return Err(format!("protocol {} instead of {}", other.protocol, self.protocol));
+        }
+        if other.version != self.version {
+            return Err(format!("protocol version {} instead of {}", other.version, self.version));
+        }
+        if other.genesis_hash != self.genesis_hash {
+            return Err(format!("genesis block {} instead of {}", other.genesis_hash, self.genesis_hash));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
+    // handshake, first on every connection. These stay the first variants in every client so
+    // that nodes of other protocols decode them and get dropped.
+    Version(Version),
+    Verack(),
     Ping(String),
     Pong(String),
     NewBlockHashes(Vec<H256>),
@@ -26,5 +70,42 @@ pub enum Message {
     FlyGetChain(),
     FlyChain(FlyClientProposal,FlyClientProof),
     FlyVerifyRandomTxn(),
-    FlyTxnProof(FlyClientProposal, FlyClientProof, H256, Vec<H256>, usize, usize,H256)
+    FlyTxnProof(FlyClientProposal, FlyClientProof, H256, Vec<H256>, usize, usize,H256),
+    // headers-first sync: block locator, and the headers of the longest chain after it
+    GetHeaders(Vec<H256>),
+    Headers(Vec<Header>),
+    // peer discovery
+    GetAddr(),
+    Addr(Vec<SocketAddr>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::generate_random_hash;
+
+    #[test]
+    fn version_check() {
+        let ours = Version {
+            protocol: PROTOCOL.to_string(),
+            version: PROTOCOL_VERSION,
+            genesis_hash: generate_random_hash(),
+            best_height: 10,
+            services: Services::Full,
+            listen_port: 6000,
+        };
+        let mut theirs = ours.clone();
+        theirs.best_height = 0;
+        theirs.services = Services::SPV;
+        assert!(ours.check(&theirs).is_ok());
+        theirs.protocol = "bitcoin".to_string();
+        assert!(ours.check(&theirs).is_err());
+        // the handshake is the first variant on the wire, whatever the client
+        let encoded = bincode::serialize(&Message::Version(ours.clone())).unwrap();
+        assert_eq!(&encoded[..4], &[0, 0, 0, 0]);
+        match bincode::deserialize(&encoded).unwrap() {
+            Message::Version(version) => assert!(ours.check(&version).is_ok()),
+            _ => panic!("not a version message"),
+        }
+    }
 }
@@ -1,7 +1,11 @@
+pub mod addrbook;
+pub mod emulation;
 pub mod message;
 pub mod peer;
+pub mod scores;
 pub mod server;
 pub mod worker;
 pub mod spv_worker;
 pub mod selfish_worker;
+pub mod sync;
 //pub mod fly_worker;
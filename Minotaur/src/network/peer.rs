@@ -1,10 +1,11 @@
+use super::emulation::{Link, LinkProfile};
 use super::message;
 use log::{trace, warn};
 use mio;
 use mio_extras::channel;
 use std::convert::TryInto;
 use std::io::{Read, Write};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 
 enum DecodeState {
     Length,
@@ -158,6 +159,7 @@ impl WriteContext {
 pub fn new(
     stream: mio::net::TcpStream,
     direction: Direction,
+    link: Option<Link>,
 ) -> std::io::Result<(Context, Handle)> {
     let reader_stream = stream.try_clone()?;
     let writer_stream = stream.try_clone()?;
@@ -184,6 +186,8 @@ pub fn new(
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        listen_addr: Arc::new(Mutex::new(None)),
+        link: link.map(|link| Arc::new(Mutex::new(link))),
     };
     let ctx = Context {
         addr,
@@ -192,6 +196,10 @@ pub fn new(
         writer: write_ctx,
         handle: handle.clone(),
         direction,
+        verified: false,
+        version: None,
+        listen_addr: None,
+        connected_at: std::time::Instant::now(),
     };
     Ok((ctx, handle))
 }
@@ -209,20 +217,61 @@ pub struct Context {
     pub writer: WriteContext,
     pub handle: Handle,
     pub direction: Direction,
+    /// whether the handshake is complete
+    pub verified: bool,
+    /// the handshake of the peer, once it has sent a compatible one
+    pub version: Option<message::Version>,
+    /// the address the peer accepts connections at, known after the handshake
+    pub listen_addr: Option<std::net::SocketAddr>,
+    /// when the connection was registered, the handshake must complete in time
+    pub connected_at: std::time::Instant,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     addr: std::net::SocketAddr,
+    // the address the peer accepts connections at, set for every clone by the handshake
+    listen_addr: Arc<Mutex<Option<std::net::SocketAddr>>>,
     write_queue: channel::Sender<Vec<u8>>,
+    // network emulation, messages are delayed on it before they reach the write queue
+    link: Option<Arc<Mutex<Link>>>,
 }
 
 impl Handle {
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// The address the peer accepts connections at, the connection address until the handshake
+    /// tells it
+    pub fn listen_addr(&self) -> std::net::SocketAddr {
+        self.listen_addr.lock().unwrap().unwrap_or(self.addr)
+    }
+
+    pub fn set_listen_addr(&self, addr: std::net::SocketAddr) {
+        *self.listen_addr.lock().unwrap() = Some(addr);
+    }
+
     pub fn write(&self, msg: message::Message) {
         // TODO: return result
         let buffer = bincode::serialize(&msg).unwrap();
+        if let Some(link) = &self.link {
+            // a lost handshake would leave the connection hanging
+            let droppable = !matches!(msg, message::Message::Version(_) | message::Message::Verack());
+            if !link.lock().unwrap().send(buffer, &self.write_queue, droppable) {
+                trace!("Emulated network lost a message to peer {}", self.addr);
+            }
+            return;
+        }
         if self.write_queue.send(buffer).is_err() {
             warn!("Failed to send write request for peer {}, channel detached", self.addr);
         }
     }
+
+    /// Switch the emulated link to `profile`, once the peer's listen address is known
+    pub fn set_link_profile(&self, profile: LinkProfile) {
+        if let Some(link) = &self.link {
+            link.lock().unwrap().set_profile(profile);
+        }
+    }
 }
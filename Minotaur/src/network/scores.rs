@@ -0,0 +1,226 @@
+use crate::crypto::hash::H256;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Score of a newly connected peer, it is banned once it reaches 0
+const INITIAL_SCORE: i64 = 100;
+/// Responses arriving later than this count as unsolicited
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehaviour {
+    /// a message that does not decode
+    Undecodable,
+    /// a block or header that fails validation
+    InvalidBlock,
+    /// a transaction with a bad signature
+    InvalidTransaction,
+    /// blocks, transactions or headers we did not ask for
+    Unsolicited,
+}
+
+impl Misbehaviour {
+    fn penalty(&self) -> i64 {
+        match self {
+            Misbehaviour::Undecodable => 50,
+            Misbehaviour::InvalidBlock => 50,
+            Misbehaviour::InvalidTransaction => 20,
+            Misbehaviour::Unsolicited => 10,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Ban {
+    /// port 0 when the ban covers a whole public IP
+    pub addr: SocketAddr,
+    /// unix time the ban ends
+    pub until: u64,
+}
+
+/// Misbehaviour score and ban of every peer, and the requests we expect each connection to
+/// answer. Peers are told apart as `peer_key` does.
+pub struct PeerScores {
+    scores: HashMap<SocketAddr, i64>,
+    // blocks and transactions asked from each peer, how many times and when last
+    requested: HashMap<SocketAddr, HashMap<H256, (usize, Instant)>>,
+    // peers asked for headers
+    headers_requested: HashMap<SocketAddr, Instant>,
+    bans: HashMap<SocketAddr, u64>,
+    ban_time: u64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn is_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || (ip.segments()[0] & 0xfe00) == 0xfc00,
+    }
+}
+
+/// Key of the peer listening at `addr` in the scores and bans. A public IP is one peer whatever
+/// ports it uses; on loopback and private networks, where test networks run every node on the
+/// same IP, each listen address is a peer of its own.
+pub fn peer_key(addr: SocketAddr) -> SocketAddr {
+    if is_local(&addr.ip()) {
+        addr
+    } else {
+        SocketAddr::new(addr.ip(), 0)
+    }
+}
+
+impl PeerScores {
+    /// Peers are banned for `ban_time` seconds
+    pub fn new(ban_time: u64) -> Self {
+        PeerScores {
+            scores: HashMap::new(),
+            requested: HashMap::new(),
+            headers_requested: HashMap::new(),
+            bans: HashMap::new(),
+            ban_time,
+        }
+    }
+
+    /// Lower the score of the peer listening at `addr`, returns true once it has to be banned
+    pub fn punish(&mut self, addr: SocketAddr, misbehaviour: Misbehaviour) -> bool {
+        let score = self.scores.entry(peer_key(addr)).or_insert(INITIAL_SCORE);
+        let was_banned = *score <= 0;
+        *score -= misbehaviour.penalty();
+        *score <= 0 && !was_banned
+    }
+
+    pub fn score(&self, addr: &SocketAddr) -> i64 {
+        self.scores.get(&peer_key(*addr)).cloned().unwrap_or(INITIAL_SCORE)
+    }
+
+    /// Remember that blocks or transactions were asked from `addr`
+    pub fn requested(&mut self, addr: SocketAddr, hashes: &[H256]) {
+        let now = Instant::now();
+        let requested = self.requested.entry(addr).or_insert_with(HashMap::new);
+        requested.retain(|_, (_, t)| now.duration_since(*t) < REQUEST_TIMEOUT);
+        for hash in hashes {
+            let entry = requested.entry(*hash).or_insert((0, now));
+            *entry = (entry.0 + 1, now);
+        }
+    }
+
+    /// Whether `hash` was asked from `addr` and not answered yet
+    pub fn answered(&mut self, addr: SocketAddr, hash: &H256) -> bool {
+        let requested = match self.requested.get_mut(&addr) {
+            Some(requested) => requested,
+            None => return false,
+        };
+        match requested.get_mut(hash) {
+            Some((count, t)) if t.elapsed() < REQUEST_TIMEOUT => {
+                *count -= 1;
+                if *count == 0 {
+                    requested.remove(hash);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn requested_headers(&mut self, addr: SocketAddr) {
+        self.headers_requested.insert(addr, Instant::now());
+    }
+
+    pub fn answered_headers(&mut self, addr: SocketAddr) -> bool {
+        match self.headers_requested.remove(&addr) {
+            Some(t) => t.elapsed() < REQUEST_TIMEOUT,
+            None => false,
+        }
+    }
+
+    /// Ban the peer listening at `addr` and forget its requests; its score starts over once the
+    /// ban ends
+    pub fn ban(&mut self, addr: SocketAddr) {
+        self.scores.remove(&peer_key(addr));
+        self.requested.remove(&addr);
+        self.headers_requested.remove(&addr);
+        self.bans.insert(peer_key(addr), now_secs() + self.ban_time);
+    }
+
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        match self.bans.get(&peer_key(*addr)) {
+            Some(until) => *until > now_secs(),
+            None => false,
+        }
+    }
+
+    /// Bans that have not ended yet
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = now_secs();
+        self.bans.iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(addr, until)| Ban { addr: *addr, until: *until })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::generate_random_hash;
+
+    #[test]
+    fn punish_and_ban() {
+        let addr: SocketAddr = "203.0.113.1:50000".parse().unwrap();
+        let reconnected: SocketAddr = "203.0.113.1:50001".parse().unwrap();
+        let other: SocketAddr = "203.0.113.2:50000".parse().unwrap();
+        let mut scores = PeerScores::new(60);
+        assert!(!scores.punish(addr, Misbehaviour::Unsolicited));
+        assert_eq!(scores.score(&addr), 90);
+        // the score of a public IP follows it across ports
+        assert!(!scores.punish(reconnected, Misbehaviour::InvalidBlock));
+        assert_eq!(scores.score(&addr), 40);
+        // only the crossing of the threshold bans
+        assert!(scores.punish(addr, Misbehaviour::Undecodable));
+        assert!(!scores.punish(addr, Misbehaviour::Undecodable));
+        scores.ban(addr);
+        assert!(scores.is_banned(&reconnected));
+        assert!(!scores.is_banned(&other));
+        assert_eq!(scores.bans().len(), 1);
+    }
+
+    #[test]
+    fn local_peers() {
+        // every node of a local test network runs on the same IP
+        let node1: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let node2: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let mut scores = PeerScores::new(60);
+        assert!(!scores.punish(node1, Misbehaviour::InvalidBlock));
+        assert_eq!(scores.score(&node2), INITIAL_SCORE);
+        scores.ban(node1);
+        assert!(scores.is_banned(&node1));
+        assert!(!scores.is_banned(&node2));
+        // the server disconnects the connections with the key of the banned peer
+        assert_ne!(peer_key(node1), peer_key(node2));
+        assert_eq!(peer_key("10.0.0.1:6001".parse().unwrap()), "10.0.0.1:6001".parse().unwrap());
+        assert_eq!(peer_key("8.8.8.8:6001".parse().unwrap()), peer_key("8.8.8.8:50000".parse().unwrap()));
+    }
+
+    #[test]
+    fn unsolicited() {
+        let addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:50001".parse().unwrap();
+        let hash = generate_random_hash();
+        let mut scores = PeerScores::new(60);
+        scores.requested(addr, &[hash]);
+        scores.requested(addr, &[hash]);
+        assert!(!scores.answered(other, &hash));
+        // asked twice, answered twice
+        assert!(scores.answered(addr, &hash));
+        assert!(scores.answered(addr, &hash));
+        assert!(!scores.answered(addr, &hash));
+        assert!(!scores.answered_headers(addr));
+        scores.requested_headers(addr);
+        assert!(scores.answered_headers(addr));
+    }
+}
@@ -1,20 +1,40 @@
+use super::addrbook::AddrBook;
+use super::emulation::{Emulator, NetemConfig};
 use super::message;
 use super::peer::{self, ReadResult, WriteResult};
+use super::scores::{self, PeerScores};
+use crate::blockchain::Blockchain;
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
-use std::sync::mpsc;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time;
 
 const MAX_INCOMING_CLIENT: usize = 256;
 const MAX_EVENT: usize = 1024;
+/// How often the outgoing connections are topped up
+const DISCOVERY_INTERVAL: time::Duration = time::Duration::from_secs(5);
+/// Longest an outgoing TCP connect may take
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+/// Longest a new connection may take to complete the Version/Verack handshake
+const HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    mut version: message::Version,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    addr_book: &Arc<Mutex<AddrBook>>,
+    scores: &Arc<Mutex<PeerScores>>,
+    outbound: usize,
+    netem: Option<NetemConfig>,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = channel::channel();
+    version.listen_port = addr.port();
     let handle = Handle {
         control_chan: control_signal_sender,
     };
@@ -25,6 +45,12 @@ pub fn new(
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
+        version,
+        blockchain: Arc::clone(blockchain),
+        addr_book: Arc::clone(addr_book),
+        scores: Arc::clone(scores),
+        outbound,
+        emulator: netem.map(Emulator::new),
         _handle: handle.clone(),
     };
     Ok((ctx, handle))
@@ -37,12 +63,38 @@ pub struct Context {
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
     new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    // our handshake, the best height is filled in for every connection
+    version: message::Version,
+    blockchain: Arc<Mutex<Blockchain>>,
+    addr_book: Arc<Mutex<AddrBook>>,
+    scores: Arc<Mutex<PeerScores>>,
+    // outgoing connections to keep open
+    outbound: usize,
+    // network emulation of `--netem`
+    emulator: Option<Emulator>,
     _handle: Handle,
 }
 
+#[derive(Serialize, Clone, Debug)]
+pub struct PeerInfo {
+    pub addr: std::net::SocketAddr,
+    pub listen_addr: Option<std::net::SocketAddr>,
+    pub outgoing: bool,
+    pub verified: bool,
+    pub services: Option<message::Services>,
+    pub best_height: Option<u128>,
+}
+
 impl Context {
     /// Start a new server context.
     pub fn start(mut self) -> std::io::Result<()> {
+        let handle = self._handle.clone();
+        let addr_book = Arc::clone(&self.addr_book);
+        let scores = Arc::clone(&self.scores);
+        let (addr, outbound) = (self.addr, self.outbound);
+        thread::spawn(move || {
+            maintain_outbound(handle, addr_book, scores, addr, outbound);
+        });
         thread::spawn(move || {
             self.listen().unwrap_or_else(|e| {
                 error!("P2P server error: {}", e);
@@ -79,7 +131,7 @@ impl Context {
             mio::Ready::readable(),
             mio::PollOpt::edge(),
         )?;
-        let (ctx, handle) = peer::new(stream, direction)?;
+        let (ctx, handle) = peer::new(stream, direction, self.emulator.as_ref().map(|emulator| emulator.link()))?;
 
         // register the writer queue
         self.poll.register(
@@ -89,6 +141,11 @@ impl Context {
             mio::PollOpt::edge() | mio::PollOpt::oneshot(),
         )?;
 
+        // the handshake goes out before anything else
+        let mut version = self.version.clone();
+        version.best_height = self.blockchain.lock().unwrap().get_depth();
+        handle.write(message::Message::Version(version));
+
         // insert the context and return the handle
         vacant.insert(ctx);
         // record the key of this peer
@@ -97,11 +154,8 @@ impl Context {
         Ok(handle)
     }
 
-    /// Connect to a peer, and register this peer
-    fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
-        // we need to estabilsh a stdlib tcp stream, since we need it to block
-        debug!("Establishing connection to peer {}", addr);
-        let stream = std::net::TcpStream::connect(addr)?;
+    /// Register a peer we connected to
+    fn connect(&mut self, stream: std::net::TcpStream) -> std::io::Result<peer::Handle> {
         let mio_stream = net::TcpStream::from_stream(stream)?;
         self.register(mio_stream, peer::Direction::Outgoing)
     }
@@ -113,6 +167,10 @@ impl Context {
         addr: std::net::SocketAddr,
     ) -> std::io::Result<()> {
         debug!("New incoming connection from {}", addr);
+        if self.scores.lock().unwrap().is_banned(&addr) {
+            debug!("Refused incoming connection from banned {}", addr);
+            return Ok(());
+        }
         match self.register(stream, peer::Direction::Incoming) {
             Ok(_) => {
                 info!("Connected to incoming peer {}", addr);
@@ -128,19 +186,66 @@ impl Context {
         match req {
             ControlSignal::ConnectNewPeer(req) => {
                 trace!("Processing ConnectNewPeer command");
-                let handle = self.connect(&req.addr);
+                let handle = self.connect(req.stream);
                 req.result_chan.send(handle).unwrap();
             }
+            ControlSignal::ListPeers(result_chan) => {
+                trace!("Processing ListPeers command");
+                let peers = self.peer_list.iter().map(|peer_id| {
+                    let peer = &self.peers[*peer_id];
+                    PeerInfo {
+                        addr: peer.addr,
+                        listen_addr: peer.listen_addr,
+                        outgoing: match peer.direction {
+                            peer::Direction::Outgoing => true,
+                            peer::Direction::Incoming => false,
+                        },
+                        verified: peer.verified,
+                        services: peer.version.as_ref().map(|version| version.services),
+                        best_height: peer.version.as_ref().map(|version| version.best_height),
+                    }
+                }).collect();
+                result_chan.send(peers).unwrap();
+            }
+            ControlSignal::Ban(addr) => {
+                trace!("Processing Ban command");
+                self.scores.lock().unwrap().ban(addr);
+                // every connection of the banned peer goes
+                let key = scores::peer_key(addr);
+                let peers = &self.peers;
+                let (banned, kept): (Vec<usize>, Vec<usize>) = self.peer_list.iter()
+                    .partition(|&&x| scores::peer_key(peers[x].listen_addr.unwrap_or(peers[x].addr)) == key);
+                for peer_id in banned.iter() {
+                    self.peers.remove(*peer_id);
+                }
+                self.peer_list = kept;
+                info!("Disconnected {} connections of banned peer {}", banned.len(), addr);
+            }
             ControlSignal::BroadcastMessage(msg) => {
                 trace!("Processing BroadcastMessage command");
                 for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                    if self.peers[*peer_id].verified {
+                        self.peers[*peer_id].handle.write(msg.clone());
+                    }
                 }
             }
         }
         Ok(())
     }
 
+    /// Disconnect the peers that did not complete the handshake in time
+    fn close_stale_handshakes(&mut self) {
+        let peers = &self.peers;
+        let (stale, kept): (Vec<usize>, Vec<usize>) = self.peer_list.iter().partition(|&&x| {
+            !peers[x].verified && peers[x].connected_at.elapsed() > HANDSHAKE_TIMEOUT
+        });
+        for peer_id in stale {
+            warn!("Peer {} did not complete the handshake in time, disconnecting", self.peers[peer_id].addr);
+            self.peers.remove(peer_id);
+        }
+        self.peer_list = kept;
+    }
+
     fn register_write_interest(&mut self, peer_id: usize) -> std::io::Result<()> {
         trace!("Registering socket write interest for peer {}", peer_id);
         let peer = &mut self.peers[peer_id];
@@ -177,6 +282,52 @@ impl Context {
                 Ok(ReadResult::Message(m)) => {
                     trace!("Peer {} yield message", peer_id);
                     // we just received a full message
+                    if !peer.verified {
+                        // the peer sends its Version, then a Verack once it accepted ours
+                        let failure = match (bincode::deserialize(&m), &peer.version) {
+                            (Ok(message::Message::Version(version)), None) => match self.version.check(&version) {
+                                Ok(()) => {
+                                    // the peer listens on the IP it connects from
+                                    let listen_addr = std::net::SocketAddr::new(peer.addr.ip(), version.listen_port);
+                                    if self.scores.lock().unwrap().is_banned(&listen_addr) {
+                                        Some(format!("{} is banned", listen_addr))
+                                    } else {
+                                        debug!("Peer {} runs {} version {} at height {}", peer.addr, version.protocol, version.version, version.best_height);
+                                        peer.listen_addr = Some(listen_addr);
+                                        peer.handle.set_listen_addr(listen_addr);
+                                        if let Some(emulator) = &self.emulator {
+                                            peer.handle.set_link_profile(emulator.profile(&listen_addr));
+                                        }
+                                        peer.version = Some(version);
+                                        peer.handle.write(message::Message::Verack());
+                                        None
+                                    }
+                                }
+                                Err(reason) => Some(reason),
+                            },
+                            (Ok(message::Message::Verack()), Some(version)) => {
+                                peer.verified = true;
+                                self.addr_book.lock().unwrap().seen(peer.listen_addr.unwrap());
+                                peer.handle.write(message::Message::GetAddr());
+                                // let the worker know, it starts syncing from the new peer
+                                let m = bincode::serialize(&message::Message::Version(version.clone())).unwrap();
+                                self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
+                                None
+                            }
+                            (Ok(_), _) => Some("unexpected message before the handshake completed".to_string()),
+                            (Err(e), _) => Some(format!("undecodable handshake: {}", e)),
+                        };
+                        match failure {
+                            None => continue,
+                            Some(reason) => {
+                                warn!("Handshake with peer {} failed: {}, disconnecting", peer.addr, reason);
+                                self.peers.remove(peer_id);
+                                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+                                self.peer_list.swap_remove(index);
+                                break;
+                            }
+                        }
+                    }
                     self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
                     continue;
                 }
@@ -284,7 +435,8 @@ impl Context {
         let mut events = mio::Events::with_capacity(MAX_EVENT);
 
         loop {
-            self.poll.poll(&mut events, None)?;
+            // wake up regularly to close connections stuck in the handshake
+            self.poll.poll(&mut events, Some(HANDSHAKE_TIMEOUT / 2))?;
 
             for event in events.iter() {
                 match event.token() {
@@ -360,6 +512,7 @@ impl Context {
                     }
                 }
             }
+            self.close_stale_handshakes();
         }
     }
 }
@@ -370,10 +523,14 @@ pub struct Handle {
 }
 
 impl Handle {
+    /// Connect to a peer and register it. The connect blocks the caller for at most
+    /// `CONNECT_TIMEOUT`, never the event loop.
     pub fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<peer::Handle> {
+        debug!("Establishing connection to peer {}", addr);
+        let stream = std::net::TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
         let (sender, receiver) = cbchannel::unbounded();
         let request = ConnectRequest {
-            addr,
+            stream,
             result_chan: sender,
         };
         self.control_chan
@@ -382,6 +539,22 @@ impl Handle {
         receiver.recv().unwrap()
     }
 
+    /// Every connected peer
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::ListPeers(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+
+    /// Disconnect the peer connected from `addr` and ban it
+    pub fn ban(&self, addr: std::net::SocketAddr) {
+        self.control_chan
+            .send(ControlSignal::Ban(addr))
+            .unwrap();
+    }
+
     pub fn broadcast(&self, msg: message::Message) {
         self.control_chan
             .send(ControlSignal::BroadcastMessage(msg))
@@ -392,9 +565,57 @@ impl Handle {
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
     BroadcastMessage(message::Message),
+    ListPeers(cbchannel::Sender<Vec<PeerInfo>>),
+    Ban(std::net::SocketAddr),
 }
 
 struct ConnectRequest {
-    addr: std::net::SocketAddr,
+    stream: std::net::TcpStream,
     result_chan: cbchannel::Sender<std::io::Result<peer::Handle>>,
 }
+
+/// Whether `addr` is where this node listens at `own`
+fn is_own_addr(own: &std::net::SocketAddr, addr: &std::net::SocketAddr) -> bool {
+    addr == own
+        || (addr.port() == own.port() && (own.ip().is_unspecified() || (own.ip().is_loopback() && addr.ip().is_loopback())))
+}
+
+/// Keep `outbound` outgoing connections open, reconnecting to the address book when peers drop
+/// and asking the connected peers for more addresses while there are too few. Banned peers are
+/// skipped.
+fn maintain_outbound(handle: Handle, addr_book: Arc<Mutex<AddrBook>>, scores: Arc<Mutex<PeerScores>>, own: std::net::SocketAddr, outbound: usize) {
+    loop {
+        thread::sleep(DISCOVERY_INTERVAL);
+        let peers = handle.peers();
+        let num_outgoing = peers.iter().filter(|peer| peer.outgoing).count();
+        if num_outgoing >= outbound {
+            continue;
+        }
+        let connected: HashSet<std::net::SocketAddr> = peers.iter()
+            .map(|peer| peer.listen_addr.unwrap_or(peer.addr))
+            .collect();
+        let candidates: Vec<std::net::SocketAddr> = addr_book.lock().unwrap().candidates(&connected)
+            .into_iter()
+            .filter(|addr| !scores.lock().unwrap().is_banned(addr))
+            .collect();
+        let mut num_connected = num_outgoing;
+        for addr in candidates.into_iter().filter(|addr| !is_own_addr(&own, addr)) {
+            if num_connected >= outbound {
+                break;
+            }
+            match handle.connect(addr) {
+                Ok(_) => {
+                    info!("Connected to outgoing peer {}", addr);
+                    num_connected += 1;
+                }
+                Err(e) => {
+                    debug!("Error connecting to peer {}: {}", addr, e);
+                    addr_book.lock().unwrap().failed(addr);
+                }
+            }
+        }
+        if num_connected < outbound {
+            handle.broadcast(message::Message::GetAddr());
+        }
+    }
+}
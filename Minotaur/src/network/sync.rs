@@ -0,0 +1,247 @@
+use super::peer;
+use crate::block::{Block, Header};
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{Hashable, H256};
+use crate::resource::STAKE;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use vrf::openssl::ECVRF;
+
+/// Most headers sent in one `Headers` message
+pub const MAX_HEADERS: usize = 2000;
+/// Bodies asked for in one `GetBlocks` message
+const BATCH_SIZE: usize = 16;
+/// Most bodies requested from one peer at a time
+const MAX_IN_FLIGHT: usize = 64;
+/// A body not received after this long is asked from another peer
+const BODY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest a peer whose requests keep timing out is left without requests
+const MAX_BACKOFF: Duration = Duration::from_secs(320);
+
+/// Headers-first download of the chain. The PoS headers are downloaded and checked first, then
+/// the PoS bodies and the PoW blocks they reference are fetched from every peer that served
+/// headers, and handed to the worker parents first.
+pub struct HeaderSync {
+    // checked headers whose blocks are not in the blockchain yet, with their height
+    headers: HashMap<H256, (Header, u128)>,
+    // PoS blocks still to hand to the worker, parents first
+    chain: VecDeque<H256>,
+    // bodies we still need
+    pending: HashSet<H256>,
+    // pending bodies not requested yet
+    wanted: VecDeque<H256>,
+    // pending bodies requested, from whom and when
+    in_flight: HashMap<H256, (SocketAddr, Instant)>,
+    // bodies downloaded but not handed to the worker yet
+    bodies: HashMap<H256, Block>,
+    // peers that served headers
+    peers: HashMap<SocketAddr, peer::Handle>,
+    // peers whose requests timed out, how many times in a row and until when they are skipped
+    backoff: HashMap<SocketAddr, (u32, Instant)>,
+    best_height: u128,
+    num_downloaded: usize,
+}
+
+#[derive(Serialize)]
+pub struct SyncProgress {
+    pub syncing: bool,
+    pub height: u128,
+    pub best_header_height: u128,
+    pub headers_pending: usize,
+    pub bodies_pending: usize,
+    pub bodies_in_flight: usize,
+    pub bodies_downloaded: usize,
+    pub peers: usize,
+}
+
+impl HeaderSync {
+    pub fn new() -> Self {
+        HeaderSync {
+            headers: HashMap::new(),
+            chain: VecDeque::new(),
+            pending: HashSet::new(),
+            wanted: VecDeque::new(),
+            in_flight: HashMap::new(),
+            bodies: HashMap::new(),
+            peers: HashMap::new(),
+            backoff: HashMap::new(),
+            best_height: 0,
+            num_downloaded: 0,
+        }
+    }
+
+    /// Check and queue headers received from `peer`. Returns how many are new, or the hash of
+    /// the first header that does not extend a known block or carries an invalid VRF output
+    /// or signature.
+    pub fn add_headers(&mut self, blockchain: &Blockchain, vrf: &mut ECVRF, headers: Vec<Header>, peer: &peer::Handle) -> Result<usize, H256> {
+        let mut num_new = 0;
+        for header in headers {
+            let hash = header.hash();
+            if blockchain.contains_hash(&hash) || self.headers.contains_key(&hash) {
+                continue;
+            }
+            let (parent_ts, parent_height) = match self.headers.get(&header.parent) {
+                Some((parent, height)) => (parent.timestamp, *height),
+                None => match blockchain.find_one_header(&header.parent) {
+                    Some(parent) => (parent.timestamp, blockchain.find_one_depth(&header.parent).unwrap()),
+                    None => return Err(hash),
+                },
+            };
+            let slot = blockchain.slot(header.timestamp);
+            if slot <= blockchain.slot(parent_ts)
+                || header.difficulty(STAKE) != blockchain.get_pos_difficulty()
+                || !header.verify_vrf(vrf, slot)
+                || !header.verify_signature(vrf) {
+                return Err(hash);
+            }
+            self.best_height = self.best_height.max(parent_height + 1);
+            self.headers.insert(hash, (header, parent_height + 1));
+            self.chain.push_back(hash);
+            if self.pending.insert(hash) {
+                self.wanted.push_back(hash);
+            }
+            num_new += 1;
+        }
+        self.peers.insert(peer.addr(), peer.clone());
+        Ok(num_new)
+    }
+
+    /// Keep a block if the download is waiting for it. The PoW blocks a PoS body references are
+    /// queued for download too.
+    pub fn received(&mut self, blockchain: &Blockchain, blk: &Block) -> bool {
+        let hash = blk.hash();
+        if !self.pending.remove(&hash) {
+            return false;
+        }
+        if let Some((addr, _)) = self.in_flight.remove(&hash) {
+            self.backoff.remove(&addr);
+        }
+        if blk.block_type == STAKE {
+            for txn_block in &blk.content.transaction_ref {
+                if !blockchain.contains_hash(txn_block) && !self.bodies.contains_key(txn_block) && self.pending.insert(*txn_block) {
+                    self.wanted.push_back(*txn_block);
+                }
+            }
+        }
+        self.bodies.insert(hash, blk.clone());
+        self.num_downloaded += 1;
+        true
+    }
+
+    /// Take the downloaded blocks the worker can insert, in chain order with every PoS block
+    /// right after the PoW blocks it references
+    pub fn take_ready(&mut self, blockchain: &Blockchain) -> Vec<Block> {
+        let mut ready = vec![];
+        let mut released: HashSet<H256> = HashSet::new();
+        while let Some(hash) = self.chain.front().cloned() {
+            if blockchain.contains_hash(&hash) {
+                // it came in through gossip meanwhile
+                self.chain.pop_front();
+                self.headers.remove(&hash);
+                self.bodies.remove(&hash);
+                self.pending.remove(&hash);
+                continue;
+            }
+            let txn_blocks = match self.bodies.get(&hash) {
+                Some(blk) => blk.content.transaction_ref.clone(),
+                None => break,
+            };
+            let complete = txn_blocks.iter().all(|txn_block| {
+                blockchain.contains_hash(txn_block) || released.contains(txn_block) || self.bodies.contains_key(txn_block)
+            });
+            if !complete {
+                break;
+            }
+            for txn_block in txn_blocks {
+                if let Some(blk) = self.bodies.remove(&txn_block) {
+                    released.insert(txn_block);
+                    ready.push(blk);
+                }
+            }
+            self.chain.pop_front();
+            self.headers.remove(&hash);
+            released.insert(hash);
+            ready.push(self.bodies.remove(&hash).unwrap());
+        }
+        ready
+    }
+
+    /// Spread the bodies not requested yet over the peers that served headers. Requests that
+    /// timed out go back to the queue and their peer is skipped for a while, twice as long after
+    /// every timeout in a row.
+    pub fn requests(&mut self) -> Vec<(peer::Handle, Vec<H256>)> {
+        let now = Instant::now();
+        let expired: Vec<(H256, SocketAddr)> = self.in_flight.iter()
+            .filter(|(_, (_, requested))| now.duration_since(*requested) > BODY_TIMEOUT)
+            .map(|(hash, (addr, _))| (*hash, *addr))
+            .collect();
+        let mut timed_out: HashSet<SocketAddr> = HashSet::new();
+        for (hash, addr) in expired {
+            self.in_flight.remove(&hash);
+            self.wanted.push_front(hash);
+            timed_out.insert(addr);
+        }
+        for addr in timed_out {
+            let strikes = self.backoff.get(&addr).map_or(0, |(strikes, _)| *strikes) + 1;
+            let delay = (BODY_TIMEOUT * (1 << strikes.min(5))).min(MAX_BACKOFF);
+            self.backoff.insert(addr, (strikes, now + delay));
+        }
+
+        let mut load: HashMap<SocketAddr, usize> = HashMap::new();
+        for (addr, _) in self.in_flight.values() {
+            *load.entry(*addr).or_insert(0) += 1;
+        }
+        let mut batches: HashMap<SocketAddr, Vec<H256>> = HashMap::new();
+        loop {
+            let mut assigned = false;
+            for addr in self.peers.keys() {
+                if self.backoff.get(addr).map_or(false, |(_, until)| *until > now) {
+                    continue;
+                }
+                let load = load.entry(*addr).or_insert(0);
+                if *load + BATCH_SIZE > MAX_IN_FLIGHT {
+                    continue;
+                }
+                let batch = batches.entry(*addr).or_insert_with(Vec::new);
+                let mut count = 0;
+                while count < BATCH_SIZE {
+                    match self.wanted.pop_front() {
+                        Some(hash) => {
+                            if self.pending.contains(&hash) && !self.in_flight.contains_key(&hash) {
+                                self.in_flight.insert(hash, (*addr, now));
+                                batch.push(hash);
+                                count += 1;
+                            }
+                        }
+                        None => break,
+                    }
+                }
+                *load += count;
+                assigned |= count > 0;
+            }
+            if !assigned {
+                break;
+            }
+        }
+        batches.into_iter()
+            .filter(|(_, batch)| !batch.is_empty())
+            .map(|(addr, batch)| (self.peers[&addr].clone(), batch))
+            .collect()
+    }
+
+    pub fn progress(&self, blockchain: &Blockchain) -> SyncProgress {
+        let height = blockchain.get_depth();
+        SyncProgress {
+            syncing: !self.chain.is_empty() || !self.pending.is_empty(),
+            height,
+            best_header_height: self.best_height.max(height),
+            headers_pending: self.chain.len(),
+            bodies_pending: self.pending.len(),
+            bodies_in_flight: self.in_flight.len(),
+            bodies_downloaded: self.num_downloaded,
+            peers: self.peers.len(),
+        }
+    }
+}
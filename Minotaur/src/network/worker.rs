@@ -1,16 +1,23 @@
 use crate::crypto::merkle::{MerkleTree, verify};
 use crate::{staker, miner};
+use crate::mempool::{self, Mempool};
 use crate::spam_recorder::SpamRecorder;
 use crate::state::{State,compute_key_hash,transaction_check};
-use crate::transaction::verify_signedtxn;
+use crate::transaction::{verify_signedtxns, VerifyPool};
 use crate::transaction::SignedTransaction;
 use std::collections::{HashMap, HashSet};
 use super::message::Message;
 use super::peer;
+use super::addrbook::{self, AddrBook};
+use super::scores::{Misbehaviour, PeerScores};
+use super::sync::{self as header_sync, HeaderSync};
 use crate::network::server::Handle as ServerHandle;
 use crossbeam::channel;
-use log::{debug, warn, error};
+use log::{debug, error, warn};
 use crate::block::Block;
+use crate::resource::{Resources, STAKE};
+use crate::validation::{self, Rejection};
+use crate::metrics::Metrics;
 use crate::blockchain::{Blockchain,FlyClientProposal,FlyClientProof,FlyClientQuery};
 use crate::crypto::hash::{Hashable, H160, H256};
 use std::collections::VecDeque;
@@ -23,7 +30,9 @@ use sha2::{Digest, Sha256};
 
 use log::info;
 
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::process;
 use std::thread;
 
 use vrf::openssl::{CipherSuite, ECVRF};
@@ -38,13 +47,22 @@ pub struct Context {
     buffer: Arc<Mutex<HashMap<H256,Block>>>,
     all_blocks: Arc<Mutex<HashMap<H256,Block>>>,
     delays: Arc<Mutex<Vec<u128>>>,
-    mempool: Arc<Mutex<Vec<SignedTransaction>>>,
+    mempool: Arc<Mutex<Mempool>>,
     all_txns: Arc<Mutex<HashMap<H256,SignedTransaction>>>,
     spam_recorder: Arc<Mutex<SpamRecorder>>,
     state: Arc<Mutex<State>>,
     tranpool: Arc<Mutex<Vec<H256>>>,  
     context_update_send: channel::Sender<staker::ContextUpdateSignal>,
     context_update_send_pow: channel::Sender<miner::ContextUpdateSignal>,
+    resources: Arc<Resources>,
+    max_drift: u128,
+    // invalid blocks received from each peer
+    invalid_blocks: Arc<Mutex<HashMap<SocketAddr,usize>>>,
+    sync: Arc<Mutex<HeaderSync>>,
+    addr_book: Arc<Mutex<AddrBook>>,
+    scores: Arc<Mutex<PeerScores>>,
+    metrics: Arc<Mutex<Metrics>>,
+    verify_pool: Arc<VerifyPool>,
 }
 
 pub fn new(
@@ -55,13 +73,20 @@ pub fn new(
     buffer: &Arc<Mutex<HashMap<H256,Block>>>,
     all_blocks: &Arc<Mutex<HashMap<H256,Block>>>,
     time: &Arc<Mutex<Vec<u128>>>,
-    mempool: &Arc<Mutex<Vec<SignedTransaction>>>,
+    mempool: &Arc<Mutex<Mempool>>,
     all_txns: &Arc<Mutex<HashMap<H256,SignedTransaction>>>,
     spam_recorder: &Arc<Mutex<SpamRecorder>>,
     state: &Arc<Mutex<State>>,
     tranpool: &Arc<Mutex<Vec<H256>>>,
     context_update_send: channel::Sender<staker::ContextUpdateSignal>,
     context_update_send_pow: channel::Sender<miner::ContextUpdateSignal>,
+    resources: &Arc<Resources>,
+    max_drift: u128,
+    sync: &Arc<Mutex<HeaderSync>>,
+    addr_book: &Arc<Mutex<AddrBook>>,
+    scores: &Arc<Mutex<PeerScores>>,
+    metrics: &Arc<Mutex<Metrics>>,
+    verify_pool: &Arc<VerifyPool>,
 ) -> Context {
     Context {
         msg_chan: msg_src,
@@ -78,6 +103,14 @@ pub fn new(
         tranpool: Arc::clone(tranpool),
         context_update_send,
         context_update_send_pow,
+        resources: Arc::clone(resources),
+        max_drift,
+        invalid_blocks: Arc::new(Mutex::new(HashMap::new())),
+        sync: Arc::clone(sync),
+        addr_book: Arc::clone(addr_book),
+        scores: Arc::clone(scores),
+        metrics: Arc::clone(metrics),
+        verify_pool: Arc::clone(verify_pool),
     }
 }
 
@@ -93,11 +126,51 @@ impl Context {
         }
     }
 
+    /// Count an invalid block or header from `peer`, returns how many it has sent
+    fn count_invalid(&self, peer: &peer::Handle) -> usize {
+        let count = {
+            let mut invalid_blocks = self.invalid_blocks.lock().unwrap();
+            let count = invalid_blocks.entry(peer.addr()).or_insert(0);
+            *count += 1;
+            *count
+        };
+        self.misbehave(peer, Misbehaviour::InvalidBlock);
+        count
+    }
+
+    /// Lower the score of `peer`, it is disconnected and banned once the score is too low
+    fn misbehave(&self, peer: &peer::Handle, misbehaviour: Misbehaviour) {
+        debug!("Peer {} misbehaved: {:?}", peer.addr(), misbehaviour);
+        if self.scores.lock().unwrap().punish(peer.listen_addr(), misbehaviour) {
+            warn!("Banning peer {}", peer.listen_addr());
+            self.server.ban(peer.listen_addr());
+        }
+    }
+
+    /// Ask `peer` for blocks, expecting them in a `Blocks` message
+    fn get_blocks(&self, peer: &peer::Handle, hashes: Vec<H256>) {
+        self.scores.lock().unwrap().requested(peer.addr(), &hashes);
+        peer.write(Message::GetBlocks(hashes));
+    }
+
+    /// Ask `peer` for the headers after `locator`, expecting a `Headers` message
+    fn get_headers(&self, peer: &peer::Handle, locator: Vec<H256>) {
+        self.scores.lock().unwrap().requested_headers(peer.addr());
+        peer.write(Message::GetHeaders(locator));
+    }
+
     fn worker_loop(&self) {
         loop {
             let msg = self.msg_chan.recv().unwrap();
             let (msg, peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Undecodable message from peer {}: {}", peer.addr(), e);
+                    self.misbehave(&peer, Misbehaviour::Undecodable);
+                    continue;
+                }
+            };
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
@@ -112,13 +185,13 @@ impl Context {
                     // let all_blocks = self.blockchain.lock().unwrap().all_blocks_in_longest_chain();
 
 				    for hash in hashes {
-						if !self.all_blocks.lock().unwrap().contains_key(&hash) {
+						if !self.blockchain.lock().unwrap().contains_hash(&hash) && !self.all_blocks.lock().unwrap().contains_key(&hash) {
 					    	hashes_request.push(hash);
 						}
                     }
 
 					if !hashes_request.is_empty() {
-                        peer.write(Message::GetBlocks(hashes_request));
+                        self.get_blocks(&peer, hashes_request);
                         //self.server.broadcast(Message::NewBlockHashes(tmp));
                     }
                 }
@@ -127,8 +200,9 @@ impl Context {
                     // let all_blocks = self.blockchain.lock().unwrap().all_blocks_in_longest_chain();
                     let mut blocks = vec![];
                     for hash in hashes {
-                        if self.all_blocks.lock().unwrap().contains_key(&hash) {
-                            let blk = self.all_blocks.lock().unwrap().get(&hash).expect("Message::GetBlocks error").clone();
+                        // stored blocks come from the block store, only blocks not inserted yet are kept in memory
+                        let stored = self.blockchain.lock().unwrap().find_one_block(&hash);
+                        if let Some(blk) = stored.or_else(|| self.all_blocks.lock().unwrap().get(&hash).cloned()) {
                             debug!("find block hash {:?}!", blk.hash());
                             blocks.push(blk);
                         }
@@ -140,27 +214,106 @@ impl Context {
 
                 
 
+                Message::GetAddr() => {
+                    let addrs = self.addr_book.lock().unwrap().sample(addrbook::MAX_ADDRS);
+                    if !addrs.is_empty() {
+                        peer.write(Message::Addr(addrs));
+                    }
+                }
+
+                Message::Addr(addrs) => {
+                    let addrs: Vec<SocketAddr> = addrs.into_iter().take(addrbook::MAX_ADDRS).collect();
+                    let num_new = self.addr_book.lock().unwrap().add(Some(peer.addr().ip()), &addrs);
+                    debug!("{} new peer addresses from peer {}", num_new, peer.addr());
+                }
+
+                Message::Version(version) => {
+                    // a new peer, download the headers we miss from it
+                    let locator = {
+                        let blockchain = self.blockchain.lock().unwrap();
+                        if version.best_height <= blockchain.get_depth() {
+                            continue;
+                        }
+                        blockchain.locator()
+                    };
+                    self.get_headers(&peer, locator);
+                }
+
+                Message::GetHeaders(locator) => {
+                    let headers = self.blockchain.lock().unwrap().headers_after(&locator, header_sync::MAX_HEADERS);
+                    if !headers.is_empty() {
+                        peer.write(Message::Headers(headers));
+                    }
+                }
+
+                Message::Headers(headers) => {
+                    if !self.scores.lock().unwrap().answered_headers(peer.addr()) {
+                        self.misbehave(&peer, Misbehaviour::Unsolicited);
+                        continue;
+                    }
+                    let mut vrf = ECVRF::from_suite(CipherSuite::SECP256K1_SHA256_TAI).unwrap();
+                    let last = headers.last().map(|header| header.hash());
+                    let more = headers.len() >= header_sync::MAX_HEADERS;
+                    let (result, requests) = {
+                        let blockchain = self.blockchain.lock().unwrap();
+                        let mut sync = self.sync.lock().unwrap();
+                        let result = sync.add_headers(&blockchain, &mut vrf, headers, &peer);
+                        (result, sync.requests())
+                    };
+                    match result {
+                        Ok(num_new) => {
+                            debug!("{} new headers from peer {}", num_new, peer.addr());
+                            if more {
+                                self.get_headers(&peer, vec![last.unwrap()]);
+                            }
+                        }
+                        Err(hash) => {
+                            let count = self.count_invalid(&peer);
+                            warn!("Invalid header {} from peer {}, {} invalid blocks from this peer", hash, peer.addr(), count);
+                        }
+                    }
+                    for (sync_peer, hashes) in requests {
+                        self.get_blocks(&sync_peer, hashes);
+                    }
+                }
+
                 Message::Blocks(blks) => {
                     let mut queue: VecDeque<Block> = VecDeque::new();
                     let mut hashes_send = vec![];
 
                     let mut vrf = ECVRF::from_suite(CipherSuite::SECP256K1_SHA256_TAI).unwrap();
 
+                    let mut unsolicited = false;
                     for blk in blks {
-                        //verify txns inside blks
-                        // let mut flag = false;
-                        // for txn in &blk.content.data {
-                        //     if !verify_signedtxn(&txn) {
-                        //         flag = true;
-                        //         break;
-                        //     }
-                        // }
-                        // if flag {
-                        //     break;
-                        // }
+                        if !self.scores.lock().unwrap().answered(peer.addr(), &blk.hash()) {
+                            unsolicited = true;
+                            continue;
+                        }
+                        // PoS blocks must carry the body their staker signed, the transactions of
+                        // PoW blocks must match their header
+                        let valid = if blk.block_type == STAKE {
+                            blk.validate_signature(&mut vrf)
+                        } else {
+                            blk.validate_txns(&self.verify_pool)
+                        };
+                        if let Err(e) = valid {
+                            let count = self.count_invalid(&peer);
+                            warn!("Invalid block {} from peer {}: {:?}, {} invalid blocks from this peer", blk.hash(), peer.addr(), e, count);
+                            continue;
+                        }
+                        self.metrics.lock().unwrap().received(&blk);
                         let copy = blk.clone();
                         self.all_blocks.lock().unwrap().insert(copy.hash(), copy);
 
+                        // blocks of the headers-first download are inserted in chain order below
+                        let synced = {
+                            let blockchain = self.blockchain.lock().unwrap();
+                            self.sync.lock().unwrap().received(&blockchain, &blk)
+                        };
+                        if synced {
+                            continue;
+                        }
+
                         // let serialized: Vec<u8> = bincode::serialize(&blk).unwrap();
                         // info!("block size {}", serialized.len());
 
@@ -172,16 +325,26 @@ impl Context {
                     	hashes_send.push(blk.hash());
                         queue.push_back(blk);
                     }
+                    let (ready, requests) = {
+                        let blockchain = self.blockchain.lock().unwrap();
+                        let mut sync = self.sync.lock().unwrap();
+                        (sync.take_ready(&blockchain), sync.requests())
+                    };
+                    queue.extend(ready);
+                    for (sync_peer, hashes) in requests {
+                        self.get_blocks(&sync_peer, hashes);
+                    }
                     self.server.broadcast(Message::NewBlockHashes(hashes_send));
                     let mut hashes_request = vec![];
                     while !queue.is_empty() {
                         let blk = queue.pop_front().unwrap();
                         let parent = blk.header.parent;
                         let blk_type = blk.block_type;
-                        if blk_type {
-                            let ts_slice = blk.header.timestamp.to_be_bytes();
+                        if blk_type == STAKE {
+                            let slot = self.blockchain.lock().unwrap().slot(blk.header.timestamp);
+                            let slot_slice = slot.to_be_bytes();
                             let rand_slice = blk.header.rand.to_be_bytes();
-                            let message = [rand_slice,ts_slice].concat();
+                            let message = [rand_slice,slot_slice].concat();
                             let vrf_pk: &[u8] = &blk.header.vrf_pub_key;
                             let vrf_beta = vrf.verify(&blk.header.vrf_pub_key, &blk.header.vrf_proof, &message);
                             let mut unknown_hashes: Vec<H256> = Vec::new(); 
@@ -202,15 +365,49 @@ impl Context {
                                 Ok(vrf_beta) => {
                                     let vrf_hash_bytes: &[u8] = &blk.header.vrf_hash;
                                     let vrf_hash_sha256: H256 = ring::digest::digest(&ring::digest::SHA256, vrf_hash_bytes).into();
-                                    if vrf_hash_sha256 <= blk.header.pos_difficulty && blk.header.pos_difficulty == self.blockchain.lock().unwrap().get_pos_difficulty() 
+                                    if vrf_hash_sha256 <= blk.header.difficulty(STAKE) && blk.header.difficulty(STAKE) == self.blockchain.lock().unwrap().get_pos_difficulty() 
                                     && blk.header.vrf_hash == vrf_beta  {
                                         //if self.blockchain.lock().unwrap().contains_hash(&parent) && self.state.lock().unwrap().check_block(&parent) { //blockchain has the parent
                                             //let mut current_state = self.state.lock().unwrap().one_block_state(&parent).clone();
                                         if unknown_hashes.is_empty() {
+                                            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros();
+                                            let checked = {
+                                                let blockchain = self.blockchain.lock().unwrap();
+                                                let mut state = self.state.lock().unwrap();
+                                                validation::check_pos_header(&blockchain, &mut state, &self.resources, &blk, &vrf_hash_sha256, now, self.max_drift)
+                                            };
+                                            match checked {
+                                                Err(Rejection::Invalid(reason)) => {
+                                                    warn!("PoS block {} {}", blk.hash(), reason);
+                                                    self.misbehave(&peer, Misbehaviour::InvalidBlock);
+                                                    continue;
+                                                }
+                                                Err(Rejection::Dropped(reason)) => {
+                                                    warn!("PoS block {} {}, dropping it", blk.hash(), reason);
+                                                    continue;
+                                                }
+                                                Ok(()) => {}
+                                            }
+                                            let (equivocation, excluded) = {
+                                                let mut blockchain = self.blockchain.lock().unwrap();
+                                                (blockchain.record_slot(&blk), blockchain.is_equivocator(&blk.header.vrf_pub_key))
+                                            };
+                                            if let Some(other) = equivocation {
+                                                // honest peers relay both blocks too, only the producer is excluded
+                                                warn!("Equivocation: producer {} signed PoS blocks {} and {} in slot {}", hex::encode(&blk.header.vrf_pub_key), other, blk.hash(), slot);
+                                                continue;
+                                            }
+                                            if excluded {
+                                                debug!("Dropping PoS block {} of equivocating producer {}", blk.hash(), hex::encode(&blk.header.vrf_pub_key));
+                                                continue;
+                                            }
                                             //let txn_blocks = blk.content.transaction_ref.clone();
                                             let mut last_longest_chain: Vec<H256> = self.blockchain.lock().unwrap().all_blocks_in_longest_chain();
-                                            if self.blockchain.lock().unwrap().insert_pos(&blk,false) {
-                                                //self.state.lock().unwrap().update_block(&blk);
+                                            let inserted = self.blockchain.lock().unwrap().insert_pos(&blk,false).unwrap_or_else(|e| {
+                                                error!("Failed to store PoS block {}: {}, shutting down", blk.hash(), e);
+                                                process::exit(1);
+                                            });
+                                            if inserted {
                                                 // longest chain changes
                                                 // update the longest chain
 
@@ -229,8 +426,12 @@ impl Context {
                                                     let block = self.blockchain.lock().unwrap().find_one_block(&blk_hash).unwrap();
                                                     blocks.push(block);
                                                 }
-                                                // self.state.lock().unwrap().update_blocks(&blocks);
+                                                for block in &blocks {
+                                                    let pow_blocks = self.blockchain.lock().unwrap().get_referenced_blocks(block);
+                                                    self.state.lock().unwrap().update_block(block, &pow_blocks);
+                                                }
                                                 
+                                                mempool::update_tip(&self.mempool, &self.spam_recorder, &self.blockchain, &self.state, &last_longest_chain);
 
                                                 // add txn_blocks back to the tranpool
                                                 for blk_hash in last_longest_chain {
@@ -304,24 +505,50 @@ impl Context {
                                         }
                                         info!("external valid pos block received");
                                     } else {
-                                        error!("bad pos block");
+                                        warn!("PoS block {} from peer {} fails its stake target", blk.hash(), peer.addr());
+                                        self.misbehave(&peer, Misbehaviour::InvalidBlock);
                                     }
                                 }
                                 Err(e) => {
-                                    println!("VRF proof is not valid: {}", e);
+                                    warn!("VRF proof of PoS block {} from peer {} is not valid: {}", blk.hash(), peer.addr(), e);
+                                    self.misbehave(&peer, Misbehaviour::InvalidBlock);
                                 }
                             }
                         } else {
-                            if blk.hash() <= blk.header.pow_difficulty {//&& blk.header.pow_difficulty == self.blockchain.lock().unwrap().get_pow_difficulty(blk.header.timestamp) {
+                            let resource = match self.resources.get(blk_type) {
+                                Some(resource) => resource,
+                                None => {
+                                    warn!("Block {} proves unknown resource {}", blk.hash(), blk_type);
+                                    continue;
+                                }
+                            };
+                            if resource.verify(&blk) {
                                 if self.blockchain.lock().unwrap().contains_hash(&parent) {
-                                    self.blockchain.lock().unwrap().insert_pow(&blk);
+                                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros();
+                                    let checked = validation::check_pow_header(&mut self.blockchain.lock().unwrap(), resource, &blk, now, self.max_drift);
+                                    match checked {
+                                        Err(Rejection::Invalid(reason)) => {
+                                            warn!("{} block {} {}", resource.name(), blk.hash(), reason);
+                                            self.misbehave(&peer, Misbehaviour::InvalidBlock);
+                                            continue;
+                                        }
+                                        Err(Rejection::Dropped(reason)) => {
+                                            warn!("{} block {} {}, dropping it", resource.name(), blk.hash(), reason);
+                                            continue;
+                                        }
+                                        Ok(()) => {}
+                                    }
+                                    if let Err(e) = self.blockchain.lock().unwrap().insert_pow(&blk) {
+                                        error!("Failed to store PoW block {}: {}, shutting down", blk.hash(), e);
+                                        process::exit(1);
+                                    }
                                     let txns = blk.content.data.clone();
                                     let hash = blk.hash().clone();
                                     {
                                         let mut spam_recorder = self.spam_recorder.lock().unwrap();
                                         txns.iter().for_each(|txn|{spam_recorder.test_and_set(txn);});
                                     }
-                                    self.mempool.lock().unwrap().retain(|txn| !txns.contains(txn));
+                                    self.mempool.lock().unwrap().remove_included(&txns);
                                     if !self.tranpool.lock().unwrap().contains(&hash){
                                         self.tranpool.lock().unwrap().push(hash);
                                     }
@@ -353,7 +580,8 @@ impl Context {
                                 // tell the miner to update the context
                                 self.context_update_send_pow.send(miner::ContextUpdateSignal::NewBlock).unwrap();
                             } else {
-                                        error!("BAD BLOCK");
+                                warn!("{} block {} from peer {} does not meet its target", resource.name(), blk.hash(), peer.addr());
+                                self.misbehave(&peer, Misbehaviour::InvalidBlock);
                             }
 
                         }
@@ -362,7 +590,10 @@ impl Context {
                         
 
                     if !hashes_request.is_empty() {
-                        peer.write(Message::GetBlocks(hashes_request));
+                        self.get_blocks(&peer, hashes_request);
+                    }
+                    if unsolicited {
+                        self.misbehave(&peer, Misbehaviour::Unsolicited);
                     }
 
                     let mut total_delay = 0;
@@ -378,6 +609,7 @@ impl Context {
                     info!("Longest Blockchain Length: {}", self.blockchain.lock().unwrap().get_depth());
                     info!("Total Number of PoW Blocks in Blockchain: {}", self.blockchain.lock().unwrap().get_num_pow());
                     info!("Total Number of PoS Blocks in Blockchain: {}", self.blockchain.lock().unwrap().get_num_pos());
+                    info!("Total Number of Rejected PoW Blocks: {}", self.blockchain.lock().unwrap().get_num_rejected_pow());
                     // info!("Total Number of Blocks: {}", self.all_blocks.lock().unwrap().len());
 
                     let last_block = self.blockchain.lock().unwrap().tip();                    
@@ -406,6 +638,7 @@ impl Context {
                     }
 
 					if !hashes_request.is_empty() {
+                        self.scores.lock().unwrap().requested(peer.addr(), &hashes_request);
                         peer.write(Message::GetTransactions(hashes_request));
                     }
                 }
@@ -428,12 +661,19 @@ impl Context {
 
                 Message::Transactions(txns) => {
                     let mut hashes_send = vec![];
-                    for txn in txns {
+                    let txns: Vec<SignedTransaction> = txns.into_iter().filter(|txn| self.scores.lock().unwrap().answered(peer.addr(), &txn.hash())).collect();
+                    let valid = verify_signedtxns(&self.verify_pool, &txns);
+                    for (txn, valid) in txns.into_iter().zip(valid) {
+                        if !valid {
+                            warn!("Transaction {} from peer {} has a bad signature", txn.hash(), peer.addr());
+                            self.misbehave(&peer, Misbehaviour::InvalidTransaction);
+                            continue;
+                        }
                         let copy = txn.clone();
                         self.all_txns.lock().unwrap().insert(txn.hash(), txn);
                         hashes_send.push(copy.clone().hash());
-                        if!self.mempool.lock().unwrap().contains(&copy) {
-                            self.mempool.lock().unwrap().push(copy.clone());
+                        if let Err(e) = mempool::insert(&self.mempool, &self.blockchain, &self.state, copy.clone()) {
+                            debug!("Transaction {} not added to the mempool: {:?}", copy.hash(), e);
                         }
 
                     }
@@ -0,0 +1,129 @@
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H256, Hashable};
+use crate::genesis::GenesisSpec;
+use crate::state::State;
+
+/// Index of a resource in `Header::difficulties`, in the genesis spec and in `Block::block_type`
+pub type ResourceId = usize;
+
+/// PoS blocks, they form the chain and reference the blocks of every other resource
+pub const STAKE: ResourceId = 0;
+/// PoW blocks
+pub const WORK: ResourceId = 1;
+
+/// A resource nodes can prove to get a share of the PoS leader election. Every resource except
+/// stake produces its own blocks, which PoS blocks reference through `transaction_ref`.
+pub trait Resource: Send + Sync {
+    fn id(&self) -> ResourceId;
+
+    /// Name used in the genesis spec
+    fn name(&self) -> &'static str;
+
+    /// Target a block of this resource extending `parent` at `ts` has to meet
+    fn difficulty(&self, blockchain: &Blockchain, parent: &H256, ts: u128) -> H256;
+
+    /// Whether the block proves this resource for the target in its header
+    fn verify(&self, block: &Block) -> bool;
+
+    /// Share of this resource `producer` held in the epoch ending at `boundary`, `None` if unknown
+    fn sample(&self, blockchain: &Blockchain, state: &State, boundary: &H256, producer: &[u8]) -> Option<f64>;
+}
+
+pub struct Stake;
+
+impl Resource for Stake {
+    fn id(&self) -> ResourceId {
+        STAKE
+    }
+
+    fn name(&self) -> &'static str {
+        "stake"
+    }
+
+    fn difficulty(&self, blockchain: &Blockchain, _parent: &H256, _ts: u128) -> H256 {
+        blockchain.get_pos_difficulty()
+    }
+
+    fn verify(&self, block: &Block) -> bool {
+        let vrf_hash_bytes: &[u8] = &block.header.vrf_hash;
+        let vrf_hash_sha256: H256 = ring::digest::digest(&ring::digest::SHA256, vrf_hash_bytes).into();
+        vrf_hash_sha256 <= block.header.difficulty(STAKE)
+    }
+
+    fn sample(&self, _blockchain: &Blockchain, state: &State, boundary: &H256, producer: &[u8]) -> Option<f64> {
+        state.stake_fraction(boundary, producer)
+    }
+}
+
+pub struct Work;
+
+impl Resource for Work {
+    fn id(&self) -> ResourceId {
+        WORK
+    }
+
+    fn name(&self) -> &'static str {
+        "work"
+    }
+
+    fn difficulty(&self, blockchain: &Blockchain, parent: &H256, ts: u128) -> H256 {
+        blockchain.get_pow_difficulty(ts, *parent)
+    }
+
+    fn verify(&self, block: &Block) -> bool {
+        block.hash() <= block.header.difficulty(WORK)
+    }
+
+    fn sample(&self, blockchain: &Blockchain, _state: &State, boundary: &H256, producer: &[u8]) -> Option<f64> {
+        if blockchain.find_one_depth(boundary)? == 0 {
+            // nobody has mined in a previous epoch yet
+            return Some(1f64);
+        }
+        let counts = blockchain.count_resource_blocks(boundary, WORK);
+        let all_count: usize = counts.values().sum();
+        Some(*counts.get(producer).unwrap_or(&0) as f64 / all_count.max(1) as f64)
+    }
+}
+
+/// Every resource known to this client, in `ResourceId` order
+pub fn builtin() -> Vec<Box<dyn Resource>> {
+    vec![Box::new(Stake), Box::new(Work)]
+}
+
+/// The resources of a network with their weights in the leader election
+pub struct Resources {
+    resources: Vec<Box<dyn Resource>>,
+    weights: Vec<f64>,
+}
+
+impl Resources {
+    pub fn new(spec: &GenesisSpec) -> Self {
+        Resources {
+            resources: builtin(),
+            weights: spec.weights(),
+        }
+    }
+
+    pub fn get(&self, id: ResourceId) -> Option<&dyn Resource> {
+        self.resources.get(id).map(|r| r.as_ref())
+    }
+
+    pub fn weight(&self, id: ResourceId) -> f64 {
+        self.weights.get(id).cloned().unwrap_or(0f64)
+    }
+
+    /// Weighted sum of the shares `producer` holds of every resource at the epoch boundary,
+    /// `None` if one of them is unknown. Resources with no weight are not sampled.
+    pub fn virtual_stake(&self, blockchain: &Blockchain, state: &State, boundary: &H256, producer: &[u8]) -> Option<f64> {
+        let mut virtual_stake = 0f64;
+        for resource in self.resources.iter() {
+            let weight = self.weight(resource.id());
+            if weight == 0f64 {
+                continue;
+            }
+            virtual_stake += weight * resource.sample(blockchain, state, boundary, producer)?;
+        }
+        Some(virtual_stake)
+    }
+}
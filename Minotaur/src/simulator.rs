@@ -0,0 +1,665 @@
+use crate::block::{Block, generate_pos_block, generate_pow_block};
+use crate::blockchain::{Blockchain, ForkChoice};
+use crate::crypto::hash::{H160, H256, Hashable, hash_multiply_by, hash_probability};
+use crate::genesis::{GenesisSpec, GenesisStake};
+use crate::network::emulation::{LinkProfile, NetemConfig};
+use crate::resource::{Resources, STAKE};
+use crate::state::State;
+use crate::transaction::SignedTransaction;
+use crate::validation::{self, Rejection};
+use log::{debug, info};
+use rand::distributions::{Distribution, Exp};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Serialize, Deserialize};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::net::SocketAddr;
+
+/// PoW blocks a PoS block references, once that many are waiting, like the staker
+const TXN_BLOCKS_PER_POS: usize = 32;
+/// Bytes of a request for a missing block
+const REQUEST_SIZE: usize = 64;
+/// Node `i` is `127.0.0.1:(BASE_PORT + i)` in the links of the network config
+const BASE_PORT: u16 = 6000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SimNodeConfig {
+    /// stake bonded to the node in the genesis spec
+    #[serde(default)]
+    pub stake: usize,
+    /// PoW hashes per second
+    #[serde(default)]
+    pub hash_rate: f64,
+}
+
+fn default_fork_choice() -> String {
+    "longest".to_string()
+}
+
+/// A simulation run by `--simulate`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SimConfig {
+    pub seed: u64,
+    /// virtual micro seconds to simulate
+    pub duration: u128,
+    pub nodes: Vec<SimNodeConfig>,
+    /// the genesis stakes are replaced by the ones of `nodes`
+    #[serde(default)]
+    pub genesis: GenesisSpec,
+    #[serde(default)]
+    pub network: NetemConfig,
+    #[serde(default = "default_fork_choice")]
+    pub fork_choice: String,
+}
+
+impl SimConfig {
+    /// Read a simulation config from a JSON file
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(filename).map_err(|e| format!("cannot read {}: {}", filename, e))?;
+        serde_json::from_str(&content).map_err(|e| format!("cannot parse {}: {}", filename, e))
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct NodeReport {
+    pub tip: String,
+    pub depth: u128,
+    pub num_pos: u128,
+    pub num_pow: u128,
+    pub pos_produced: usize,
+    pub pow_produced: usize,
+    /// share of the PoS blocks of the common prefix the node produced
+    pub chain_share: f64,
+    /// balance of the node's coinbase at the end of the common prefix, the block rewards it earned
+    pub rewards: usize,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SimReport {
+    pub seed: u64,
+    pub duration: u128,
+    pub events: u64,
+    pub messages: u64,
+    pub lost: u64,
+    /// height up to which the longest chains of all nodes agree
+    pub common_prefix: u128,
+    pub nodes: Vec<NodeReport>,
+}
+
+enum Action {
+    Stake { node: usize, slot: u128, gen: u64 },
+    Mine { node: usize, gen: u64 },
+    // the staking threshold or the PoW target changes with the epoch
+    Restake { node: usize, gen: u64 },
+    Remine { node: usize, gen: u64 },
+    Deliver { node: usize, from: usize, block: Block },
+}
+
+struct Event {
+    at: u128,
+    seq: u64,
+    action: Action,
+}
+
+impl PartialEq for Event {
+    fn eq(&self, other: &Self) -> bool {
+        self.at == other.at && self.seq == other.seq
+    }
+}
+
+impl Eq for Event {}
+
+impl PartialOrd for Event {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Event {
+    // reversed, the heap pops the earliest event first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.at.cmp(&self.at).then(other.seq.cmp(&self.seq))
+    }
+}
+
+// one direction of the link between two nodes, see `emulation::Link`
+struct SimLink {
+    profile: LinkProfile,
+    busy_until: u128,
+    last_delivery: u128,
+}
+
+struct Node {
+    blockchain: Blockchain,
+    state: State,
+    vrf_pub_key: Vec<u8>,
+    coinbase: H160,
+    hash_rate: f64,
+    // PoW blocks not referenced by the longest chain, oldest first
+    tranpool: Vec<H256>,
+    // blocks waiting for their parent or the blocks they reference, in arrival order
+    buffer: Vec<Block>,
+    requested: HashSet<H256>,
+    thresholds: HashMap<H256, H256>,
+    rands: HashMap<(H256, u128), u128>,
+    // bumped to cancel the scheduled staking or mining
+    stake_gen: u64,
+    mine_gen: u64,
+    pos_produced: usize,
+    pow_produced: usize,
+}
+
+/// Many nodes in one process on a virtual clock, driven by a seeded RNG. Nodes keep a real
+/// `Blockchain` and `State`, and every block goes through the worker's header checks
+/// (`validation`) on the virtual clock. The rest is simplified:
+/// - the winning slot and the time of the next PoW block are sampled with the probabilities the
+///   VRF thresholds and PoW targets give, so a run costs time per block rather than per slot or
+///   hash; a PoS block carries a value drawn under its producer's threshold in place of the
+///   SHA-256 of a VRF output, without proof or signature, and PoW hashes are not evaluated
+/// - blocks carry no transactions, so there is no mempool and no transaction validation
+/// - the chain switch of the worker is repeated in `switch_chain`
+/// Nodes are fully connected; a block announcement may be lost, missing blocks are fetched from
+/// the node that sent a child.
+pub struct Simulation {
+    seed: u64,
+    duration: u128,
+    epoch_time: u128,
+    slot_time: u128,
+    resources: Resources,
+    nodes: Vec<Node>,
+    // [from][to]
+    links: Vec<Vec<SimLink>>,
+    events: BinaryHeap<Event>,
+    now: u128,
+    seq: u64,
+    rng: StdRng,
+    num_events: u64,
+    num_messages: u64,
+    num_lost: u64,
+}
+
+impl Simulation {
+    pub fn new(config: SimConfig) -> Result<Self, String> {
+        if config.nodes.is_empty() {
+            return Err("no nodes to simulate".to_string());
+        }
+        if config.nodes.iter().any(|node| !(node.hash_rate >= 0.0)) {
+            return Err("hash rates must not be negative".to_string());
+        }
+        let fork_choice: ForkChoice = config.fork_choice.parse()?;
+        config.network.validate()?;
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let keys: Vec<Vec<u8>> = config.nodes.iter().map(|_| {
+            let mut key = vec![0u8; 32];
+            rng.fill(&mut key[..]);
+            key
+        }).collect();
+        let mut spec = config.genesis.clone();
+        spec.stakes = config.nodes.iter().zip(keys.iter())
+            .filter(|(node, _)| node.stake > 0)
+            .map(|(node, key)| GenesisStake { vrf_pub_key: hex::encode(key), stake: node.stake })
+            .collect();
+        spec.validate()?;
+
+        let nodes = config.nodes.iter().zip(keys.into_iter()).map(|(node, vrf_pub_key)| {
+            let blockchain = Blockchain::new(0, &spec, fork_choice);
+            let mut state = State::new();
+            state.rewards = spec.rewards.clone();
+            state.ico(blockchain.tip(), &spec.accounts(), &spec.stakes());
+            let mut coinbase = [0u8; 20];
+            coinbase.copy_from_slice(&vrf_pub_key[..20]);
+            Node {
+                blockchain,
+                state,
+                vrf_pub_key,
+                coinbase: coinbase.into(),
+                hash_rate: node.hash_rate,
+                tranpool: vec![],
+                buffer: vec![],
+                requested: HashSet::new(),
+                thresholds: HashMap::new(),
+                rands: HashMap::new(),
+                stake_gen: 0,
+                mine_gen: 0,
+                pos_produced: 0,
+                pow_produced: 0,
+            }
+        }).collect();
+        let num_nodes = config.nodes.len();
+        let links = (0..num_nodes).map(|_| (0..num_nodes).map(|to| {
+            let addr = SocketAddr::from(([127, 0, 0, 1], BASE_PORT + to as u16));
+            SimLink { profile: config.network.profile(&addr), busy_until: 0, last_delivery: 0 }
+        }).collect()).collect();
+
+        let mut simulation = Simulation {
+            seed: config.seed,
+            duration: config.duration,
+            epoch_time: spec.epoch_time,
+            slot_time: spec.slot_time,
+            resources: Resources::new(&spec),
+            nodes,
+            links,
+            events: BinaryHeap::new(),
+            now: 0,
+            seq: 0,
+            rng,
+            num_events: 0,
+            num_messages: 0,
+            num_lost: 0,
+        };
+        for node in 0..num_nodes {
+            simulation.schedule_staking(node, 1);
+            simulation.schedule_mining(node);
+        }
+        Ok(simulation)
+    }
+
+    /// Run until the end of the simulated duration
+    pub fn run(&mut self) -> SimReport {
+        while let Some(event) = self.events.pop() {
+            if event.at > self.duration {
+                break;
+            }
+            self.now = event.at;
+            self.num_events += 1;
+            match event.action {
+                Action::Stake { node, slot, gen } if gen == self.nodes[node].stake_gen => self.stake(node, slot),
+                Action::Mine { node, gen } if gen == self.nodes[node].mine_gen => self.mine(node),
+                Action::Restake { node, gen } if gen == self.nodes[node].stake_gen => {
+                    let slot = self.now / self.slot_time;
+                    self.schedule_staking(node, slot);
+                }
+                Action::Remine { node, gen } if gen == self.nodes[node].mine_gen => self.schedule_mining(node),
+                Action::Deliver { node, from, block } => self.receive(node, from, block),
+                _ => {}
+            }
+        }
+        self.now = self.duration;
+        self.report()
+    }
+
+    fn push(&mut self, at: u128, action: Action) {
+        self.seq += 1;
+        self.events.push(Event { at, seq: self.seq, action });
+    }
+
+    /// First slot of the epoch after the one `ts` is in
+    fn next_epoch_slot(&self, ts: u128) -> u128 {
+        let next_epoch = (ts / self.epoch_time + 1) * self.epoch_time;
+        (next_epoch + self.slot_time - 1) / self.slot_time
+    }
+
+    // `staker::pos_threshold`, cached per epoch boundary like the staker does
+    fn threshold(&mut self, node: usize, ts: u128) -> H256 {
+        let parent = self.nodes[node].blockchain.tip();
+        let boundary = self.nodes[node].blockchain.epoch_boundary(&parent, ts);
+        let n = &mut self.nodes[node];
+        if !n.thresholds.contains_key(&boundary) {
+            match self.resources.virtual_stake(&n.blockchain, &n.state, &boundary, &n.vrf_pub_key) {
+                Some(virtual_stake) => {
+                    let threshold = hash_multiply_by(&n.blockchain.get_pos_difficulty(), virtual_stake);
+                    n.thresholds.insert(boundary, threshold);
+                }
+                None => debug!("Node {} does not know the state of epoch boundary {}", node, boundary),
+            }
+        }
+        n.thresholds.get(&boundary).cloned().unwrap_or_default()
+    }
+
+    fn epoch_rand(&mut self, node: usize, parent: &H256, ts: u128) -> u128 {
+        let boundary = self.nodes[node].blockchain.epoch_boundary(parent, ts);
+        let n = &mut self.nodes[node];
+        let epoch = n.blockchain.epoch(ts);
+        let blockchain = &n.blockchain;
+        *n.rands.entry((boundary, epoch)).or_insert_with(|| blockchain.epoch_rand(parent, ts))
+    }
+
+    /// Draw the next slot from `first_slot` on in which `node` wins the leader election on its tip
+    fn schedule_staking(&mut self, node: usize, first_slot: u128) {
+        self.nodes[node].stake_gen += 1;
+        let gen = self.nodes[node].stake_gen;
+        let ts = first_slot * self.slot_time;
+        let next_epoch_slot = self.next_epoch_slot(ts);
+        let p = hash_probability(&self.threshold(node, ts));
+        // slots lost before the first win
+        let losses = if p >= 1.0 {
+            0f64
+        } else if p > 0.0 {
+            ((1.0 - self.rng.gen::<f64>()).ln() / (1.0 - p).ln()).floor()
+        } else {
+            std::f64::INFINITY
+        };
+        if losses < (next_epoch_slot - first_slot) as f64 {
+            let slot = first_slot + losses as u128;
+            self.push(slot * self.slot_time, Action::Stake { node, slot, gen });
+        } else {
+            self.push(next_epoch_slot * self.slot_time, Action::Restake { node, gen });
+        }
+    }
+
+    /// Draw when `node` finds its next PoW block on its tip
+    fn schedule_mining(&mut self, node: usize) {
+        self.nodes[node].mine_gen += 1;
+        let gen = self.nodes[node].mine_gen;
+        let n = &self.nodes[node];
+        let target = n.blockchain.get_pow_difficulty(self.now, n.blockchain.tip());
+        // blocks per micro second
+        let rate = n.hash_rate * hash_probability(&target) / 1e6;
+        let next_epoch = (self.now / self.epoch_time + 1) * self.epoch_time;
+        let wait = if rate > 0.0 {
+            Exp::new(rate).sample(&mut self.rng).ceil()
+        } else {
+            std::f64::INFINITY
+        };
+        if wait < (next_epoch - self.now) as f64 {
+            let at = self.now + (wait as u128).max(1);
+            self.push(at, Action::Mine { node, gen });
+        } else {
+            self.push(next_epoch, Action::Remine { node, gen });
+        }
+    }
+
+    fn stake(&mut self, node: usize, slot: u128) {
+        let ts = slot * self.slot_time;
+        let parent = self.nodes[node].blockchain.tip();
+        let rand = self.epoch_rand(node, &parent, ts);
+        // the slot is won, the output is drawn under the threshold
+        let threshold = self.threshold(node, ts);
+        let output: [u8; 32] = (&hash_multiply_by(&threshold, self.rng.gen::<f64>())).into();
+        let vrf_hash = output.to_vec();
+        let nonce = self.rng.gen();
+        let n = &mut self.nodes[node];
+        let transaction_ref = if n.tranpool.len() >= TXN_BLOCKS_PER_POS {
+            n.tranpool[..TXN_BLOCKS_PER_POS].to_vec()
+        } else {
+            vec![]
+        };
+        let difficulties = [n.blockchain.get_pos_difficulty(), n.blockchain.get_pow_difficulty(ts, parent)];
+        let mut blk = generate_pos_block(&vec![], &transaction_ref, &parent, nonce, &difficulties, ts, &vec![], &vrf_hash,
+            &n.vrf_pub_key, rand, false);
+        blk.header.coinbase = n.coinbase;
+        match self.accept(node, blk.clone()) {
+            Ok(tip_changed) => {
+                self.nodes[node].pos_produced += 1;
+                self.broadcast(node, &blk);
+                if !tip_changed {
+                    self.schedule_staking(node, slot + 1);
+                }
+            }
+            Err(rejection) => {
+                debug!("Node {} produced a rejected PoS block: {:?}", node, rejection);
+                self.schedule_staking(node, slot + 1);
+            }
+        }
+    }
+
+    fn mine(&mut self, node: usize) {
+        let parent = self.nodes[node].blockchain.tip();
+        let rand = self.epoch_rand(node, &parent, self.now);
+        let nonce = self.rng.gen();
+        let n = &mut self.nodes[node];
+        let difficulties = [n.blockchain.get_pos_difficulty(), n.blockchain.get_pow_difficulty(self.now, parent)];
+        // the merkle tree needs a transaction, an unsigned one stands for the mempool
+        let data = vec![SignedTransaction::default()];
+        let mut blk = generate_pow_block(&data, &vec![], &parent, nonce, &difficulties, self.now, &vec![], &vec![],
+            &n.vrf_pub_key, rand, false);
+        blk.header.coinbase = n.coinbase;
+        match self.accept(node, blk.clone()) {
+            Ok(_) => {
+                self.nodes[node].pow_produced += 1;
+                self.broadcast(node, &blk);
+            }
+            Err(rejection) => debug!("Node {} produced a rejected PoW block: {:?}", node, rejection),
+        }
+        self.schedule_mining(node);
+    }
+
+    /// Delivery time of a message sent over the link, None if it is lost
+    fn send(&mut self, from: usize, to: usize, size: usize, depart: u128, droppable: bool) -> Option<u128> {
+        let link = &mut self.links[from][to];
+        let latency = link.profile.sample_latency(&mut self.rng, droppable)?;
+        link.busy_until = link.busy_until.max(depart) + link.profile.transmission_time(size) as u128;
+        link.last_delivery = link.last_delivery.max(link.busy_until + latency as u128);
+        Some(link.last_delivery)
+    }
+
+    fn broadcast(&mut self, node: usize, blk: &Block) {
+        let size = bincode::serialized_size(blk).unwrap() as usize;
+        for to in 0..self.nodes.len() {
+            if to == node {
+                continue;
+            }
+            self.num_messages += 1;
+            match self.send(node, to, size, self.now, true) {
+                Some(at) => self.push(at, Action::Deliver { node: to, from: node, block: blk.clone() }),
+                None => self.num_lost += 1,
+            }
+        }
+    }
+
+    /// Fetch a block `node` misses from `from`. Fetches are retried until they succeed, so they
+    /// are never lost.
+    fn request(&mut self, node: usize, from: usize, hash: H256) {
+        let blk = match self.nodes[from].blockchain.find_one_block(&hash) {
+            Some(blk) => blk,
+            None => return,
+        };
+        self.nodes[node].requested.insert(hash);
+        let size = bincode::serialized_size(&blk).unwrap() as usize;
+        self.num_messages += 2;
+        let arrival = self.send(node, from, REQUEST_SIZE, self.now, false).unwrap();
+        let at = self.send(from, node, size, arrival, false).unwrap();
+        self.push(at, Action::Deliver { node, from, block: blk });
+    }
+
+    fn missing(&self, node: usize, blk: &Block) -> Vec<H256> {
+        let blockchain = &self.nodes[node].blockchain;
+        let mut missing = vec![];
+        if !blockchain.contains_hash(&blk.header.parent) {
+            missing.push(blk.header.parent);
+        }
+        if blk.block_type == STAKE {
+            for txn_block in blk.content.transaction_ref.iter() {
+                if !blockchain.contains_hash(txn_block) {
+                    missing.push(*txn_block);
+                }
+            }
+        }
+        missing
+    }
+
+    fn receive(&mut self, node: usize, from: usize, blk: Block) {
+        let hash = blk.hash();
+        self.nodes[node].requested.remove(&hash);
+        if self.nodes[node].blockchain.contains_hash(&hash) || self.nodes[node].buffer.iter().any(|b| b.hash() == hash) {
+            return;
+        }
+        let missing = self.missing(node, &blk);
+        if !missing.is_empty() {
+            self.nodes[node].buffer.push(blk);
+            for hash in missing {
+                let n = &self.nodes[node];
+                if !n.requested.contains(&hash) && !n.buffer.iter().any(|b| b.hash() == hash) {
+                    self.request(node, from, hash);
+                }
+            }
+            return;
+        }
+        self.receive_ready(node, blk);
+        // blocks that waited for it
+        loop {
+            let ready = self.nodes[node].buffer.iter().position(|b| self.missing(node, b).is_empty());
+            match ready {
+                Some(index) => {
+                    let blk = self.nodes[node].buffer.remove(index);
+                    self.receive_ready(node, blk);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn receive_ready(&mut self, node: usize, blk: Block) {
+        let hash = blk.hash();
+        if let Err(rejection) = self.accept(node, blk) {
+            debug!("Node {} rejected block {}: {:?}", node, hash, rejection);
+        }
+    }
+
+    /// Check and insert a block whose parent and references are known, returns whether the tip
+    /// changed
+    fn accept(&mut self, node: usize, blk: Block) -> Result<bool, Rejection> {
+        let hash = blk.hash();
+        // clocks are exact, no drift is allowed
+        if blk.block_type != STAKE {
+            let resource = self.resources.get(blk.block_type).ok_or(Rejection::Dropped(format!("proves unknown resource {}", blk.block_type)))?;
+            let n = &mut self.nodes[node];
+            validation::check_pow_header(&mut n.blockchain, resource, &blk, self.now, 0)?;
+            if n.blockchain.insert_pow(&blk).expect("simulated nodes keep their blocks in memory") && !n.tranpool.contains(&hash) {
+                n.tranpool.push(hash);
+            }
+            return Ok(false);
+        }
+        let n = &mut self.nodes[node];
+        validation::check_pos_header(&n.blockchain, &mut n.state, &self.resources, &blk, &vrf_output(&blk.header.vrf_hash), self.now, 0)?;
+        if n.blockchain.record_slot(&blk).is_some() || n.blockchain.is_equivocator(&blk.header.vrf_pub_key) {
+            return Ok(false);
+        }
+        let old_tip = n.blockchain.tip();
+        if !n.blockchain.insert_pos(&blk, false).expect("simulated nodes keep their blocks in memory") {
+            return Ok(false);
+        }
+        self.switch_chain(node, old_tip);
+        let slot = self.now / self.slot_time;
+        self.schedule_staking(node, slot + 1);
+        self.schedule_mining(node);
+        Ok(true)
+    }
+
+    /// Execute the blocks of the new longest chain and give the PoW blocks of the abandoned fork
+    /// back to the tranpool, like the worker
+    fn switch_chain(&mut self, node: usize, old_tip: H256) {
+        let n = &mut self.nodes[node];
+        let blockchain = &n.blockchain;
+        let (mut old, mut new) = (old_tip, blockchain.tip());
+        let (mut old_height, mut new_height) = (blockchain.find_one_depth(&old).unwrap(), blockchain.find_one_depth(&new).unwrap());
+        let (mut abandoned, mut adopted) = (vec![], vec![]);
+        while old != new {
+            if new_height >= old_height {
+                adopted.push(new);
+                new = blockchain.find_one_header(&new).unwrap().parent;
+                new_height -= 1;
+            } else {
+                abandoned.push(old);
+                old = blockchain.find_one_header(&old).unwrap().parent;
+                old_height -= 1;
+            }
+        }
+        for hash in abandoned {
+            for txn_block in blockchain.find_one_block(&hash).unwrap().content.transaction_ref {
+                if !n.tranpool.contains(&txn_block) {
+                    n.tranpool.push(txn_block);
+                }
+            }
+        }
+        for hash in adopted.into_iter().rev() {
+            let blk = blockchain.find_one_block(&hash).unwrap();
+            let pow_blocks = blockchain.get_referenced_blocks(&blk);
+            n.state.update_block(&blk, &pow_blocks);
+            n.tranpool.retain(|txn_block| !blk.content.transaction_ref.contains(txn_block));
+        }
+    }
+
+    fn report(&self) -> SimReport {
+        // chains agree up to some height and differ above it
+        let min_depth = self.nodes.iter().map(|n| n.blockchain.get_depth()).min().unwrap();
+        let agree = |height: u128| {
+            let hash = self.nodes[0].blockchain.find_one_height(height);
+            self.nodes.iter().all(|n| n.blockchain.find_one_height(height) == hash)
+        };
+        let (mut low, mut high) = (0, min_depth);
+        while low < high {
+            let mid = (low + high + 1) / 2;
+            if agree(mid) {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        let common_prefix = low;
+
+        let mut produced: HashMap<Vec<u8>, usize> = HashMap::new();
+        let blockchain = &self.nodes[0].blockchain;
+        let mut hash = blockchain.find_one_height(common_prefix);
+        let accounts = self.nodes[0].state.state_per_block.get(&hash).cloned().unwrap_or_default();
+        while blockchain.find_one_depth(&hash).unwrap() > 0 {
+            let header = blockchain.find_one_header(&hash).unwrap();
+            *produced.entry(header.vrf_pub_key).or_insert(0) += 1;
+            hash = header.parent;
+        }
+        let nodes = self.nodes.iter().map(|n| NodeReport {
+            tip: n.blockchain.tip().to_string(),
+            depth: n.blockchain.get_depth(),
+            num_pos: n.blockchain.get_num_pos(),
+            num_pow: n.blockchain.get_num_pow(),
+            pos_produced: n.pos_produced,
+            pow_produced: n.pow_produced,
+            chain_share: *produced.get(&n.vrf_pub_key).unwrap_or(&0) as f64 / common_prefix.max(1) as f64,
+            rewards: accounts.get(&n.coinbase).map_or(0, |(_, balance)| *balance),
+        }).collect();
+        info!("Simulated {} micro seconds with {} events", self.duration, self.num_events);
+        SimReport {
+            seed: self.seed,
+            duration: self.duration,
+            events: self.num_events,
+            messages: self.num_messages,
+            lost: self.num_lost,
+            common_prefix,
+            nodes,
+        }
+    }
+}
+
+/// What is compared with the stake threshold, the output itself where the worker takes the
+/// SHA-256 of a VRF output
+fn vrf_output(vrf_hash: &[u8]) -> H256 {
+    vrf_hash.to_vec().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(seed: u64) -> SimConfig {
+        let mut genesis = GenesisSpec::default();
+        // about as many PoW blocks per epoch as the two miners find, so the target stays put
+        genesis.epoch_time = 60_000_000;
+        genesis.epoch_size = 70;
+        genesis.rewards.pos = 10;
+        serde_json::from_str::<SimConfig>(r#"{
+            "seed": 0,
+            "duration": 300000000,
+            "nodes": [{"stake": 1, "hash_rate": 1000}, {"stake": 1, "hash_rate": 1000}, {"stake": 2}],
+            "network": {"default": {"latency": {"uniform": {"min": 10000, "max": 100000}}, "bandwidth": 1000000, "loss": 0.1}}
+        }"#).map(|config| SimConfig { seed, genesis, ..config }).unwrap()
+    }
+
+    #[test]
+    fn deterministic() {
+        let report = Simulation::new(config(1)).unwrap().run();
+        assert_eq!(Simulation::new(config(1)).unwrap().run(), report);
+        assert_ne!(Simulation::new(config(2)).unwrap().run().nodes[0].tip, report.nodes[0].tip);
+
+        // the nodes keep up with each other and the third has half of the stake
+        assert!(report.common_prefix > 0 && report.common_prefix + 10 >= report.nodes[0].depth);
+        assert!(report.lost > 0);
+        assert!(report.nodes.iter().all(|node| node.num_pow > 0));
+        assert_eq!(report.nodes[2].pow_produced, 0);
+        assert!(report.nodes[2].chain_share > 0.3 && report.nodes[2].chain_share < 0.7);
+        // a staker that does not mine earns the rewards of its PoS blocks
+        let produced = (report.nodes[2].chain_share * report.common_prefix as f64).round() as usize;
+        assert_eq!(report.nodes[2].rewards, produced * 10);
+    }
+}
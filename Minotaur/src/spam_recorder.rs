@@ -22,4 +22,8 @@ impl SpamRecorder {
     pub fn test_and_set(&mut self, t: &SignedTransaction) -> bool {
         self.set.insert(t.into())
     }
+    /// drop a transaction of an orphaned block, so that it can be mined again
+    pub fn forget(&mut self, t: &SignedTransaction) {
+        self.set.remove(&(t.into()));
+    }
 }
\ No newline at end of file
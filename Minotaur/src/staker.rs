@@ -12,14 +12,19 @@ use crate::network::server::Handle as ServerHandle;
 use crate::blockchain::Blockchain;
 use crate::network::message::Message;
 use crate::state::{State,transaction_check,compute_key_hash};
+use crate::resource::Resources;
+use crate::metrics::Metrics;
+use crate::mempool::{self, Mempool};
+use crate::spam_recorder::SpamRecorder;
 
 
-use log::info;
+use log::{debug, error, info};
 use std::sync::{Arc, Mutex};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time;
 use std::time::{SystemTime, UNIX_EPOCH};
+use std::process;
 use std::thread;
 use rand::Rng;
 
@@ -50,17 +55,20 @@ pub struct Context {
     context_update_recv: Receiver<ContextUpdateSignal>,
     context_update_send: Sender<ContextUpdateSignal>,
     server: ServerHandle,
-    //mempool: Arc<Mutex<Vec<SignedTransaction>>>,
+    mempool: Arc<Mutex<Mempool>>,
+    spam_recorder: Arc<Mutex<SpamRecorder>>,
     state: Arc<Mutex<State>>,
     all_blocks: Arc<Mutex<HashMap<H256,Block>>>,
     tranpool: Arc<Mutex<Vec<H256>>>,              //Pool of hash of transaction blocks that are not included yet
     vrf_secret_key: Vec<u8>,
     vrf_public_key: Vec<u8>,
     selfish_staker: bool,
-    epoch_block_counts: HashMap<u128,(HashMap<Vec<u8>,usize>,f64)>,
-    omega: f64,
-    beta: f64,
+    epoch_thresholds: HashMap<H256,H256>,
+    epoch_rands: HashMap<(H256,u128),u128>,
+    resources: Arc<Resources>,
     atttime: u128,
+    metrics: Arc<Mutex<Metrics>>,
+    coinbase: H160,
 }
 
 #[derive(Clone)]
@@ -74,16 +82,18 @@ pub fn new(
     context_update_recv: Receiver<ContextUpdateSignal>,
     context_update_send: Sender<ContextUpdateSignal>,
     server: &ServerHandle,
-    //mempool: &Arc<Mutex<Vec<SignedTransaction>>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    spam_recorder: &Arc<Mutex<SpamRecorder>>,
     state: &Arc<Mutex<State>>,
     all_blocks: &Arc<Mutex<HashMap<H256,Block>>>,
     tranpool: &Arc<Mutex<Vec<H256>>>,
     vrf_secret_key: &Vec<u8>,
     vrf_public_key: &Vec<u8>,
     selfish_staker: bool,
-    omega: f64,
-    beta: f64,
+    resources: &Arc<Resources>,
     atttime: u128,
+    metrics: &Arc<Mutex<Metrics>>,
+    coinbase: H160,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
 
@@ -94,17 +104,20 @@ pub fn new(
         context_update_recv,
         context_update_send,
         server: server.clone(),
-        //mempool: Arc::clone(mempool),
+        mempool: Arc::clone(mempool),
+        spam_recorder: Arc::clone(spam_recorder),
         state: Arc::clone(state),
         all_blocks: Arc::clone(all_blocks),
         tranpool: Arc::clone(tranpool),
         vrf_secret_key: vrf_secret_key.clone(),
         vrf_public_key: vrf_public_key.clone(),
         selfish_staker: selfish_staker,
-        epoch_block_counts: Default::default(),
-        omega,
-        beta,
+        epoch_thresholds: Default::default(),
+        epoch_rands: Default::default(),
+        resources: Arc::clone(resources),
         atttime,
+        metrics: Arc::clone(metrics),
+        coinbase,
     };
 
     let handle = Handle {
@@ -114,6 +127,35 @@ pub fn new(
     (ctx, handle)
 }
 
+/// Threshold the VRF output of `vrf_pub_key` has to be below to produce a PoS block on `parent` at
+/// `ts`: the PoS difficulty scaled by the weighted shares of every resource the producer held at
+/// the epoch boundary. `None` if one of the shares is unknown.
+pub fn pos_threshold(blockchain: &Blockchain, state: &State, resources: &Resources, vrf_pub_key: &[u8], parent: &H256, ts: u128) -> Option<H256> {
+    let boundary = blockchain.epoch_boundary(parent, ts);
+    let virtual_pos = resources.virtual_stake(blockchain, state, &boundary, vrf_pub_key)?;
+    Some(hash_multiply_by(&blockchain.get_pos_difficulty(), virtual_pos))
+}
+
+/// Execute the blocks from the last executed ancestor of `hash` up to `hash`, so the state of a
+/// block off the longest chain can be read. False if an ancestor is missing.
+pub fn execute_ancestors(blockchain: &Blockchain, state: &mut State, hash: &H256) -> bool {
+    let mut path = vec![];
+    let mut hash = *hash;
+    while !state.check_block(&hash) {
+        let block = match blockchain.find_one_block(&hash) {
+            Some(block) => block,
+            None => return false,
+        };
+        hash = block.header.parent;
+        path.push(block);
+    }
+    for block in path.iter().rev() {
+        let pow_blocks = blockchain.get_referenced_blocks(block);
+        state.update_block(block, &pow_blocks);
+    }
+    true
+}
+
 impl Handle {
     pub fn exit(&self) {
         self.control_chan.send(ControlSignal::Exit).unwrap();
@@ -152,43 +194,31 @@ impl Context {
     }
     
     fn staker_loop(&mut self) {
-        // include pow pos and virtual pos
+        // include pow pos, virtual pos and the epoch randomness
         macro_rules! calc_difficulties {
             ($bc:expr, $ts:expr, $parent:expr) => {
                 {
-                let current_epoch = $bc.epoch($ts);
-                if !self.epoch_block_counts.contains_key(&current_epoch) {
-                    let count_pow_blocks = $bc.is_new_epoch_and_count_blocks($ts);
-                    if let Some(v) = count_pow_blocks {
-                        let set_to_count: HashMap<Vec<u8>, usize> = v.into_iter().map(|(k,v)|(k,v.len())).collect();
-                        let self_count = set_to_count.get(&self.vrf_public_key).unwrap_or(&0);
-                        let all_count: usize = {
-                            let x = set_to_count.iter().map(|(_,v)|*v).sum();
-                            if x>0 {
-                                x
-                            } else {
-                                1
-                            }
-                        };
-                        let fraction: f64 = *self_count as f64 / all_count as f64;
-                        info!("[New Epoch] the count of blocks in previous epoch: {:?}",set_to_count);
-                        self.epoch_block_counts.insert(current_epoch, (set_to_count, fraction));
-                    } else if current_epoch == 0 {
-                        // special handling
-                        self.epoch_block_counts.insert(current_epoch, (Default::default(), 1f64));
-                    } else {
-                        panic!("not reach here")
-                    }
-                }
                 let pow_difficulty = $bc.get_pow_difficulty($ts,$parent);
                 let pos_difficulty = $bc.get_pos_difficulty();
-                let (_, virtual_stake_fraction)= self.epoch_block_counts.get(&current_epoch).unwrap();
-                // Virtual pos difficulty
-                // self.beta is used to conveniently change stake power for experiments
-                // if no requirement to change it, remove self.beta
-                let virtual_pos = self.omega * virtual_stake_fraction + self.beta * (1f64-self.omega);
-                let virtual_pos_difficulty = hash_multiply_by(&pos_difficulty, virtual_pos);
-                (pow_difficulty, pos_difficulty, virtual_pos_difficulty)
+                // the threshold only changes with the epoch boundary block
+                let boundary = $bc.epoch_boundary(&$parent, $ts);
+                if !self.epoch_thresholds.contains_key(&boundary) {
+                    let state = self.state.lock().unwrap();
+                    match pos_threshold(&$bc, &state, &self.resources, &self.vrf_public_key, &$parent, $ts) {
+                        Some(threshold) => {
+                            info!("[New Epoch] virtual stake {:?} at epoch boundary {}", self.resources.virtual_stake(&$bc, &state, &boundary, &self.vrf_public_key), boundary);
+                            self.epoch_thresholds.insert(boundary, threshold);
+                        }
+                        None => debug!("State of epoch boundary {} is unknown, cannot stake", boundary),
+                    }
+                }
+                let virtual_pos_difficulty = self.epoch_thresholds.get(&boundary).cloned().unwrap_or_default();
+                let current_epoch = $bc.epoch($ts);
+                if !self.epoch_rands.contains_key(&(boundary, current_epoch)) {
+                    self.epoch_rands.insert((boundary, current_epoch), $bc.epoch_rand(&$parent, $ts));
+                }
+                let rand = self.epoch_rands[&(boundary, current_epoch)];
+                (pow_difficulty, pos_difficulty, virtual_pos_difficulty, rand)
                 }
             }
         }
@@ -202,6 +232,7 @@ impl Context {
         let bc = self.blockchain.lock().unwrap();
         let mut parent = bc.tip();
         let mut parent_depth = bc.get_depth();
+        let mut last_slot = bc.slot(bc.find_one_header(&parent).unwrap().timestamp);
         drop(bc);
         // main mining loop
         loop {
@@ -233,7 +264,16 @@ impl Context {
             // parent = self.blockchain.lock().unwrap().tip();
             let mut ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros();
             let bc = self.blockchain.lock().unwrap();
-            let (mut pow_difficulty, mut pos_difficulty, mut virtual_pos_difficulty) = calc_difficulties!(bc, ts, parent);
+            let slot = bc.slot(ts);
+            if slot <= last_slot {
+                // one VRF evaluation per slot, wait for the next one
+                let next_slot = bc.slot_start(last_slot + 1);
+                drop(bc);
+                thread::sleep(time::Duration::from_micros(next_slot.saturating_sub(ts) as u64));
+                continue;
+            }
+            last_slot = slot;
+            let (mut pow_difficulty, mut pos_difficulty, mut virtual_pos_difficulty, mut rand) = calc_difficulties!(bc, ts, parent);
             drop(bc);
             //let parent_mmr = self.blockchain.lock().unwrap().get_mmr(&parent);
             let mut rng = rand::thread_rng();
@@ -243,10 +283,9 @@ impl Context {
             let mut enough_txn_block = false;
 
             let mut transaction_ref: Vec<H256> = Vec::new();
-            let mut rand: u128 = Default::default();  // TODO: update rand every epoch
-            let ts_slice = ts.to_be_bytes();
+            let slot_slice = slot.to_be_bytes();
             let rand_slice = rand.to_be_bytes();
-            let message = [rand_slice,ts_slice].concat();
+            let message = [rand_slice,slot_slice].concat();
             // VRF proof and hash output
             let mut vrf_proof = vrf.prove(&self.vrf_secret_key, &message).unwrap();
             let mut vrf_hash = vrf.proof_to_hash(&vrf_proof).unwrap();
@@ -292,8 +331,9 @@ impl Context {
                         }
                     }
                     if new_block {
-                        ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros();
-                        if self.atttime==0 || ts < self.atttime {
+                        // stay in the slot the VRF was evaluated for
+                        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros();
+                        if self.atttime==0 || now < self.atttime {
                             let bc = self.blockchain.lock().unwrap();
                             parent = bc.tip();
                             parent_depth = bc.get_depth();
@@ -302,10 +342,10 @@ impl Context {
                             pow_difficulty = tmp.0;
                             pos_difficulty = tmp.1;
                             virtual_pos_difficulty = tmp.2;
-                            rand = Default::default();  // TODO: update rand every epoch
-                            let ts_slice = ts.to_be_bytes();
+                            rand = tmp.3;
+                            let slot_slice = slot.to_be_bytes();
                             let rand_slice = rand.to_be_bytes();
-                            let message = [rand_slice,ts_slice].concat();
+                            let message = [rand_slice,slot_slice].concat();
                             // VRF proof and hash output
                             vrf_proof = vrf.prove(&self.vrf_secret_key, &message).unwrap();
                             vrf_hash = vrf.proof_to_hash(&vrf_proof).unwrap();
@@ -320,32 +360,44 @@ impl Context {
                         pow_difficulty = tmp.0;
                         pos_difficulty = tmp.1;
                         virtual_pos_difficulty = tmp.2;
-                        rand = Default::default();  // TODO: update rand every epoch
-                        let ts_slice = ts.to_be_bytes();
+                        rand = tmp.3;
+                        let slot_slice = slot.to_be_bytes();
                         let rand_slice = rand.to_be_bytes();
-                        let message = [rand_slice,ts_slice].concat();
+                        let message = [rand_slice,slot_slice].concat();
                         // VRF proof and hash output
                         vrf_proof = vrf.prove(&self.vrf_secret_key, &message).unwrap();
                         vrf_hash = vrf.proof_to_hash(&vrf_proof).unwrap();
                     }
                 }
-                let blk = generate_pos_block(&data, &transaction_ref, &parent, rng.gen(), &pow_difficulty, &pos_difficulty, ts, &vrf_proof, &vrf_hash, 
+                let mut blk = generate_pos_block(&data, &transaction_ref, &parent, rng.gen(), &[pos_difficulty, pow_difficulty], ts, &vrf_proof, &vrf_hash, 
                       &self.vrf_public_key, rand, self.selfish_staker);
+                blk.header.coinbase = self.coinbase;
                 let vrf_hash_bytes: &[u8] = &vrf_hash;
                 let vrf_hash_sha256: H256 = ring::digest::digest(&ring::digest::SHA256, vrf_hash_bytes).into();
                 //info!("Vrf: {}",vrf_hash_sha256);
-                if vrf_hash_sha256 <= virtual_pos_difficulty {    //TODO: change to PoS mining             
+                let parent_slot = {
+                    let bc = self.blockchain.lock().unwrap();
+                    bc.slot(bc.find_one_header(&parent).unwrap().timestamp)
+                };
+                // blocks have to be in a later slot than their parent
+                if vrf_hash_sha256 <= virtual_pos_difficulty && parent_slot < slot {    //TODO: change to PoS mining             
                     info!("Virtual diff: {}, PoS diff: {}",virtual_pos_difficulty,pos_difficulty);
+                    blk.sign(&mut vrf, &self.vrf_secret_key);
                     let copy = blk.clone();
                     count += 1;
+                    self.metrics.lock().unwrap().mined(&blk);
                     info!("Mined {} PoS blocks!", count);
                     info!("Timestamp of the block: {}", copy.header.timestamp);
                     let mut last_longest_chain: Vec<H256> = self.blockchain.lock().unwrap().all_blocks_in_longest_chain();
 
                     self.all_blocks.lock().unwrap().insert(blk.hash(), blk.clone());
 
-                    if self.blockchain.lock().unwrap().insert_pos(&blk, self.selfish_staker) {
-                        //self.state.lock().unwrap().update_block(&blk);
+                    self.blockchain.lock().unwrap().record_slot(&blk);
+                    let inserted = self.blockchain.lock().unwrap().insert_pos(&blk, self.selfish_staker).unwrap_or_else(|e| {
+                        error!("Failed to store PoS block {}: {}, shutting down", blk.hash(), e);
+                        process::exit(1);
+                    });
+                    if inserted {
                         // longest chain changes
                         // update the longest chain
                         let mut longest_chain: Vec<H256> = self.blockchain.lock().unwrap().all_blocks_in_longest_chain();
@@ -361,7 +413,11 @@ impl Context {
                             let block = self.blockchain.lock().unwrap().find_one_block(&blk_hash).unwrap();
                             blocks.push(block);
                         }
-                        // self.state.lock().unwrap().update_blocks(&blocks);
+                        for block in &blocks {
+                            let pow_blocks = self.blockchain.lock().unwrap().get_referenced_blocks(block);
+                            self.state.lock().unwrap().update_block(block, &pow_blocks);
+                        }
+                        mempool::update_tip(&self.mempool, &self.spam_recorder, &self.blockchain, &self.state, &last_longest_chain);
 
                         // add txn_blocks back to the tranpool
                         for blk_hash in last_longest_chain {
@@ -380,31 +436,6 @@ impl Context {
                             let txn_blocks = b.content.transaction_ref;
                             self.tranpool.lock().unwrap().retain(|txn_block| !txn_blocks.contains(txn_block));
                         }
-
-                        
-                        //clean up mempool
-                        // let mem_snap = self.mempool.lock().unwrap().clone();
-                        // let mem_size = mem_snap.len();
-                        // let txns = mem_snap.to_vec();
-                        // let temp_tip = self.blockchain.lock().unwrap().tip().clone(); 
-                        // if self.state.lock().unwrap().check_block(&temp_tip) {
-                        //     let temp_state = self.state.lock().unwrap().one_block_state(&temp_tip).clone();
-                        //     let mut invalid_txns = Vec::new();
-                        //     for txn in txns {
-                        //         let copy = txn.clone();
-                        //         let pubk = copy.sign.pubk.clone();
-                        //         let nonce = copy.transaction.nonce.clone();
-                        //         let value = copy.transaction.value.clone();
-
-                        //         let sender: H160 = compute_key_hash(pubk).into();
-                        //         let (s_nonce, s_amount) = temp_state.get(&sender).unwrap().clone();
-                        //         if s_nonce >= nonce {
-                        //             invalid_txns.push(copy.clone());
-                        //         }
-                        //     }
-                        //     self.mempool.lock().unwrap().retain(|txn| !invalid_txns.contains(txn));
-                        // }
-                        
                     } else {
                         // longest chain not change
                         //self.state.lock().unwrap().update_block(&blk);
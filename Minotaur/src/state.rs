@@ -1,9 +1,11 @@
 use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
 use crate::block::Block;
 use crate::crypto::hash::{H256,H160,Hashable};
+use crate::genesis::RewardSchedule;
 use std::collections::HashMap;
 use crate::transaction::{SignedTransaction,verify_signedtxn};
 use log::{info, debug, warn};
+use serde::{Serialize, Deserialize};
 use rand::{Rng, SeedableRng, StdRng};
 use std::io::BufReader;
 use std::io::BufRead;
@@ -12,9 +14,17 @@ use std::fs;
 
 
 type state = HashMap<H160, (usize, usize)>;
+// stake bonded to each VRF public key
+type stake = HashMap<Vec<u8>, usize>;
 
 pub struct State {
     pub state_per_block: HashMap<H256, state>,
+    pub stake_per_block: HashMap<H256, stake>,
+    pub skipped_per_block: HashMap<H256, Vec<(H256, SkipReason)>>,
+    height_per_block: HashMap<H256, u128>,
+    pub rewards: RewardSchedule,
+    // genesis hash transactions must carry
+    chain_id: H256,
 }
 
 pub fn file_to_vec(filename: String) -> io::Result<Vec<String>> {
@@ -23,19 +33,29 @@ pub fn file_to_vec(filename: String) -> io::Result<Vec<String>> {
     Ok(file_reader.lines().filter_map(io::Result::ok).collect())
 }
 
-pub fn create_ico_keys(n: usize) -> Vec<Ed25519KeyPair> {
-    let lines: Vec<String> = file_to_vec("pubkeys.txt".to_string()).unwrap();
+/// Load the keys of the given ICO accounts, in the same order, from a file with one hex encoded
+/// pkcs8 document per line. Accounts without a key in the file are skipped.
+pub fn create_ico_keys(filename: &str, accounts: &[H160]) -> Vec<Ed25519KeyPair> {
+    let lines: Vec<String> = file_to_vec(filename.to_string()).unwrap_or_else(|e| {
+        warn!("Cannot read keys from {}: {}", filename, e);
+        vec![]
+    });
 
-    let mut keys: Vec<Ed25519KeyPair> = Vec::new();
-    for i in 0..n {
-        let pkcs8_bytes = hex::decode(lines[i].clone()).unwrap();
-        let key = Ed25519KeyPair::from_pkcs8((&pkcs8_bytes[..]).into()).unwrap();
-        keys.push(key);
+    let mut keys_by_account: HashMap<H160, Ed25519KeyPair> = HashMap::new();
+    for line in lines {
+        let pkcs8_bytes = match hex::decode(line.trim()) {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+        if let Ok(key) = Ed25519KeyPair::from_pkcs8((&pkcs8_bytes[..]).into()) {
+            let account: H160 = compute_key_hash(key.public_key().as_ref().to_vec()).into();
+            keys_by_account.insert(account, key);
+        }
     }
-    keys
+    accounts.iter().filter_map(|account| keys_by_account.remove(account)).collect()
 }
 
-pub fn create_ico_accounts(keys: Vec<Ed25519KeyPair>) -> Vec<H160> {
+pub fn create_ico_accounts(keys: &[Ed25519KeyPair]) -> Vec<H160> {
     let mut accounts: Vec<H160> = Vec::new();
     for key in keys {
         let account: H160 = compute_key_hash(key.public_key().as_ref().to_vec()).into();
@@ -49,50 +69,131 @@ pub fn compute_key_hash(key: Vec<u8>) -> H256 {
     ring::digest::digest(&ring::digest::SHA256, bytes).into()
 }
 
-pub fn transaction_check(current_state: &mut state, tx: &SignedTransaction) -> bool {
-	if verify_signedtxn(&tx) {
-		let copy = tx.clone();
-        let pubk = copy.sign.pubk.clone();
-        let nonce = copy.transaction.nonce.clone();
-        let value = copy.transaction.value.clone();
-        let recv = copy.transaction.recv.clone();
-
-        let sender: H160 = compute_key_hash(pubk).into();
-        let (s_nonce, s_amount) = current_state.get(&sender).unwrap().clone();
-        let (r_nonce, r_amount) = current_state.get(&recv).unwrap().clone();
-
-        if nonce == s_nonce+1 &&  s_amount >= value{
-            current_state.insert(sender, (s_nonce+1, s_amount-value));
-            current_state.insert(recv, (r_nonce, r_amount+value));
-            return true;
-        } else {
-            return false;
-        }
-    } else {
-    	return false;
+/// Why a transaction was skipped when its PoS block was executed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    BadSignature,
+    UnknownAccount,
+    BadNonce { expected: usize, got: usize },
+    /// `value` is the amount plus the fee
+    InsufficientBalance { balance: usize, value: usize },
+    /// signed for another network
+    WrongChain { chain_id: H256 },
+    Expired { expiry: u128, height: u128 },
+}
+
+/// Apply a transaction to the state and stake, leaving both untouched if it is invalid. The fee
+/// goes to `coinbase`.
+pub fn execute_transaction(current_state: &mut state, current_stake: &mut stake, tx: &SignedTransaction, coinbase: &H160) -> Result<(), SkipReason> {
+    if !verify_signedtxn(tx) {
+        return Err(SkipReason::BadSignature);
+    }
+    let nonce = tx.transaction.nonce;
+    let value = tx.transaction.value;
+    let fee = tx.transaction.fee;
+    let recv = tx.transaction.recv;
+    let sender = tx.transaction.sender;
+    let (s_nonce, s_amount) = match current_state.get(&sender) {
+        Some(account) => *account,
+        None => return Err(SkipReason::UnknownAccount),
+    };
+    if nonce != s_nonce+1 {
+        return Err(SkipReason::BadNonce { expected: s_nonce+1, got: nonce });
+    }
+    if s_amount < value.saturating_add(fee) {
+        return Err(SkipReason::InsufficientBalance { balance: s_amount, value: value.saturating_add(fee) });
     }
+    current_state.insert(sender, (s_nonce+1, s_amount-value-fee));
+    credit(current_state, coinbase, fee);
+    if let Some(vrf_pub_key) = &tx.transaction.bond {
+        *current_stake.entry(vrf_pub_key.clone()).or_insert(0) += value;
+        return Ok(());
+    }
+    credit(current_state, &recv, value);
+    Ok(())
+}
+
+// the account is created on its first incoming transfer
+fn credit(current_state: &mut state, account: &H160, value: usize) {
+    let (nonce, amount) = current_state.get(account).cloned().unwrap_or((0, 0));
+    current_state.insert(*account, (nonce, amount+value));
+}
 
+pub fn transaction_check(current_state: &mut state, tx: &SignedTransaction) -> bool {
+    execute_transaction(current_state, &mut stake::new(), tx, &Default::default()).is_ok()
+}
+
+/// Whether a transaction submitted to the mempool may execute on top of `current_state`, after
+/// the sender's transactions with lower nonces
+pub fn pending_check(current_state: &state, tx: &SignedTransaction) -> Result<(), SkipReason> {
+    if !verify_signedtxn(tx) {
+        return Err(SkipReason::BadSignature);
+    }
+    let (s_nonce, s_amount) = match current_state.get(&tx.transaction.sender) {
+        Some(account) => *account,
+        None => return Err(SkipReason::UnknownAccount),
+    };
+    if tx.transaction.nonce <= s_nonce {
+        return Err(SkipReason::BadNonce { expected: s_nonce+1, got: tx.transaction.nonce });
+    }
+    let value = tx.transaction.value.saturating_add(tx.transaction.fee);
+    if s_amount < value {
+        return Err(SkipReason::InsufficientBalance { balance: s_amount, value });
+    }
+    Ok(())
 }
 
 impl State {
     pub fn new() -> Self {
         let state_per_block = HashMap::new();
-        State{state_per_block}
+        let stake_per_block = HashMap::new();
+        let skipped_per_block = HashMap::new();
+        let height_per_block = HashMap::new();
+        State{state_per_block, stake_per_block, skipped_per_block, height_per_block, rewards: Default::default(), chain_id: Default::default()}
     }
 
-    pub fn ico(&mut self, genesis_hash: H256, accounts: &Vec<H160>, amount: usize) {
+    pub fn ico(&mut self, genesis_hash: H256, accounts: &[(H160, usize)], stakes: &[(Vec<u8>, usize)]) {
         if self.state_per_block.len()>0 {
             info!("Already did an ICO!");
             return;
         }
         let mut s = state::new();
-        for account in accounts {
-            s.insert(*account, (0, amount));
+        for (account, amount) in accounts {
+            s.insert(*account, (0, *amount));
         }
         self.state_per_block.insert(genesis_hash, s);
+        self.stake_per_block.insert(genesis_hash, stakes.iter().cloned().collect());
+        self.height_per_block.insert(genesis_hash, 0);
+        self.chain_id = genesis_hash;
+    }
+
+    /// Genesis hash of the ICO, the chain id of valid transactions
+    pub fn chain_id(&self) -> H256 {
+        self.chain_id
+    }
+
+    /// PoS height of an executed block
+    pub fn height(&self, hash: &H256) -> Option<u128> {
+        self.height_per_block.get(hash).cloned()
+    }
+
+    /// Whether a transaction is for this network and not expired in a child of `parent`
+    pub fn envelope_check(&self, parent: &H256, tx: &SignedTransaction) -> Result<(), SkipReason> {
+        if tx.transaction.chain_id != self.chain_id {
+            return Err(SkipReason::WrongChain { chain_id: tx.transaction.chain_id });
+        }
+        let height = self.height(parent).unwrap_or(0) + 1;
+        match tx.transaction.expiry {
+            Some(expiry) if expiry < height => Err(SkipReason::Expired { expiry, height }),
+            _ => Ok(()),
+        }
     }
 
-    pub fn update_block(&mut self, block: &Block) {
+    /// Execute a PoS block on top of its parent's state. `pow_blocks` are the blocks its
+    /// `transaction_ref` references for the first time on its chain, in order; invalid
+    /// transactions are skipped and recorded, the fees of valid ones go to the coinbase of their
+    /// PoW block. Then the block rewards go to the coinbases of the PoS block and the PoW blocks.
+    pub fn update_block(&mut self, block: &Block, pow_blocks: &[Block]) {
         if self.state_per_block.contains_key(&block.hash()) {
             return;
         }
@@ -102,24 +203,57 @@ impl State {
             return;
         }
         let mut parent_state = self.state_per_block.get(&parent_hash).unwrap().clone();
-        let txns = block.content.data.clone();
-        for txn in txns {
-            let sender: H160 = compute_key_hash(txn.sign.pubk).into();
-            let recv = txn.transaction.recv;
-            // todo: add txn check, if the account exists or amount is enough
-            
-            let (s_nonce, s_amount) = parent_state.get(&sender).unwrap().clone();
-            let (r_nonce, r_amount) = parent_state.get(&recv).unwrap().clone();
-            parent_state.insert(sender, (s_nonce+1, s_amount-txn.transaction.value));
-            parent_state.insert(recv, (r_nonce, r_amount+txn.transaction.value));
+        let mut parent_stake = self.stake_per_block.get(&parent_hash).cloned().unwrap_or_default();
+        let mut skipped = Vec::new();
+        for pow_block in pow_blocks {
+            for txn in pow_block.content.data.iter() {
+                let executed = self.envelope_check(&parent_hash, txn)
+                    .and_then(|_| execute_transaction(&mut parent_state, &mut parent_stake, txn, &pow_block.header.coinbase));
+                if let Err(reason) = executed {
+                    debug!("Skip txn {} in block {}: {:?}", txn.hash(), block.hash(), reason);
+                    skipped.push((txn.hash(), reason));
+                }
+            }
         }
+        if !skipped.is_empty() {
+            self.skipped_per_block.insert(block.hash(), skipped);
+        }
+        let height = self.height_per_block.get(&parent_hash).cloned().unwrap_or(0) + 1;
+        let pos_reward = self.rewards.pos_reward(height);
+        if pos_reward > 0 {
+            credit(&mut parent_state, &block.header.coinbase, pos_reward);
+        }
+        let pow_reward = self.rewards.pow_reward(height);
+        if pow_reward > 0 {
+            for pow_block in pow_blocks {
+                credit(&mut parent_state, &pow_block.header.coinbase, pow_reward);
+            }
+        }
+        self.height_per_block.insert(block.hash(), height);
         self.state_per_block.insert(block.hash(), parent_state);
+        self.stake_per_block.insert(block.hash(), parent_stake);
     }
 
-    pub fn update_blocks(&mut self, blocks: &Vec<Block>) {
-        for block in blocks {
-            self.update_block(block);
+    /// Stake bonded to every VRF public key after executing a block
+    pub fn one_block_stake(&self, hash: &H256) -> Option<stake> {
+        self.stake_per_block.get(hash).cloned()
+    }
+
+    /// Fraction of the total stake bonded to `vrf_pub_key` after executing a block,
+    /// `None` if the block has not been executed
+    pub fn stake_fraction(&self, hash: &H256, vrf_pub_key: &[u8]) -> Option<f64> {
+        let stakes = self.stake_per_block.get(hash)?;
+        let total: usize = stakes.values().sum();
+        if total == 0 {
+            return Some(0.0);
         }
+        let own = stakes.get(vrf_pub_key).cloned().unwrap_or(0);
+        Some(own as f64 / total as f64)
+    }
+
+    /// Transactions skipped when executing a block, with the reason
+    pub fn skipped_txns(&self, hash: &H256) -> Vec<(H256, SkipReason)> {
+        self.skipped_per_block.get(hash).cloned().unwrap_or_default()
     }
 
     pub fn check_block(&mut self, hash: &H256) -> bool{
@@ -139,4 +273,118 @@ impl State {
             info!("account {:?} has nonce {} value {}", key, nonce, amount);
         }
     }
- }
\ No newline at end of file
+ }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{generate_genesis_block, generate_pos_block, generate_pow_block};
+    use crate::genesis::GenesisSpec;
+    use crate::crypto::key_pair;
+    use crate::transaction::{generate_bond_transaction, sign_transaction, Transaction};
+
+    fn pos_child(parent: &H256, nonce: u32) -> Block {
+        generate_pos_block(&vec![], &vec![], parent, nonce, &[],
+            0, &vec![], &vec![], &[], 0, false)
+    }
+
+    fn pow_block(txns: &[SignedTransaction], coinbase: H160) -> Block {
+        let mut block = generate_pow_block(&txns.to_vec(), &vec![], &Default::default(), 0, &[],
+            0, &vec![], &vec![], &[], 0, false);
+        block.header.coinbase = coinbase;
+        block
+    }
+
+    fn transfer(chain_id: H256, recv: H160, value: usize, nonce: usize, key: &Ed25519KeyPair) -> SignedTransaction {
+        sign_transaction(Transaction { chain_id, recv, value, nonce, ..Default::default() }, key)
+    }
+
+    #[test]
+    fn execute_and_skip() {
+        let alice = key_pair::random();
+        let bob = key_pair::random();
+        let alice_account: H160 = compute_key_hash(alice.public_key().as_ref().to_vec()).into();
+        let bob_account: H160 = compute_key_hash(bob.public_key().as_ref().to_vec()).into();
+        let miner: H160 = [7u8; 20].into();
+        let genesis = generate_genesis_block(0, &GenesisSpec::default());
+        let mut state = State::new();
+        state.ico(genesis.hash(), &[(alice_account, 100)], &[]);
+
+        let chain_id = genesis.hash();
+        let txns = vec![
+            sign_transaction(Transaction { fee: 5, ..transfer(chain_id, bob_account, 30, 1, &alice).transaction }, &alice),
+            transfer(chain_id, bob_account, 30, 3, &alice),
+            transfer(chain_id, bob_account, 500, 2, &alice),
+            transfer(chain_id, alice_account, 10, 2, &bob),
+            // replayed from another network, and expired
+            transfer(Default::default(), bob_account, 1, 2, &alice),
+            sign_transaction(Transaction { expiry: Some(0), ..transfer(chain_id, bob_account, 1, 2, &alice).transaction }, &alice),
+        ];
+        let block = pos_child(&genesis.hash(), 1);
+        state.update_block(&block, &[pow_block(&txns, miner)]);
+        let after = state.one_block_state(&block.hash());
+        assert_eq!(after.get(&alice_account), Some(&(1, 65)));
+        assert_eq!(after.get(&bob_account), Some(&(0, 30)));
+        assert_eq!(after.get(&miner), Some(&(0, 5)));
+        let reasons: Vec<SkipReason> = state.skipped_txns(&block.hash()).into_iter().map(|(_, r)| r).collect();
+        assert_eq!(reasons, vec![
+            SkipReason::BadNonce { expected: 2, got: 3 },
+            SkipReason::InsufficientBalance { balance: 65, value: 500 },
+            SkipReason::BadNonce { expected: 1, got: 2 },
+            SkipReason::WrongChain { chain_id: Default::default() },
+            SkipReason::Expired { expiry: 0, height: 1 },
+        ]);
+        // the mempool takes any later nonce of a sender
+        assert_eq!(pending_check(&after, &txns[1]), Ok(()));
+        assert_eq!(pending_check(&after, &txns[0]), Err(SkipReason::BadNonce { expected: 2, got: 1 }));
+        assert_eq!(pending_check(&after, &txns[2]), Err(SkipReason::InsufficientBalance { balance: 65, value: 500 }));
+
+        // a competing fork executes on top of the genesis state, not on top of the other fork
+        let fork = pos_child(&genesis.hash(), 2);
+        state.update_block(&fork, &[pow_block(&txns[2..], miner)]);
+        assert_eq!(state.one_block_state(&fork.hash()).get(&alice_account), Some(&(0, 100)));
+        assert_eq!(state.skipped_txns(&fork.hash())[1].1, SkipReason::UnknownAccount);
+    }
+
+    #[test]
+    fn bond_stake() {
+        let alice = key_pair::random();
+        let alice_account: H160 = compute_key_hash(alice.public_key().as_ref().to_vec()).into();
+        let genesis = generate_genesis_block(0, &GenesisSpec::default());
+        let mut state = State::new();
+        state.ico(genesis.hash(), &[(alice_account, 100)], &[(vec![1], 30), (vec![2], 10)]);
+        assert_eq!(state.stake_fraction(&genesis.hash(), &[1]), Some(0.75));
+
+        let block = pos_child(&genesis.hash(), 1);
+        let bond = sign_transaction(Transaction { chain_id: genesis.hash(), ..generate_bond_transaction(&[2], 20, 1, &alice).transaction }, &alice);
+        state.update_block(&block, &[pow_block(&[bond], Default::default())]);
+        assert_eq!(state.one_block_state(&block.hash()).get(&alice_account), Some(&(1, 80)));
+        assert_eq!(state.stake_fraction(&block.hash(), &[1]), Some(0.5));
+        assert_eq!(state.stake_fraction(&block.hash(), &[2]), Some(0.5));
+        assert_eq!(state.stake_fraction(&block.hash(), &[3]), Some(0.0));
+        assert_eq!(state.stake_fraction(&pos_child(&block.hash(), 2).hash(), &[1]), None);
+    }
+
+    #[test]
+    fn block_rewards() {
+        let staker: H160 = [1u8; 20].into();
+        let miner: H160 = [2u8; 20].into();
+        let genesis = generate_genesis_block(0, &GenesisSpec::default());
+        let mut state = State::new();
+        state.rewards = RewardSchedule { pos: 8, pow: 4, halving_interval: 2 };
+        state.ico(genesis.hash(), &[], &[]);
+
+        let mut parent = genesis.hash();
+        for nonce in 1..=3 {
+            let mut block = pos_child(&parent, nonce);
+            block.header.coinbase = staker;
+            let pow_blocks = if nonce == 1 { vec![pow_block(&[SignedTransaction::default()], miner)] } else { vec![] };
+            state.update_block(&block, &pow_blocks);
+            parent = block.hash();
+        }
+        // the rewards halve from height 2 on
+        let after = state.one_block_state(&parent);
+        assert_eq!(after.get(&staker), Some(&(0, 16)));
+        assert_eq!(after.get(&miner), Some(&(0, 4)));
+        assert_eq!(state.one_block_state(&genesis.hash()).get(&staker), None);
+    }
+}
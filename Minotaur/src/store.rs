@@ -0,0 +1,328 @@
+use crate::block::Header;
+use crate::blockchain::Data;
+use crate::crypto::hash::H256;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+
+const LOG_FILE: &str = "blocks.log";
+const INDEX_FILE: &str = "blocks.idx";
+// hash (32) + offset (8) + length (4)
+const INDEX_RECORD_SIZE: usize = 44;
+
+/// Storage backend of the `Blockchain`. Blocks are only ever appended, never removed.
+pub trait BlockStore: Send {
+    /// Store a block together with its height
+    fn insert(&mut self, hash: H256, data: &Data) -> io::Result<()>;
+
+    fn get(&self, hash: &H256) -> Option<Data>;
+
+    fn contains(&self, hash: &H256) -> bool;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Hashes of all stored blocks, in insertion order
+    fn hashes(&self) -> Vec<H256>;
+
+    fn get_header(&self, hash: &H256) -> Option<Header> {
+        self.get(hash).map(|data| data.blk.header)
+    }
+
+    fn get_height(&self, hash: &H256) -> Option<u128> {
+        self.get(hash).map(|data| data.height)
+    }
+}
+
+/// Keeps every block in memory, nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    blocks: HashMap<H256, Data>,
+    order: Vec<H256>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            blocks: HashMap::new(),
+            order: Vec::new(),
+        }
+    }
+}
+
+impl BlockStore for MemoryStore {
+    fn insert(&mut self, hash: H256, data: &Data) -> io::Result<()> {
+        if self.blocks.insert(hash, data.clone()).is_none() {
+            self.order.push(hash);
+        }
+        Ok(())
+    }
+
+    fn get(&self, hash: &H256) -> Option<Data> {
+        self.blocks.get(hash).cloned()
+    }
+
+    fn contains(&self, hash: &H256) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn hashes(&self) -> Vec<H256> {
+        self.order.clone()
+    }
+
+    fn get_header(&self, hash: &H256) -> Option<Header> {
+        self.blocks.get(hash).map(|data| data.blk.header.clone())
+    }
+
+    fn get_height(&self, hash: &H256) -> Option<u128> {
+        self.blocks.get(hash).map(|data| data.height)
+    }
+}
+
+struct IndexEntry {
+    offset: u64,
+    length: u32,
+    height: u128,
+    header: Header,
+}
+
+/// Append-only block log plus an index, both living in `datadir`.
+///
+/// `blocks.log` holds length-prefixed bincode `Data` records, `blocks.idx` holds fixed-size
+/// (hash, offset, length) records pointing into the log. Only headers and heights are kept in
+/// memory, block bodies are read back from the log on demand.
+pub struct DiskStore {
+    log: File,
+    index: File,
+    log_len: u64,
+    entries: HashMap<H256, IndexEntry>,
+    order: Vec<H256>,
+}
+
+impl DiskStore {
+    /// Open the store in `datadir`, creating it if needed and recovering the index from the log.
+    pub fn open<P: AsRef<Path>>(datadir: P) -> io::Result<Self> {
+        let datadir: PathBuf = datadir.as_ref().to_path_buf();
+        fs::create_dir_all(&datadir)?;
+        let log = OpenOptions::new().read(true).append(true).create(true).open(datadir.join(LOG_FILE))?;
+        let index = OpenOptions::new().read(true).append(true).create(true).open(datadir.join(INDEX_FILE))?;
+        let log_len = log.metadata()?.len();
+        let mut store = DiskStore {
+            log,
+            index,
+            log_len,
+            entries: HashMap::new(),
+            order: Vec::new(),
+        };
+        store.recover()?;
+        info!("Opened block store at {:?} with {} blocks", datadir, store.order.len());
+        Ok(store)
+    }
+
+    fn recover(&mut self) -> io::Result<()> {
+        let mut raw = Vec::new();
+        (&self.index).seek(SeekFrom::Start(0))?;
+        (&self.index).read_to_end(&mut raw)?;
+        // index records that point into the log
+        let mut end: u64 = 0;
+        let mut valid_records = 0;
+        for record in raw.chunks(INDEX_RECORD_SIZE) {
+            if record.len() < INDEX_RECORD_SIZE {
+                break;
+            }
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(&record[0..32]);
+            let offset = u64::from_be_bytes(record[32..40].try_into().unwrap());
+            let length = u32::from_be_bytes(record[40..44].try_into().unwrap());
+            if offset + 4 + length as u64 > self.log_len {
+                break;
+            }
+            let data = match self.read_at(offset, length) {
+                Ok(data) => data,
+                Err(_) => break,
+            };
+            self.remember(hash.into(), offset, length, &data);
+            end = offset + 4 + length as u64;
+            valid_records += 1;
+        }
+        if valid_records * INDEX_RECORD_SIZE != raw.len() {
+            warn!("Block index is truncated or corrupted, rebuilding it from the log");
+            self.index.set_len((valid_records * INDEX_RECORD_SIZE) as u64)?;
+        }
+        // blocks written to the log but missing from the index
+        while end + 4 <= self.log_len {
+            let mut len_buf = [0u8; 4];
+            (&self.log).seek(SeekFrom::Start(end))?;
+            (&self.log).read_exact(&mut len_buf)?;
+            let length = u32::from_be_bytes(len_buf);
+            if end + 4 + length as u64 > self.log_len {
+                break;
+            }
+            let data = match self.read_at(end, length) {
+                Ok(data) => data,
+                Err(_) => break,
+            };
+            let hash = crate::crypto::hash::Hashable::hash(&data.blk);
+            self.append_index(&hash, end, length)?;
+            self.remember(hash, end, length, &data);
+            end += 4 + length as u64;
+        }
+        if end < self.log_len {
+            warn!("Dropping {} bytes of incomplete block log", self.log_len - end);
+            self.log.set_len(end)?;
+            self.log_len = end;
+        }
+        Ok(())
+    }
+
+    fn remember(&mut self, hash: H256, offset: u64, length: u32, data: &Data) {
+        if !self.entries.contains_key(&hash) {
+            self.order.push(hash);
+        }
+        self.entries.insert(hash, IndexEntry {
+            offset,
+            length,
+            height: data.height,
+            header: data.blk.header.clone(),
+        });
+    }
+
+    fn read_at(&self, offset: u64, length: u32) -> io::Result<Data> {
+        let mut buf = vec![0u8; length as usize];
+        (&self.log).seek(SeekFrom::Start(offset + 4))?;
+        (&self.log).read_exact(&mut buf)?;
+        bincode::deserialize(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn append_index(&mut self, hash: &H256, offset: u64, length: u32) -> io::Result<()> {
+        let mut record = Vec::with_capacity(INDEX_RECORD_SIZE);
+        record.extend_from_slice(hash.as_ref());
+        record.extend_from_slice(&offset.to_be_bytes());
+        record.extend_from_slice(&length.to_be_bytes());
+        self.index.write_all(&record)?;
+        self.index.flush()
+    }
+
+    fn append(&mut self, hash: &H256, data: &Data) -> io::Result<()> {
+        let serialized = bincode::serialize(data).unwrap();
+        let length = serialized.len() as u32;
+        let offset = self.log_len;
+        self.log.write_all(&length.to_be_bytes())?;
+        self.log.write_all(&serialized)?;
+        self.log.flush()?;
+        self.log_len += 4 + length as u64;
+        self.append_index(hash, offset, length)?;
+        self.remember(*hash, offset, length, data);
+        Ok(())
+    }
+}
+
+impl BlockStore for DiskStore {
+    fn insert(&mut self, hash: H256, data: &Data) -> io::Result<()> {
+        if self.entries.contains_key(&hash) {
+            return Ok(());
+        }
+        self.append(&hash, data)
+    }
+
+    fn get(&self, hash: &H256) -> Option<Data> {
+        let entry = self.entries.get(hash)?;
+        match self.read_at(entry.offset, entry.length) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                warn!("Failed to read block {} from disk: {}", hash, e);
+                None
+            }
+        }
+    }
+
+    fn contains(&self, hash: &H256) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn hashes(&self) -> Vec<H256> {
+        self.order.clone()
+    }
+
+    fn get_header(&self, hash: &H256) -> Option<Header> {
+        self.entries.get(hash).map(|entry| entry.header.clone())
+    }
+
+    fn get_height(&self, hash: &H256) -> Option<u128> {
+        self.entries.get(hash).map(|entry| entry.height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{generate_genesis_block, generate_pos_block};
+    use crate::genesis::GenesisSpec;
+    use crate::crypto::hash::Hashable;
+    use std::fs::OpenOptions;
+
+    fn temp_datadir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("minotaur-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn child(parent: &H256, height: u128) -> Data {
+        let blk = generate_pos_block(&vec![], &vec![], parent, height as u32, &[],
+            height, &vec![], &vec![], &[], 0, false);
+        Data { blk, height }
+    }
+
+    #[test]
+    fn reopen() {
+        let dir = temp_datadir("reopen");
+        let genesis = Data { blk: generate_genesis_block(0, &GenesisSpec::default()), height: 0 };
+        let block = child(&genesis.blk.hash(), 1);
+        {
+            let mut store = DiskStore::open(&dir).unwrap();
+            store.insert(genesis.blk.hash(), &genesis).unwrap();
+            store.insert(block.blk.hash(), &block).unwrap();
+            store.insert(block.blk.hash(), &block).unwrap();
+            assert_eq!(store.len(), 2);
+        }
+        let store = DiskStore::open(&dir).unwrap();
+        assert_eq!(store.hashes(), vec![genesis.blk.hash(), block.blk.hash()]);
+        assert_eq!(store.get(&block.blk.hash()), Some(block.clone()));
+        assert_eq!(store.get_height(&block.blk.hash()), Some(1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rebuild_index() {
+        let dir = temp_datadir("rebuild");
+        let genesis = Data { blk: generate_genesis_block(0, &GenesisSpec::default()), height: 0 };
+        let block = child(&genesis.blk.hash(), 1);
+        {
+            let mut store = DiskStore::open(&dir).unwrap();
+            store.insert(genesis.blk.hash(), &genesis).unwrap();
+            store.insert(block.blk.hash(), &block).unwrap();
+        }
+        // lose the last index record and half of another one
+        let index = OpenOptions::new().write(true).open(dir.join(INDEX_FILE)).unwrap();
+        index.set_len((INDEX_RECORD_SIZE / 2) as u64).unwrap();
+        drop(index);
+        let store = DiskStore::open(&dir).unwrap();
+        assert_eq!(store.hashes(), vec![genesis.blk.hash(), block.blk.hash()]);
+        assert_eq!(store.get(&block.blk.hash()), Some(block));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -4,13 +4,37 @@ use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm,
 use rand::Rng;
 use crate::crypto::hash::{self, Hashable, H256,generate_random_hash};
 use crate::crypto::key_pair;
+use crate::state::compute_key_hash;
 
 
+/// Version of the transaction format, transactions of other versions are invalid
+pub const TX_VERSION: u16 = 1;
+/// Longest memo a transaction may carry, in bytes
+pub const MAX_MEMO_SIZE: usize = 256;
+/// Largest bincode encoding of a valid transaction: the fixed fields, the signature and a bond to
+/// a VRF public key take under 512 bytes, the memo at most `MAX_MEMO_SIZE`
+pub const MAX_TXN_BYTES: usize = 512 + MAX_MEMO_SIZE;
+/// Prefix of the signed bytes of a transaction, so that signatures of other messages or other
+/// protocols are never valid transaction signatures
+const SIGN_DOMAIN: &[u8] = b"minotaur/transaction";
+
 #[derive(Serialize, Deserialize, Debug, Default,Hash, Eq, PartialEq,Clone)]
 pub struct Transaction {
+    pub version: u16,
+    /// hash of the genesis block of the network the transaction is valid on
+    pub chain_id: H256,
+    /// account paying the value and the fee, the hash of the signing key
+    pub sender: hash::H160,
     pub recv: hash::H160,
     pub value: usize,
     pub nonce: usize,
+    /// VRF public key to bond `value` to as stake, `recv` is ignored if set
+    pub bond: Option<Vec<u8>>,
+    /// paid to the coinbase of the PoW block carrying the transaction
+    pub fee: usize,
+    /// last PoS height the transaction may be executed at
+    pub expiry: Option<u128>,
+    pub memo: Option<Vec<u8>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default,Hash, Eq, PartialEq,Clone)]
@@ -33,6 +57,13 @@ impl Hashable for Transaction {
     }
 }
 
+impl SignedTransaction {
+    /// Bytes the transaction takes in a block
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+}
+
 impl Hashable for SignedTransaction {
     fn hash(&self) -> H256 {
         let serialized: Vec<u8> = bincode::serialize(&self).unwrap();
@@ -42,55 +73,133 @@ impl Hashable for SignedTransaction {
 }
 
 
+/// Bytes a transaction signature covers: the signing domain, then the bincode encoding
+pub fn signing_bytes(t: &Transaction) -> Vec<u8> {
+    [SIGN_DOMAIN, &bincode::serialize(&t).unwrap()].concat()
+}
+
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
-    let serialized: Vec<u8> = bincode::serialize(&t).unwrap();
-    key.sign(&serialized)
+    key.sign(&signing_bytes(t))
 }
 
 /// Verify digital signature of a transaction, using public key instead of secret key
 pub fn verify(t: &Transaction, public_key: &<Ed25519KeyPair as KeyPair>::PublicKey, signature: &Signature) -> bool {
-    let serialized: Vec<u8> = bincode::serialize(&t).unwrap();
-    let bytes: &[u8] = &serialized;
-    match VerificationAlgorithm::verify(&EdDSAParameters, public_key.as_ref().into(), bytes.into(), signature.as_ref().into()) {
+    let bytes = signing_bytes(t);
+    match VerificationAlgorithm::verify(&EdDSAParameters, public_key.as_ref().into(), bytes[..].into(), signature.as_ref().into()) {
         Ok(_) => true,
         Err(_e) => false,
     }
 }
 
+/// Whether a transaction has the current version, a memo of at most `MAX_MEMO_SIZE` bytes, and a
+/// valid signature by the key of its sender. The chain id and the expiry are checked by the state.
 pub fn verify_signedtxn(t: &SignedTransaction) -> bool {
-    let transaction = t.transaction.clone();
-    let pubk = t.sign.pubk.clone();
-    let sig = t.sign.sig.clone();
-    let serialized: Vec<u8> = bincode::serialize(&transaction).unwrap();
-    let bytes: &[u8] = &serialized;
-    match VerificationAlgorithm::verify(&EdDSAParameters, pubk[..].into(), bytes.into(), sig[..].into()) {
+    check_envelope(t) && check_signature(t)
+}
+
+/// Smallest batch `verify_signedtxns` spreads over the pool, handing out costs more for fewer
+const MIN_PARALLEL_BATCH: usize = 8;
+
+// public key, signature and signed bytes of the transactions at the indices of a batch
+type VerifyJob = (Vec<(usize, Vec<u8>, Vec<u8>, Vec<u8>)>, crossbeam::channel::Sender<Vec<(usize, bool)>>);
+
+/// Threads checking transaction signatures for `verify_signedtxns`, started once and shared by
+/// every caller
+pub struct VerifyPool {
+    jobs: crossbeam::channel::Sender<VerifyJob>,
+    threads: usize,
+}
+
+impl VerifyPool {
+    /// Start `threads` verifying threads, with 0 callers verify on their own thread
+    pub fn new(threads: usize) -> Self {
+        let (jobs, receiver) = crossbeam::channel::unbounded::<VerifyJob>();
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name("verifier".to_string())
+                .spawn(move || {
+                    for (items, results) in receiver.iter() {
+                        let _ = results.send(check_signature_items(&items));
+                    }
+                })
+                .unwrap();
+        }
+        VerifyPool { jobs, threads }
+    }
+}
+
+/// `verify_signedtxn` of every transaction of a batch, such as the contents of a block or a
+/// `Transactions` message. All envelopes are checked before any signature, then the signatures
+/// of a batch of at least `MIN_PARALLEL_BATCH` are split between the caller and the pool.
+pub fn verify_signedtxns(pool: &VerifyPool, txns: &[SignedTransaction]) -> Vec<bool> {
+    let mut valid: Vec<bool> = txns.iter().map(check_envelope).collect();
+    let items: Vec<(usize, Vec<u8>, Vec<u8>, Vec<u8>)> = txns.iter().enumerate()
+        .filter(|(i, _)| valid[*i])
+        .map(|(i, t)| (i, t.sign.pubk.clone(), t.sign.sig.clone(), signing_bytes(&t.transaction)))
+        .collect();
+    let mut checked = Vec::with_capacity(items.len());
+    if items.len() < MIN_PARALLEL_BATCH || pool.threads == 0 {
+        checked = check_signature_items(&items);
+    } else {
+        let chunk = (items.len() + pool.threads) / (pool.threads + 1);
+        let mut chunks = items.chunks(chunk);
+        let own = chunks.next().unwrap();
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        let mut pending = 0;
+        for chunk in chunks {
+            pool.jobs.send((chunk.to_vec(), sender.clone())).unwrap();
+            pending += 1;
+        }
+        checked.extend(check_signature_items(own));
+        for results in receiver.iter().take(pending) {
+            checked.extend(results);
+        }
+    }
+    for (i, ok) in checked {
+        valid[i] = ok;
+    }
+    valid
+}
+
+fn check_envelope(t: &SignedTransaction) -> bool {
+    let transaction = &t.transaction;
+    if transaction.version != TX_VERSION || transaction.memo.as_ref().map_or(false, |memo| memo.len() > MAX_MEMO_SIZE) {
+        return false;
+    }
+    let sender: H160 = compute_key_hash(t.sign.pubk.clone()).into();
+    sender == transaction.sender
+}
+
+fn check_signature(t: &SignedTransaction) -> bool {
+    let bytes = signing_bytes(&t.transaction);
+    match VerificationAlgorithm::verify(&EdDSAParameters, t.sign.pubk[..].into(), bytes[..].into(), t.sign.sig[..].into()) {
         Ok(_) => true,
         Err(_e) => false,
     }
 }
 
+fn check_signature_items(items: &[(usize, Vec<u8>, Vec<u8>, Vec<u8>)]) -> Vec<(usize, bool)> {
+    items.iter()
+        .map(|(i, pubk, sig, bytes)| (*i, VerificationAlgorithm::verify(&EdDSAParameters, pubk[..].into(), bytes[..].into(), sig[..].into()).is_ok()))
+        .collect()
+}
+
 pub fn generate_random_transaction() -> Transaction {
     let mut rng = rand::thread_rng();
     Transaction {
     recv:  hash::generate_random_hash().into(),
     value: rng.gen(),
     nonce: rng.gen(),
+    ..Default::default()
     }
 }
 
 pub fn generate_random_signed_transaction() -> SignedTransaction {
     let transaction = generate_random_transaction();
     let pubk = key_pair::random();
-    let sig = sign(&transaction, &pubk);
-    let sign = Sign {
-        pubk: pubk.public_key().as_ref().to_vec(),
-        sig: sig.as_ref().to_vec(),
-    };
-    SignedTransaction {
-        transaction,
-        sign,
-    }
+    sign_transaction(transaction, &pubk)
 }
 
 pub fn generate_valid_transaction(recv: H160, value: usize, nonce: usize) -> Transaction {
@@ -99,13 +208,21 @@ pub fn generate_valid_transaction(recv: H160, value: usize, nonce: usize) -> Tra
     recv:  recv,
     value: value,
     nonce: nonce,
+    ..Default::default()
     }
 }
 
 pub fn generate_valid_signed_transaction(recv: H160, value: usize, nonce: usize, pubk:&Ed25519KeyPair) -> SignedTransaction {
     let transaction = generate_valid_transaction(recv,value,nonce);
     //let pubk = key_pair::random();
-    let sig = sign(&transaction, &pubk);
+    sign_transaction(transaction, pubk)
+}
+
+/// Sign a transaction with the key of its sender, filling in the version and the sender
+pub fn sign_transaction(mut transaction: Transaction, pubk: &Ed25519KeyPair) -> SignedTransaction {
+    transaction.version = TX_VERSION;
+    transaction.sender = compute_key_hash(pubk.public_key().as_ref().to_vec()).into();
+    let sig = sign(&transaction, pubk);
     let sign = Sign {
         pubk: pubk.public_key().as_ref().to_vec(),
         sig: sig.as_ref().to_vec(),
@@ -116,18 +233,28 @@ pub fn generate_valid_signed_transaction(recv: H160, value: usize, nonce: usize,
     }
 }
 
+/// Bond `value` from the signer's account to the stake of a VRF public key
+pub fn generate_bond_transaction(vrf_pub_key: &[u8], value: usize, nonce: usize, pubk: &Ed25519KeyPair) -> SignedTransaction {
+    let transaction = Transaction {
+        value,
+        nonce,
+        bond: Some(vrf_pub_key.to_vec()),
+        ..Default::default()
+    };
+    sign_transaction(transaction, pubk)
+}
+
 #[derive(PartialEq, Eq, Hash, Serialize)]
 pub struct SpamId {
     pub nonce: usize,
-    pub pubk: String,
+    pub sender: H160,
 }
 
 impl std::convert::From<&SignedTransaction> for SpamId {
     fn from(t: &SignedTransaction) -> Self {
-        let hash: H256 = t.sign.pubk.clone().into();
         SpamId {
             nonce: t.transaction.nonce,
-            pubk: hash.to_string(),
+            sender: t.transaction.sender,
         }
     }
 }
@@ -143,6 +270,9 @@ mod tests {
             recv: hash::generate_random_hash().into(),
             value: rng.gen(),
             nonce: rng.gen(),
+            fee: rng.gen(),
+            memo: Some(b"memo".to_vec()),
+            ..Default::default()
         }
     }
 
@@ -158,5 +288,84 @@ mod tests {
     fn sign_verify2() {
         let t = generate_random_signed_transaction();
         assert!(verify_signedtxn(&t));
+
+        // the sender, the version and the memo size are part of the check
+        let key = key_pair::random();
+        let signed = sign_transaction(Transaction::default(), &key);
+        let resign = |transaction: Transaction| SignedTransaction { sign: Sign { sig: sign(&transaction, &key).as_ref().to_vec(), ..signed.sign.clone() }, transaction };
+        assert!(verify_signedtxn(&resign(signed.transaction.clone())));
+        assert!(!verify_signedtxn(&resign(Transaction { sender: hash::generate_random_hash().into(), ..signed.transaction.clone() })));
+        assert!(!verify_signedtxn(&resign(Transaction { version: TX_VERSION + 1, ..signed.transaction.clone() })));
+        assert!(!verify_signedtxn(&resign(Transaction { memo: Some(vec![0; MAX_MEMO_SIZE + 1]), ..signed.transaction.clone() })));
+        // a signature over the bare encoding, without the domain, is not valid
+        let bare = key.sign(&bincode::serialize(&signed.transaction).unwrap());
+        assert!(!verify_signedtxn(&SignedTransaction { sign: Sign { sig: bare.as_ref().to_vec(), ..signed.sign.clone() }, ..signed.clone() }));
+    }
+
+    #[test]
+    fn max_size() {
+        let key = key_pair::random();
+        let t = sign_transaction(Transaction {
+            value: usize::MAX,
+            nonce: usize::MAX,
+            fee: usize::MAX,
+            bond: Some(vec![255; 65]),
+            expiry: Some(u128::MAX),
+            memo: Some(vec![255; MAX_MEMO_SIZE]),
+            ..Default::default()
+        }, &key);
+        assert!(bincode::serialize(&t).unwrap().len() <= MAX_TXN_BYTES);
+        // `POST /tx` reads up to 4 characters per byte, enough for the JSON format
+        assert!(serde_json::to_string(&t).unwrap().len() <= 4 * MAX_TXN_BYTES);
+    }
+
+    /// A batch of `n` signed transactions, every third one with a bad signature
+    fn batch(n: usize) -> Vec<SignedTransaction> {
+        (0..n).map(|i| {
+            let mut t = generate_random_signed_transaction();
+            if i % 3 == 2 {
+                t.transaction.value = t.transaction.value.wrapping_add(1);
+            }
+            t
+        }).collect()
+    }
+
+    #[test]
+    fn verify_batch() {
+        for threads in [0, 2].iter() {
+            let pool = VerifyPool::new(*threads);
+            for n in [0, 2, MIN_PARALLEL_BATCH, 32, 33].iter() {
+                let txns = batch(*n);
+                let expected: Vec<bool> = txns.iter().map(verify_signedtxn).collect();
+                assert_eq!(verify_signedtxns(&pool, &txns), expected);
+            }
+        }
+    }
+
+    /// Timing of `verify_signedtxn` one transaction at a time against `verify_signedtxns` on a
+    /// pool of one thread per core, run with
+    /// `cargo test --release verify_batch_bench -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn verify_batch_bench() {
+        use std::time::Instant;
+        let rounds = 200;
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let pool = VerifyPool::new(threads);
+        // blocks of the miner hold up to 32 transactions, gossip messages are often smaller
+        for n in [1, 2, 8, 32].iter() {
+            let txns: Vec<SignedTransaction> = (0..*n).map(|_| generate_random_signed_transaction()).collect();
+            let start = Instant::now();
+            for _ in 0..rounds {
+                assert!(txns.iter().all(verify_signedtxn));
+            }
+            let single = start.elapsed() / rounds;
+            let start = Instant::now();
+            for _ in 0..rounds {
+                assert!(verify_signedtxns(&pool, &txns).into_iter().all(|ok| ok));
+            }
+            let batched = start.elapsed() / rounds;
+            println!("{:>2} txns: verify_signedtxn {:?}, verify_signedtxns on {} threads {:?}", n, single, threads, batched);
+        }
     }
 }
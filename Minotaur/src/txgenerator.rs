@@ -4,7 +4,7 @@ use crate::transaction::generate_random_transaction;
 use crate::block::{Block, Header, Content};
 use crate::crypto::merkle::MerkleTree;
 use crate::crypto::hash::{H256,Hashable,generate_random_hash};
-use crate::transaction::{Transaction,SignedTransaction,generate_valid_signed_transaction,generate_random_signed_transaction};
+use crate::transaction::{Transaction,SignedTransaction,sign_transaction,generate_random_signed_transaction};
 use crate::network::server::Handle as ServerHandle;
 use crate::blockchain::Blockchain;
 use crate::network::message::Message;
@@ -13,10 +13,11 @@ use std::collections::{HashMap, HashSet};
 use crate::crypto::hash::H160;
 use ring::signature::Ed25519KeyPair;
 use ring::signature::KeyPair;
-use crate::state::{State,transaction_check,compute_key_hash,create_ico_keys};
+use crate::state::{State,SkipReason,transaction_check,compute_key_hash,create_ico_keys};
+use crate::mempool::{self, Mempool, MempoolError};
 
 
-use log::info;
+use log::{debug, info};
 use std::sync::{Arc, Mutex};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
@@ -47,13 +48,14 @@ pub struct Context {
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     server: ServerHandle,
-    mempool: Arc<Mutex<Vec<SignedTransaction>>>,
+    mempool: Arc<Mutex<Mempool>>,
     all_txns: Arc<Mutex<HashMap<H256,SignedTransaction>>>,
     state: Arc<Mutex<State>>,
     key_pairs: Vec<Ed25519KeyPair>,
     //accounts: Vec<H160>,
     numerator: usize,
     denominator: usize,
+    max_fee: usize,
 }
 
 #[derive(Clone)]
@@ -65,13 +67,14 @@ pub struct Handle {
 pub fn new(
     blockchain: &Arc<Mutex<Blockchain>>,
     server: &ServerHandle,
-    mempool: &Arc<Mutex<Vec<SignedTransaction>>>,
+    mempool: &Arc<Mutex<Mempool>>,
     all_txns: &Arc<Mutex<HashMap<H256,SignedTransaction>>>,
     state: &Arc<Mutex<State>>,
     key_pairs: Vec<Ed25519KeyPair>,
     //accounts: &Vec<H160>,
     numerator: usize,
     denominator: usize,
+    max_fee: usize,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
 
@@ -87,6 +90,7 @@ pub fn new(
         //accounts: accounts.clone(),
         numerator,
         denominator,
+        max_fee,
     };
 
     let handle = Handle {
@@ -175,15 +179,22 @@ impl Context {
             let recv:H160 = compute_key_hash(self.key_pairs[recv_index].public_key().as_ref().to_vec()).into();
             
             let value:usize = rng.gen_range(1, 10000001);
-            let tx = generate_valid_signed_transaction(recv, value, s_nonce, &pubk);
-
-            self.mempool.lock().expect("txgenerator error 3").push(tx.clone());
-            self.all_txns.lock().expect("txgenerator error 4").insert(tx.clone().hash(), tx.clone());
-            self.server.broadcast(Message::NewTransactionHashes(vec![tx.hash()]));
+            let fee:usize = rng.gen_range(0, self.max_fee + 1);
+            let chain_id = self.state.lock().unwrap().chain_id();
+            let tx = sign_transaction(Transaction { chain_id, recv, value, nonce: s_nonce, fee, ..Default::default() }, &pubk);
+
+            match mempool::insert(&self.mempool, &self.blockchain, &self.state, tx.clone()) {
+                Ok(()) => {
+                    self.all_txns.lock().expect("txgenerator error 4").insert(tx.clone().hash(), tx.clone());
+                    self.server.broadcast(Message::NewTransactionHashes(vec![tx.hash()]));
+                    s_nonce += self.denominator;
+                }
+                // the tip executed this nonce already, move on to the next one
+                Err(MempoolError::Invalid(SkipReason::BadNonce { .. })) => s_nonce += self.denominator,
+                // retry the nonce later
+                Err(e) => debug!("Generated transaction {} not added to the mempool: {:?}", tx.hash(), e),
+            }
             // info!("new tx generated:{}",self.mempool.lock().unwrap().len());
-            //}
-
-            s_nonce += self.denominator;
 
 
             if let OperatingState::Run(i) = self.operating_state {
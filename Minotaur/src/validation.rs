@@ -0,0 +1,67 @@
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::H256;
+use crate::resource::{Resource, Resources};
+use crate::staker;
+use crate::state::State;
+
+/// Why a block is refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    /// the block breaks a consensus rule, the peer that sent it misbehaves
+    Invalid(String),
+    /// the block cannot be judged now, it is dropped without blaming the peer
+    Dropped(String),
+}
+
+/// Header checks of a PoS block whose parent is known, at local time `now`: its slot follows its
+/// parent's, it is at most `max_drift` ahead, it uses the epoch beacon, and `vrf_output` is
+/// under the stake threshold of its producer at the epoch boundary. The VRF proof and the
+/// signature are checked by the caller.
+pub fn check_pos_header(blockchain: &Blockchain, state: &mut State, resources: &Resources, blk: &Block, vrf_output: &H256, now: u128, max_drift: u128) -> Result<(), Rejection> {
+    let parent = blk.header.parent;
+    let slot = blockchain.slot(blk.header.timestamp);
+    let parent_slot = blockchain.slot(blockchain.find_one_header(&parent).unwrap().timestamp);
+    if slot <= parent_slot {
+        return Err(Rejection::Invalid(format!("is in slot {}, not after its parent's slot {}", slot, parent_slot)));
+    }
+    if blk.header.timestamp > now + max_drift {
+        return Err(Rejection::Dropped(format!("is {} micro seconds ahead of local time", blk.header.timestamp - now)));
+    }
+    // the VRF message must use the randomness beacon of the block's epoch
+    let epoch_rand = blockchain.epoch_rand(&parent, blk.header.timestamp);
+    if blk.header.rand != epoch_rand {
+        return Err(Rejection::Invalid(format!("uses rand {} instead of the epoch beacon {}", blk.header.rand, epoch_rand)));
+    }
+    // the producer must be eligible with its stake at the epoch boundary, a boundary off the
+    // longest chain is executed first
+    let boundary = blockchain.epoch_boundary(&parent, blk.header.timestamp);
+    staker::execute_ancestors(blockchain, state, &boundary);
+    match staker::pos_threshold(blockchain, state, resources, &blk.header.vrf_pub_key, &parent, blk.header.timestamp) {
+        Some(threshold) if *vrf_output > threshold => Err(Rejection::Invalid("is above the stake threshold of its producer".to_string())),
+        None => Err(Rejection::Dropped("has a producer whose stake at the epoch boundary is unknown".to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Header checks of a block of a non stake resource whose parent is known, at local time `now`:
+/// its timestamp, which picks its target, is not before its parent's nor more than `max_drift`
+/// ahead, and it claims the target of its epoch. The proof itself is checked by the caller.
+pub fn check_pow_header(blockchain: &mut Blockchain, resource: &dyn Resource, blk: &Block, now: u128, max_drift: u128) -> Result<(), Rejection> {
+    let parent = blk.header.parent;
+    let parent_ts = blockchain.find_one_header(&parent).unwrap().timestamp;
+    if blk.header.timestamp < parent_ts {
+        return Err(Rejection::Invalid("is timestamped before its parent".to_string()));
+    }
+    if blk.header.timestamp > now + max_drift {
+        return Err(Rejection::Dropped(format!("is {} micro seconds ahead of local time", blk.header.timestamp - now)));
+    }
+    // the target must follow the epoch schedule of the parent
+    let expected_difficulty = resource.difficulty(blockchain, &parent, blk.header.timestamp);
+    if blk.header.difficulty(blk.block_type) > expected_difficulty {
+        blockchain.count_rejected_pow();
+        return Err(Rejection::Invalid(format!("claims difficulty {} easier than {}, rejected {} so far", blk.header.difficulty(blk.block_type), expected_difficulty, blockchain.get_num_rejected_pow())));
+    }
+    Ok(())
+}
+
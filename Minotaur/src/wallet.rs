@@ -0,0 +1,174 @@
+use crate::crypto::hash::{H160, H256, Hashable};
+use crate::genesis::{parse_h160, parse_h256};
+use crate::state::compute_key_hash;
+use crate::transaction::{sign_transaction, SignedTransaction, Transaction};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use clap::ArgMatches;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+/// A new Ed25519 key, hex encoded pkcs8 like the lines of the `--txkeys` file
+pub fn generate_key() -> String {
+    let rng = ring::rand::SystemRandom::new();
+    let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+    hex::encode(pkcs8_bytes.as_ref())
+}
+
+pub fn parse_key(input: &str) -> Result<Ed25519KeyPair, String> {
+    let pkcs8_bytes = hex::decode(input.trim()).map_err(|e| format!("invalid hex key: {}", e))?;
+    Ed25519KeyPair::from_pkcs8((&pkcs8_bytes[..]).into()).map_err(|e| format!("invalid pkcs8 key: {}", e))
+}
+
+/// Read the first key of a file in the `--txkeys` format
+pub fn load_key(filename: &str) -> Result<Ed25519KeyPair, String> {
+    let content = fs::read_to_string(filename).map_err(|e| format!("cannot read {}: {}", filename, e))?;
+    let line = content.lines().find(|l| !l.trim().is_empty()).ok_or(format!("no key in {}", filename))?;
+    parse_key(line)
+}
+
+/// Account of the transactions signed by a key
+pub fn address(key: &Ed25519KeyPair) -> H160 {
+    compute_key_hash(key.public_key().as_ref().to_vec()).into()
+}
+
+/// A transfer to `recv`, or a bond to the stake of a VRF public key, on the network of `chain_id`
+pub fn transfer(chain_id: H256, recv: H160, value: usize, fee: usize, nonce: usize, bond: Option<&[u8]>) -> Transaction {
+    let recv = if bond.is_some() { Default::default() } else { recv };
+    Transaction { chain_id, recv, value, nonce, bond: bond.map(|b| b.to_vec()), fee, ..Default::default() }
+}
+
+/// Chain id of the node's network, the hash of its genesis block
+pub fn chain_id(api: &str) -> Result<H256, String> {
+    let response = request(api, "GET", "/chain/block?height=0", "")?;
+    let genesis: serde_json::Value = serde_json::from_str(&response).map_err(|e| format!("invalid response {}: {}", response, e))?;
+    match genesis["hash"].as_str() {
+        Some(hash) => parse_h256(hash),
+        None => Err(format!("cannot get the genesis block: {}", response)),
+    }
+}
+
+/// Hex of the bincode encoding, the compact format `POST /tx` accepts
+pub fn encode(txn: &SignedTransaction) -> String {
+    hex::encode(bincode::serialize(txn).unwrap())
+}
+
+/// Read a transaction in the JSON or the hex bincode format
+pub fn decode(input: &str) -> Result<SignedTransaction, String> {
+    let input = input.trim();
+    if input.starts_with('{') {
+        return serde_json::from_str(input).map_err(|e| format!("invalid JSON transaction: {}", e));
+    }
+    let bytes = hex::decode(input).map_err(|e| format!("invalid hex transaction: {}", e))?;
+    bincode::deserialize(&bytes).map_err(|e| format!("invalid bincode transaction: {}", e))
+}
+
+/// Send a request to the API server of a node and return the response body
+pub fn request(api: &str, method: &str, path: &str, body: &str) -> Result<String, String> {
+    let mut stream = TcpStream::connect(api).map_err(|e| format!("cannot connect to {}: {}", api, e))?;
+    write!(stream, "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method, path, api, body.len(), body).map_err(|e| format!("cannot send request to {}: {}", api, e))?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).map_err(|e| format!("cannot read response from {}: {}", api, e))?;
+    match response.find("\r\n\r\n") {
+        Some(end) => Ok(response[end + 4..].to_string()),
+        None => Err(format!("malformed response from {}", api)),
+    }
+}
+
+/// The `wallet` subcommand
+pub fn run(matches: &ArgMatches) -> Result<(), String> {
+    let api = matches.value_of("api_addr").unwrap();
+    match matches.subcommand() {
+        ("new", Some(m)) => {
+            let pkcs8 = generate_key();
+            let account = address(&parse_key(&pkcs8)?);
+            if let Some(filename) = m.value_of("out") {
+                // only the first key of a file is ever used, never add one to an existing file
+                let mut file = fs::OpenOptions::new().write(true).create_new(true).open(filename)
+                    .map_err(|e| format!("cannot create {}: {}", filename, e))?;
+                writeln!(file, "{}", pkcs8).map_err(|e| format!("cannot write {}: {}", filename, e))?;
+            } else {
+                println!("key: {}", pkcs8);
+            }
+            println!("address: {}", account);
+        }
+        ("address", Some(m)) => {
+            println!("{}", address(&load_key(m.value_of("key").unwrap())?));
+        }
+        ("balance", Some(m)) => {
+            let account = parse_h160(m.value_of("address").unwrap())?;
+            println!("{}", request(api, "GET", &format!("/ledger/account?address={}", account), "")?);
+        }
+        ("send", Some(m)) => {
+            let key = load_key(m.value_of("key").unwrap())?;
+            let recv = match m.value_of("to") {
+                Some(to) => parse_h160(to)?,
+                None => Default::default(),
+            };
+            let bond = match m.value_of("bond") {
+                Some(vrf_pub_key) => Some(hex::decode(vrf_pub_key).map_err(|e| format!("invalid VRF public key: {}", e))?),
+                None => None,
+            };
+            if recv == Default::default() && bond.is_none() {
+                return Err("give a receiver address or --bond".to_string());
+            }
+            let value = m.value_of("value").unwrap().parse::<usize>().map_err(|e| format!("invalid value: {}", e))?;
+            let fee = m.value_of("fee").unwrap().parse::<usize>().map_err(|e| format!("invalid fee: {}", e))?;
+            let nonce = match m.value_of("nonce") {
+                Some(nonce) => nonce.parse::<usize>().map_err(|e| format!("invalid nonce: {}", e))?,
+                None => {
+                    // the next nonce after the one at the node's tip
+                    let response = request(api, "GET", &format!("/ledger/account?address={}", address(&key)), "")?;
+                    let account: serde_json::Value = serde_json::from_str(&response).map_err(|e| format!("invalid response {}: {}", response, e))?;
+                    match account["nonce"].as_u64() {
+                        Some(nonce) => nonce as usize + 1,
+                        None => return Err(format!("cannot get the nonce: {}", response)),
+                    }
+                }
+            };
+            let chain_id = match m.value_of("chain") {
+                Some(chain_id) => parse_h256(chain_id)?,
+                None => chain_id(api)?,
+            };
+            let mut transaction = transfer(chain_id, recv, value, fee, nonce, bond.as_deref());
+            if let Some(expiry) = m.value_of("expiry") {
+                transaction.expiry = Some(expiry.parse::<u128>().map_err(|e| format!("invalid expiry: {}", e))?);
+            }
+            transaction.memo = m.value_of("memo").map(|memo| memo.as_bytes().to_vec());
+            let txn = sign_transaction(transaction, &key);
+            if m.is_present("print") {
+                println!("{}", encode(&txn));
+            } else {
+                println!("{}", request(api, "POST", "/tx", &encode(&txn))?);
+            }
+            eprintln!("transaction {} with nonce {}", txn.hash(), nonce);
+        }
+        _ => return Err(matches.usage().to_string()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::verify_signedtxn;
+
+    #[test]
+    fn sign_and_decode() {
+        let key = parse_key(&generate_key()).unwrap();
+        let recv = address(&parse_key(&generate_key()).unwrap());
+        let txn = sign_transaction(transfer(Default::default(), recv, 10, 1, 1, None), &key);
+        assert!(verify_signedtxn(&txn));
+        assert_eq!(txn.transaction.sender, address(&key));
+
+        assert_eq!(decode(&encode(&txn)).unwrap(), txn);
+        assert_eq!(decode(&serde_json::to_string(&txn).unwrap()).unwrap(), txn);
+        assert!(decode("zz").is_err());
+
+        let bond = sign_transaction(transfer(Default::default(), recv, 10, 0, 2, Some(&[1, 2, 3])), &key);
+        assert_eq!(bond.transaction.bond, Some(vec![1, 2, 3]));
+        assert_eq!(bond.transaction.recv, Default::default());
+        assert!(verify_signedtxn(&bond));
+    }
+}